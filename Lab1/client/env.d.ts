@@ -1 +1,6 @@
 /// <reference types="vite/client" />
+
+interface ImportMetaEnv {
+  // e.g. https://chat.example.com, no trailing slash
+  readonly VITE_SERVER_URL?: string
+}
@@ -1,29 +1,264 @@
-const getUrl = ({ chat, username }: ChatInfo) => {
-  return `ws://127.0.0.1:3030/chat/${chat}?username=${username}`
+const PROTOCOL_VERSION = 1
+
+const BEARER_PROTOCOL = 'bearer'
+
+const SERVER_URL = import.meta.env.VITE_SERVER_URL ?? 'http://127.0.0.1:3030'
+
+// https servers take wss:// sockets
+const SOCKET_URL = SERVER_URL.replace(/^http/, 'ws')
+
+// `@username` opens a direct chat with that user
+const getPath = ({ chat }: ChatInfo) =>
+  chat.startsWith('@')
+    ? `dm/${encodeURIComponent(chat.slice(1))}`
+    : `chat/${encodeURIComponent(chat)}`
+
+const getUrl = (chatInfo: ChatInfo, lastSeen?: number) => {
+  const resume = lastSeen === undefined ? '' : `&last_seen=${lastSeen}`
+  return `${SOCKET_URL}/${getPath(chatInfo)}?version=${PROTOCOL_VERSION}${resume}`
+}
+
+// Event sources can't send headers, so the token goes in the query
+const getEventsUrl = (chatInfo: ChatInfo, lastSeen?: number) => {
+  const resume = lastSeen === undefined ? '' : `&last_seen=${lastSeen}`
+  const token = encodeURIComponent(chatInfo.token)
+  return `${SERVER_URL}/${getPath(chatInfo)}/events?version=${PROTOCOL_VERSION}&token=${token}${resume}`
 }
 
 interface ChatInfo {
+  chat: string
+  token: string
+}
+
+interface Reaction {
+  emoji: string
+  users: string[]
+}
+
+interface Attachment {
+  id: number
   chat: string
   username: string
+  filename: string
+  content_type: string
+  size: number
+  thumbnail: boolean
+  message_id?: number | null
+  created_at: string
 }
 
-interface IncomingMessage {
+interface ChatMessage {
+  id?: number
+  client_id?: string
+  username: string
   text: string
+  // Action messages are sent with /me
+  kind?: 'text' | 'action'
+  created_at?: string
+  edited_at?: string | null
+  reactions?: Reaction[]
+  attachments?: Attachment[]
+  // Replies point at the first message of their thread
+  parent_id?: number | null
+  // Replies that weren't sent to the room as well
+  thread_only?: boolean
+  reply_count?: number
 }
 
-interface ChatMessage {
+type Role = 'member' | 'moderator' | 'owner'
+
+interface ReadReceipt {
   username: string
+  last_read_id: number
+}
+
+interface SnippetPart {
   text: string
+  highlight: boolean
+}
+
+interface SearchResult {
+  message: ChatMessage
+  snippet: SnippetPart[]
+}
+
+interface Thread {
+  message: ChatMessage
+  replies: ChatMessage[]
+}
+
+interface SearchParams {
+  query: string
+  author?: string
+  from?: string
+  to?: string
+  take?: number
+  offset?: number
+}
+
+type ModerationAction = 'kick' | 'ban' | 'unban' | 'mute' | 'unmute' | 'promote' | 'demote'
+
+interface InboxEntry {
+  chat: string
+  with: string
+  unread: number
+  last_message?: ChatMessage | null
+}
+
+type ClientEvent =
+  | {
+      type: 'message'
+      text: string
+      client_id?: string
+      attachments?: number[]
+      parent_id?: number
+      thread_only?: boolean
+    }
+  | { type: 'thread'; id: number; before?: number; take?: number }
+  | { type: 'history'; before?: number; after?: number; take?: number }
+  | ({ type: 'search' } & SearchParams)
+  | { type: 'context'; id: number; take?: number }
+  | { type: 'typing' }
+  | { type: 'read'; id: number }
+  | { type: 'edit'; id: number; text: string }
+  | { type: 'delete'; id: number }
+  | { type: 'react'; id: number; emoji: string }
+  | { type: 'unreact'; id: number; emoji: string }
+  | {
+      type: 'moderate'
+      action: ModerationAction
+      username: string
+      reason?: string
+      expires_in?: number
+    }
+
+type ServerEvent =
+  | {
+      type: 'welcome'
+      version: number
+      chat: string
+      username: string
+      role: Role
+      session: number
+      topic?: string | null
+      nicknames: Record<string, string>
+    }
+  | { type: 'message'; message: ChatMessage }
+  | { type: 'reply'; message: ChatMessage }
+  | { type: 'replies'; id: number; reply_count: number }
+  | ({ type: 'thread' } & Thread)
+  | { type: 'history'; messages: ChatMessage[]; read: ReadReceipt[]; has_more: boolean }
+  | { type: 'search'; results: SearchResult[]; has_more: boolean }
+  | { type: 'context'; id: number; messages: ChatMessage[] }
+  | { type: 'roster'; users: string[] }
+  | { type: 'join'; username: string }
+  | { type: 'leave'; username: string }
+  | { type: 'typing'; username: string }
+  | { type: 'read'; username: string; last_read_id: number }
+  | { type: 'ack'; client_id?: string; id: number }
+  | { type: 'edited'; message: ChatMessage }
+  | { type: 'deleted'; id: number }
+  | { type: 'reactions'; id: number; reactions: Reaction[] }
+  | { type: 'system'; action: ModerationAction; username: string; by: string; text: string }
+  | { type: 'topic'; topic?: string | null; username: string }
+  | { type: 'nick'; username: string; nickname?: string | null }
+  | { type: 'notice'; text: string }
+  | { type: 'error'; code: string; message: string; fatal: boolean }
+
+// The part of a websocket the chat uses, both transports provide it
+interface ChatSocket {
+  send: (data: string) => void
+  close: () => void
+  onmessage: ((event: MessageEvent<string>) => void) | null
+  onclose: ((event: CloseEvent) => void) | null
+}
+
+// Server-sent events one way and POST requests the other,
+// for proxies that strip websocket upgrades
+class EventSocket implements ChatSocket {
+  onmessage: ((event: MessageEvent<string>) => void) | null = null
+  onclose: ((event: CloseEvent) => void) | null = null
+  private session?: number
+  // Sent once the welcome event tells the session id
+  private pending: string[] = []
+  private closed = false
+
+  constructor(
+    private source: EventSource,
+    private token: string
+  ) {
+    source.onmessage = (event: MessageEvent<string>) => {
+      const serverEvent: ServerEvent = JSON.parse(event.data)
+      if (serverEvent.type === 'welcome') {
+        this.session = serverEvent.session
+        this.pending.forEach((data) => this.send(data))
+        this.pending = []
+      }
+      this.onmessage?.(event)
+    }
+    // The chat reconnects by itself, the event source would start over without the history
+    source.addEventListener('close', () => this.close())
+    source.onerror = () => this.close()
+  }
+
+  send(data: string) {
+    if (this.session === undefined) {
+      this.pending.push(data)
+      return
+    }
+    fetch(`${SERVER_URL}/sessions/${this.session}/events`, {
+      method: 'POST',
+      headers: { Authorization: `Bearer ${this.token}`, 'Content-Type': 'application/json' },
+      body: data
+    }).catch((err) => console.log(`Event wasn't sent: ${err}`))
+  }
+
+  close() {
+    if (this.closed) return
+    this.closed = true
+    this.source.close()
+    this.onclose?.(new CloseEvent('close'))
+  }
 }
 
-interface ErrorMessage {
-  error_message: string
+const sendEvent = (socket: ChatSocket, event: ClientEvent) => {
+  socket.send(JSON.stringify(event))
 }
 
-const connectToChat = (chatInfo: ChatInfo): Promise<WebSocket> => {
+const connectToEvents = (chatInfo: ChatInfo, lastSeen?: number): Promise<ChatSocket> => {
   return new Promise((resolve, reject) => {
-    const url = getUrl(chatInfo)
-    const socket = new WebSocket(url)
+    const url = getEventsUrl(chatInfo, lastSeen)
+    const source = new EventSource(url)
+    source.onopen = () => {
+      console.log(`Event stream connected to url: ${getEventsUrl({ ...chatInfo, token: '' })}`)
+      resolve(new EventSocket(source, chatInfo.token))
+    }
+    source.onerror = (err) => {
+      source.close()
+      reject(err)
+    }
+  })
+}
+
+// Once a websocket fails to open, the event stream is used for the rest of the page life
+let useEvents = false
+
+const connectToChat = async (chatInfo: ChatInfo, lastSeen?: number): Promise<ChatSocket> => {
+  if (!useEvents) {
+    try {
+      return await connectToSocket(chatInfo, lastSeen)
+    } catch (err) {
+      console.log(`Falling back to server-sent events: ${err}`)
+      useEvents = true
+    }
+  }
+  return connectToEvents(chatInfo, lastSeen)
+}
+
+const connectToSocket = (chatInfo: ChatInfo, lastSeen?: number): Promise<WebSocket> => {
+  return new Promise((resolve, reject) => {
+    const url = getUrl(chatInfo, lastSeen)
+    const socket = new WebSocket(url, [BEARER_PROTOCOL, chatInfo.token])
     socket.onopen = () => {
       console.log(`WS connected to url: ${url}`)
       resolve(socket)
@@ -35,6 +270,56 @@ const connectToChat = (chatInfo: ChatInfo): Promise<WebSocket> => {
   })
 }
 
-export { connectToChat }
+const fetchInbox = async (token: string): Promise<InboxEntry[]> => {
+  const response = await fetch(`${SERVER_URL}/inbox`, {
+    headers: { Authorization: `Bearer ${token}` }
+  })
+  if (!response.ok) {
+    throw new Error((await response.json()).message)
+  }
+  return response.json()
+}
 
-export type { ChatInfo, ChatMessage, IncomingMessage, ErrorMessage }
+// `chat` is the name the server gave in the welcome event, direct chats included
+const uploadAttachment = async (token: string, chat: string, file: File): Promise<Attachment> => {
+  const form = new FormData()
+  form.append('file', file)
+  const response = await fetch(
+    `${SERVER_URL}/chat/${encodeURIComponent(chat)}/attachments`,
+    {
+      method: 'POST',
+      headers: { Authorization: `Bearer ${token}` },
+      body: form
+    }
+  )
+  if (!response.ok) {
+    throw new Error((await response.json()).message)
+  }
+  return response.json()
+}
+
+// Images and links can't send headers, so the token goes in the query
+const attachmentUrl = (token: string, attachment: Attachment, thumbnail = false) => {
+  const path = thumbnail ? `${attachment.id}/thumbnail` : `${attachment.id}`
+  return `${SERVER_URL}/attachments/${path}?token=${encodeURIComponent(token)}`
+}
+
+export { attachmentUrl, connectToChat, fetchInbox, sendEvent, uploadAttachment }
+
+export type {
+  Attachment,
+  ChatInfo,
+  ChatMessage,
+  ChatSocket,
+  ClientEvent,
+  InboxEntry,
+  ModerationAction,
+  Reaction,
+  ReadReceipt,
+  Role,
+  SearchParams,
+  SearchResult,
+  ServerEvent,
+  SnippetPart,
+  Thread
+}
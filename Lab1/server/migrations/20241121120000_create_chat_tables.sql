@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS rooms (
+    name TEXT PRIMARY KEY NOT NULL,
+    created_at TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS messages (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    chat TEXT NOT NULL REFERENCES rooms (name),
+    username TEXT NOT NULL,
+    text TEXT NOT NULL,
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS messages_chat_id ON messages (chat, id);
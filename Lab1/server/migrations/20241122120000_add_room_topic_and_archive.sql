@@ -0,0 +1,3 @@
+ALTER TABLE rooms ADD COLUMN topic TEXT;
+
+ALTER TABLE rooms ADD COLUMN archived BOOLEAN NOT NULL DEFAULT FALSE;
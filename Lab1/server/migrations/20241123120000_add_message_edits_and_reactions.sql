@@ -0,0 +1,9 @@
+ALTER TABLE messages ADD COLUMN edited_at TEXT;
+
+CREATE TABLE IF NOT EXISTS reactions (
+    message_id INTEGER NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    username TEXT NOT NULL,
+    emoji TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (message_id, username, emoji)
+);
@@ -0,0 +1,10 @@
+ALTER TABLE rooms ADD COLUMN direct BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE IF NOT EXISTS room_members (
+    chat TEXT NOT NULL REFERENCES rooms (name) ON DELETE CASCADE,
+    username TEXT NOT NULL,
+    last_read_id INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (chat, username)
+);
+
+CREATE INDEX IF NOT EXISTS room_members_username ON room_members (username);
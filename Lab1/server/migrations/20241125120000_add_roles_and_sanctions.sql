@@ -0,0 +1,12 @@
+ALTER TABLE room_members ADD COLUMN role TEXT NOT NULL DEFAULT 'member';
+
+CREATE TABLE IF NOT EXISTS sanctions (
+    chat TEXT NOT NULL REFERENCES rooms (name) ON DELETE CASCADE,
+    username TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    reason TEXT,
+    expires_at TEXT,
+    created_by TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (chat, username, kind)
+);
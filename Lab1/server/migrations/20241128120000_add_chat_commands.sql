@@ -0,0 +1,3 @@
+ALTER TABLE messages ADD COLUMN kind TEXT NOT NULL DEFAULT 'text';
+
+ALTER TABLE room_members ADD COLUMN nickname TEXT;
@@ -0,0 +1,5 @@
+ALTER TABLE messages ADD COLUMN parent_id INTEGER REFERENCES messages (id) ON DELETE CASCADE;
+ALTER TABLE messages ADD COLUMN thread_only BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE messages ADD COLUMN reply_count INTEGER NOT NULL DEFAULT 0;
+
+CREATE INDEX IF NOT EXISTS messages_parent_id ON messages (parent_id);
@@ -0,0 +1 @@
+CREATE UNIQUE INDEX IF NOT EXISTS room_members_nickname ON room_members (chat, lower(nickname));
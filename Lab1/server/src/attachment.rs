@@ -0,0 +1,350 @@
+use crate::error::ChatError;
+use chrono::{DateTime, Utc};
+use image::{ImageFormat, ImageReader, Limits};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Cursor};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::warn;
+
+pub const MAX_ATTACHMENTS: usize = 10;
+const DEFAULT_MAX_SIZE: usize = 10 * 1024 * 1024;
+const MAX_FILENAME_LENGTH: usize = 255;
+const THUMBNAIL_SIZE: u32 = 256;
+// Bigger images aren't decoded at all, they may take too much memory
+const MAX_IMAGE_SIDE: u32 = 10_000;
+
+const CONTENT_TYPES: [&str; 6] = [
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "application/pdf",
+    "text/plain",
+];
+
+#[derive(Deserialize, Serialize, Debug, Clone, sqlx::FromRow)]
+pub struct Attachment {
+    pub id: i64,
+    pub chat: String,
+    // The uploader, only they can send it with a message
+    pub username: String,
+    pub filename: String,
+    pub content_type: String,
+    pub size: i64,
+    pub thumbnail: bool,
+    // Empty until the attachment is sent with a message
+    pub message_id: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Attachment {
+    pub fn is_image(&self) -> bool {
+        self.content_type.starts_with("image/")
+    }
+}
+
+#[derive(Debug)]
+pub struct Upload {
+    pub filename: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+impl Upload {
+    pub fn into_attachment(self, chat: &str, username: &str) -> (Attachment, Vec<u8>) {
+        let attachment = Attachment {
+            id: 0,
+            chat: chat.to_string(),
+            username: username.to_string(),
+            filename: sanitize_filename(&self.filename),
+            content_type: self.content_type,
+            size: self.data.len() as i64,
+            thumbnail: false,
+            message_id: None,
+            created_at: Utc::now(),
+        };
+        (attachment, self.data)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AttachmentConfig {
+    pub dir: PathBuf,
+    pub max_size: usize,
+}
+
+impl Default for AttachmentConfig {
+    fn default() -> Self {
+        AttachmentConfig {
+            dir: PathBuf::from("attachments"),
+            max_size: DEFAULT_MAX_SIZE,
+        }
+    }
+}
+
+// Uploaded files live on the local disk, one directory per chat
+#[derive(Debug, Clone)]
+pub struct AttachmentFiles {
+    config: Arc<AttachmentConfig>,
+}
+
+impl AttachmentFiles {
+    pub fn new(config: AttachmentConfig) -> Self {
+        AttachmentFiles {
+            config: Arc::new(config),
+        }
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.config.max_size
+    }
+
+    // Chat names may contain anything, so they are hex encoded
+    fn chat_dir(&self, chat: &str) -> PathBuf {
+        let name: String = chat.bytes().map(|byte| format!("{byte:02x}")).collect();
+        self.config.dir.join(name)
+    }
+
+    fn path(&self, attachment: &Attachment, thumbnail: bool) -> PathBuf {
+        let name = match thumbnail {
+            true => format!("{}.thumb.png", attachment.id),
+            false => attachment.id.to_string(),
+        };
+        self.chat_dir(&attachment.chat).join(name)
+    }
+
+    // Makes sure the content is what its type claims and renders the thumbnail of images
+    pub async fn check(&self, upload: &mut Upload) -> Result<Option<Vec<u8>>, ChatError> {
+        if upload.data.len() > self.config.max_size {
+            Err(ChatError::AttachmentTooLarge(self.config.max_size))?
+        }
+        let content_type = upload
+            .content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase();
+        if !CONTENT_TYPES.contains(&content_type.as_str()) {
+            Err(ChatError::UnsupportedAttachment(content_type.clone()))?
+        }
+        upload.content_type = content_type;
+        let mismatch = || ChatError::InvalidUpload(format!("file isn't {}", upload.content_type));
+        match ImageFormat::from_mime_type(&upload.content_type) {
+            Some(format) => {
+                if image::guess_format(&upload.data).ok() != Some(format) {
+                    Err(mismatch())?
+                }
+                let data = upload.data.clone();
+                let thumbnail =
+                    tokio::task::spawn_blocking(move || render_thumbnail(&data, format))
+                        .await
+                        .map_err(io::Error::from)?;
+                Ok(Some(thumbnail?))
+            }
+            None if upload.content_type == "application/pdf" => {
+                if !upload.data.starts_with(b"%PDF-") {
+                    Err(mismatch())?
+                }
+                Ok(None)
+            }
+            None => {
+                if std::str::from_utf8(&upload.data).is_err() {
+                    Err(mismatch())?
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    pub async fn save(
+        &self,
+        attachment: &Attachment,
+        data: &[u8],
+        thumbnail: Option<&[u8]>,
+    ) -> Result<(), ChatError> {
+        tokio::fs::create_dir_all(self.chat_dir(&attachment.chat)).await?;
+        tokio::fs::write(self.path(attachment, false), data).await?;
+        if let Some(thumbnail) = thumbnail {
+            tokio::fs::write(self.path(attachment, true), thumbnail).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn read(
+        &self,
+        attachment: &Attachment,
+        thumbnail: bool,
+    ) -> Result<Vec<u8>, ChatError> {
+        match tokio::fs::read(self.path(attachment, thumbnail)).await {
+            Ok(data) => Ok(data),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                Err(ChatError::AttachmentNotFound(attachment.id))
+            }
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    // Files that are already gone are fine, the rest is only logged
+    pub async fn remove(&self, attachments: &[Attachment]) {
+        for attachment in attachments {
+            for thumbnail in [false, true] {
+                let path = self.path(attachment, thumbnail);
+                match tokio::fs::remove_file(&path).await {
+                    Err(error) if error.kind() != io::ErrorKind::NotFound => {
+                        warn!(path = %path.display(), %error, "couldn't remove the file")
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    pub async fn remove_chat(&self, chat: &str) {
+        let dir = self.chat_dir(chat);
+        match tokio::fs::remove_dir_all(&dir).await {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => {
+                warn!(path = %dir.display(), %error, "couldn't remove the directory")
+            }
+            _ => {}
+        }
+    }
+}
+
+fn render_thumbnail(data: &[u8], format: ImageFormat) -> Result<Vec<u8>, ChatError> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_SIDE);
+    limits.max_image_height = Some(MAX_IMAGE_SIDE);
+    let mut reader = ImageReader::with_format(Cursor::new(data), format);
+    reader.limits(limits);
+    let image = reader
+        .decode()
+        .map_err(|_| ChatError::InvalidUpload("image can't be decoded".to_string()))?;
+    let image = match image.width().max(image.height()) > THUMBNAIL_SIZE {
+        true => image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE),
+        false => image,
+    };
+    let mut thumbnail = vec![];
+    image
+        .write_to(&mut Cursor::new(&mut thumbnail), ImageFormat::Png)
+        .map_err(io::Error::other)?;
+    Ok(thumbnail)
+}
+
+// Only the last path segment is kept, without control characters and quotes
+fn sanitize_filename(filename: &str) -> String {
+    let name = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"')
+        .take(MAX_FILENAME_LENGTH)
+        .collect();
+    match name.trim() {
+        "" | "." | ".." => "file".to_string(),
+        name => name.to_string(),
+    }
+}
+
+// Headers only take ASCII, the full name goes percent encoded as RFC 6266 suggests
+pub fn content_disposition(attachment: &Attachment) -> String {
+    let disposition = match attachment.is_image() {
+        true => "inline",
+        false => "attachment",
+    };
+    let fallback: String = attachment
+        .filename
+        .chars()
+        .map(|c| match c.is_ascii() && c != '\\' {
+            true => c,
+            false => '_',
+        })
+        .collect();
+    let encoded: String = attachment
+        .filename
+        .bytes()
+        .map(
+            |byte| match byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+                true => (byte as char).to_string(),
+                false => format!("%{byte:02X}"),
+            },
+        )
+        .collect();
+    format!("{disposition}; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upload(content_type: &str, data: Vec<u8>) -> Upload {
+        Upload {
+            filename: "file".to_string(),
+            content_type: content_type.to_string(),
+            data,
+        }
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = image::RgbImage::new(width, height);
+        let mut data = vec![];
+        image
+            .write_to(&mut Cursor::new(&mut data), ImageFormat::Png)
+            .unwrap();
+        data
+    }
+
+    #[tokio::test]
+    async fn images_get_thumbnails_that_fit_the_box() {
+        let files = AttachmentFiles::new(AttachmentConfig::default());
+        let mut image = upload("Image/PNG", png(1024, 512));
+        let thumbnail = files.check(&mut image).await.unwrap().unwrap();
+        assert_eq!(image.content_type, "image/png");
+        let thumbnail = image::load_from_memory(&thumbnail).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (256, 128));
+
+        let mut text = upload("text/plain; charset=utf-8", b"hello".to_vec());
+        assert!(files.check(&mut text).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn uploads_are_checked_against_the_limits() {
+        let config = AttachmentConfig {
+            max_size: 1024,
+            ..Default::default()
+        };
+        let files = AttachmentFiles::new(config);
+        let mut too_large = upload("text/plain", vec![b'a'; 1025]);
+        assert!(matches!(
+            files.check(&mut too_large).await,
+            Err(ChatError::AttachmentTooLarge(1024))
+        ));
+        let mut html = upload("text/html", b"<p>".to_vec());
+        assert!(matches!(
+            files.check(&mut html).await,
+            Err(ChatError::UnsupportedAttachment(_))
+        ));
+        let mut fake_image = upload("image/png", b"%PDF-1.4".to_vec());
+        assert!(matches!(
+            files.check(&mut fake_image).await,
+            Err(ChatError::InvalidUpload(_))
+        ));
+    }
+
+    #[test]
+    fn filenames_are_safe_for_headers() {
+        assert_eq!(sanitize_filename("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_filename("C:\\docs\\a\"b\n.txt"), "ab.txt");
+        assert_eq!(sanitize_filename(".."), "file");
+
+        let (attachment, _) = upload("application/pdf", vec![]).into_attachment("room", "alice");
+        let attachment = Attachment {
+            filename: "отчёт 1.pdf".to_string(),
+            ..attachment
+        };
+        assert_eq!(
+            content_disposition(&attachment),
+            "attachment; filename=\"_____ 1.pdf\"; filename*=UTF-8''%D0%BE%D1%82%D1%87%D1%91%D1%82%201.pdf"
+        );
+    }
+}
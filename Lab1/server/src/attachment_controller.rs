@@ -0,0 +1,103 @@
+use crate::attachment::{content_disposition, Attachment, Upload};
+use crate::auth::Identity;
+use crate::chat::Chats;
+use crate::error::ChatError;
+use futures_util::{StreamExt, TryStreamExt};
+use warp::http::header::{
+    CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE, X_CONTENT_TYPE_OPTIONS,
+};
+use warp::http::StatusCode;
+use warp::hyper::body::Buf;
+use warp::multipart::{FormData, Part};
+use warp::reply::Response;
+use warp::{Rejection, Reply};
+
+const FILE_FIELD: &str = "file";
+
+// Expects a multipart form with the file in the `file` field
+pub async fn upload_attachment(
+    chat_name: String,
+    identity: Identity,
+    form: FormData,
+    chats: Chats,
+) -> Result<impl Reply, Rejection> {
+    let upload = read_upload(form, chats.max_attachment_size())
+        .await
+        .map_err(warp::reject::custom)?;
+    let attachment = chats
+        .upload(&chat_name, &identity.username, upload)
+        .await
+        .map_err(warp::reject::custom)?;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&attachment),
+        StatusCode::CREATED,
+    ))
+}
+
+pub async fn get_attachment(
+    id: i64,
+    identity: Identity,
+    chats: Chats,
+) -> Result<impl Reply, Rejection> {
+    let (attachment, data) = chats
+        .download(id, &identity.username, false)
+        .await
+        .map_err(warp::reject::custom)?;
+    let content_type = attachment.content_type.clone();
+    Ok(file_reply(&attachment, content_type, data))
+}
+
+pub async fn get_thumbnail(
+    id: i64,
+    identity: Identity,
+    chats: Chats,
+) -> Result<impl Reply, Rejection> {
+    let (attachment, data) = chats
+        .download(id, &identity.username, true)
+        .await
+        .map_err(warp::reject::custom)?;
+    Ok(file_reply(&attachment, "image/png".to_string(), data))
+}
+
+fn file_reply(attachment: &Attachment, content_type: String, data: Vec<u8>) -> Response {
+    let reply = warp::reply::with_header(data, CONTENT_TYPE, content_type);
+    let reply =
+        warp::reply::with_header(reply, CONTENT_DISPOSITION, content_disposition(attachment));
+    // Browsers must not guess another type, e.g. render a text file as html
+    let reply = warp::reply::with_header(reply, X_CONTENT_TYPE_OPTIONS, "nosniff");
+    warp::reply::with_header(reply, CACHE_CONTROL, "private, max-age=86400").into_response()
+}
+
+async fn read_upload(mut form: FormData, max_size: usize) -> Result<Upload, ChatError> {
+    let invalid = |error: warp::Error| ChatError::InvalidUpload(error.to_string());
+    while let Some(part) = form.try_next().await.map_err(invalid)? {
+        if part.name() == FILE_FIELD {
+            return read_file(part, max_size).await;
+        }
+    }
+    Err(ChatError::InvalidUpload(format!(
+        "{FILE_FIELD:?} field is missing"
+    )))
+}
+
+async fn read_file(part: Part, max_size: usize) -> Result<Upload, ChatError> {
+    let filename = part.filename().unwrap_or_default().to_string();
+    let content_type = part
+        .content_type()
+        .unwrap_or("application/octet-stream")
+        .to_string();
+    let mut data = vec![];
+    let mut stream = part.stream();
+    while let Some(chunk) = stream.next().await {
+        let mut chunk = chunk.map_err(|error| ChatError::InvalidUpload(error.to_string()))?;
+        if data.len() + chunk.remaining() > max_size {
+            Err(ChatError::AttachmentTooLarge(max_size))?
+        }
+        data.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+    Ok(Upload {
+        filename,
+        content_type,
+        data,
+    })
+}
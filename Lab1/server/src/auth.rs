@@ -0,0 +1,87 @@
+use crate::error::ChatError;
+use jsonwebtoken::{decode, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use warp::{Filter, Rejection};
+
+pub const BEARER_PROTOCOL: &str = "bearer";
+
+// Same claims Lab2 issues on login, the username lives in `iss`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub iss: String,
+    pub sub: String,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub username: String,
+    // Browsers drop the socket if the chosen subprotocol isn't echoed back
+    pub bearer_protocol: bool,
+}
+
+pub fn validate_jwt(token: &str, secret: &[u8]) -> Result<Claims, ChatError> {
+    let validation = Validation::default();
+    let token = decode::<Claims>(token, &DecodingKey::from_secret(secret), &validation)?;
+    Ok(token.claims)
+}
+
+pub fn authenticate(
+    secret: Arc<String>,
+) -> impl Filter<Extract = (Identity,), Error = Rejection> + Clone {
+    warp::query::<TokenQuery>()
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(
+            move |query: TokenQuery, protocols: Option<String>, authorization: Option<String>| {
+                let secret = secret.clone();
+                async move {
+                    identify(query, protocols, authorization, &secret).map_err(warp::reject::custom)
+                }
+            },
+        )
+}
+
+fn identify(
+    TokenQuery { token }: TokenQuery,
+    protocols: Option<String>,
+    authorization: Option<String>,
+    secret: &str,
+) -> Result<Identity, ChatError> {
+    let protocol_token = protocols.as_deref().and_then(extract_jwt_from_protocols);
+    let bearer_protocol = protocol_token.is_some();
+    let header_token = authorization
+        .as_deref()
+        .and_then(extract_jwt_from_authorization);
+    let token = protocol_token
+        .or(header_token)
+        .or(token)
+        .ok_or(ChatError::MissingToken)?;
+    let claims = validate_jwt(&token, secret.as_bytes())?;
+    Ok(Identity {
+        username: claims.iss,
+        bearer_protocol,
+    })
+}
+
+// Expects `Sec-WebSocket-Protocol: bearer, <token>`
+fn extract_jwt_from_protocols(protocols: &str) -> Option<String> {
+    let mut protocols = protocols.split(',').map(str::trim);
+    protocols.find(|p| p.eq_ignore_ascii_case(BEARER_PROTOCOL))?;
+    protocols.next().map(str::to_string)
+}
+
+// Expects `Authorization: Bearer <token>`, used by the plain HTTP routes
+fn extract_jwt_from_authorization(authorization: &str) -> Option<String> {
+    let (scheme, token) = authorization.split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case(BEARER_PROTOCOL)
+        .then(|| token.trim().to_string())
+}
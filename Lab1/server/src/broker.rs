@@ -0,0 +1,438 @@
+use crate::error::ChatError;
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use redis::aio::{ConnectionManager, PubSub};
+use redis::{AsyncCommands, Client, RedisResult};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info};
+
+const CHANNEL_PREFIX: &str = "chat:";
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+pub type Bus = Arc<dyn Broker>;
+
+pub type Subscription = mpsc::UnboundedReceiver<String>;
+
+type Subscribers = HashMap<String, Vec<mpsc::UnboundedSender<String>>>;
+
+// Fans room events out to every server instance. Payloads published to a topic
+// reach each subscriber in the order they were published.
+#[async_trait]
+pub trait Broker: Send + Sync {
+    async fn publish(&self, topic: &str, payload: String) -> Result<(), ChatError>;
+
+    // Dropping the subscription unsubscribes from the topic
+    async fn subscribe(&self, topic: &str) -> Result<Subscription, ChatError>;
+}
+
+// Random for every process, tells the instances sharing a broker apart
+pub fn instance_id() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+// Returns false once every subscriber is gone
+fn deliver(subscribers: &mut Vec<mpsc::UnboundedSender<String>>, payload: &str) -> bool {
+    subscribers.retain(|tx| tx.send(payload.to_string()).is_ok());
+    !subscribers.is_empty()
+}
+
+// Only reaches subscribers of the same process, enough for a single instance
+#[derive(Default)]
+pub struct MemoryBroker {
+    topics: Mutex<Subscribers>,
+}
+
+#[async_trait]
+impl Broker for MemoryBroker {
+    async fn publish(&self, topic: &str, payload: String) -> Result<(), ChatError> {
+        let mut topics = self.topics.lock().unwrap();
+        if let Some(subscribers) = topics.get_mut(topic) {
+            if !deliver(subscribers, &payload) {
+                topics.remove(topic);
+            }
+        }
+        Ok(())
+    }
+
+    async fn subscribe(&self, topic: &str) -> Result<Subscription, ChatError> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut topics = self.topics.lock().unwrap();
+        topics.entry(topic.to_string()).or_default().push(tx);
+        Ok(rx)
+    }
+}
+
+struct SubscribeRequest {
+    channel: String,
+    tx: mpsc::UnboundedSender<String>,
+    subscribed: oneshot::Sender<()>,
+}
+
+// Redis pub/sub keeps the order of messages published to a channel, so every
+// instance sees the events of a room in the same order
+pub struct RedisBroker {
+    publisher: ConnectionManager,
+    requests: mpsc::UnboundedSender<SubscribeRequest>,
+}
+
+impl RedisBroker {
+    pub async fn connect(url: &str) -> Self {
+        let client = Client::open(url).unwrap_or_else(|_| panic!("Invalid redis url: {url}"));
+        let publisher = ConnectionManager::new(client.clone())
+            .await
+            .unwrap_or_else(|_| panic!("Couldn't connect to redis: {url}"));
+        let pubsub = client
+            .get_async_pubsub()
+            .await
+            .unwrap_or_else(|_| panic!("Couldn't subscribe to redis: {url}"));
+        info!("connected to redis");
+        let (requests, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run_subscriber(client, pubsub, rx));
+        RedisBroker {
+            publisher,
+            requests,
+        }
+    }
+}
+
+#[async_trait]
+impl Broker for RedisBroker {
+    async fn publish(&self, topic: &str, payload: String) -> Result<(), ChatError> {
+        let channel = format!("{CHANNEL_PREFIX}{topic}");
+        let mut publisher = self.publisher.clone();
+        publisher.publish::<_, _, ()>(channel, payload).await?;
+        Ok(())
+    }
+
+    async fn subscribe(&self, topic: &str) -> Result<Subscription, ChatError> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (subscribed, confirmed) = oneshot::channel();
+        let request = SubscribeRequest {
+            channel: format!("{CHANNEL_PREFIX}{topic}"),
+            tx,
+            subscribed,
+        };
+        let lost = || redis::RedisError::from((redis::ErrorKind::IoError, "Subscriber is gone"));
+        self.requests.send(request).map_err(|_| lost())?;
+        confirmed.await.map_err(|_| lost())?;
+        Ok(rx)
+    }
+}
+
+// Owns the subscriber connection and restores every subscription after it drops
+async fn run_subscriber(
+    client: Client,
+    pubsub: PubSub,
+    mut requests: mpsc::UnboundedReceiver<SubscribeRequest>,
+) {
+    let mut channels = Subscribers::new();
+    let mut pubsub = Some(pubsub);
+    loop {
+        let connection = match pubsub.take() {
+            Some(pubsub) => Ok(pubsub),
+            None => client.get_async_pubsub().await,
+        };
+        let result = match connection {
+            Ok(pubsub) => relay(pubsub, &mut channels, &mut requests).await,
+            Err(error) => Err(error),
+        };
+        match result {
+            // The broker was dropped
+            Ok(()) => return,
+            Err(error) => error!(%error, "redis subscriber failed"),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn relay(
+    pubsub: PubSub,
+    channels: &mut Subscribers,
+    requests: &mut mpsc::UnboundedReceiver<SubscribeRequest>,
+) -> RedisResult<()> {
+    let (mut sink, mut stream) = pubsub.split();
+    for channel in channels.keys() {
+        sink.subscribe(channel).await?;
+    }
+    loop {
+        tokio::select! {
+            request = requests.recv() => {
+                let Some(request) = request else {
+                    return Ok(());
+                };
+                let subscribers = channels.entry(request.channel.clone()).or_default();
+                let is_new = subscribers.is_empty();
+                subscribers.push(request.tx);
+                if is_new {
+                    sink.subscribe(&request.channel).await?;
+                }
+                let _ = request.subscribed.send(());
+            }
+            message = stream.next() => {
+                let Some(message) = message else {
+                    Err((redis::ErrorKind::IoError, "Connection closed"))?
+                };
+                let channel = message.get_channel_name();
+                let Ok(payload) = message.get_payload::<String>() else {
+                    continue;
+                };
+                if let Some(subscribers) = channels.get_mut(channel) {
+                    if !deliver(subscribers, &payload) {
+                        channels.remove(channel);
+                        sink.unsubscribe(channel).await?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::task::JoinHandle;
+    use tokio::time::timeout;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    type Clients = HashMap<String, Vec<(u64, mpsc::UnboundedSender<Vec<u8>>)>>;
+
+    // Stand-in for a redis server that only knows pub/sub, enough for `RedisBroker`
+    pub(crate) struct FakeRedis {
+        pub(crate) url: String,
+        channels: Arc<Mutex<Clients>>,
+        connections: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    }
+
+    impl FakeRedis {
+        pub(crate) async fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("redis://{}", listener.local_addr().unwrap());
+            let channels: Arc<Mutex<Clients>> = Default::default();
+            let connections: Arc<Mutex<Vec<JoinHandle<()>>>> = Default::default();
+            let (shared, handles) = (channels.clone(), connections.clone());
+            tokio::spawn(async move {
+                let next_id = AtomicU64::new(1);
+                while let Ok((socket, _)) = listener.accept().await {
+                    let id = next_id.fetch_add(1, Ordering::Relaxed);
+                    let handle = tokio::spawn(serve(socket, id, shared.clone()));
+                    handles.lock().unwrap().push(handle);
+                }
+            });
+            FakeRedis {
+                url,
+                channels,
+                connections,
+            }
+        }
+
+        fn subscribers(&self, channel: &str) -> usize {
+            let channels = self.channels.lock().unwrap();
+            channels.get(channel).map_or(0, Vec::len)
+        }
+
+        // Drops every client connection as if the server restarted
+        fn restart(&self) {
+            for handle in self.connections.lock().unwrap().drain(..) {
+                handle.abort();
+            }
+            self.channels.lock().unwrap().clear();
+        }
+    }
+
+    fn bulk(value: &[u8]) -> Vec<u8> {
+        let mut reply = format!("${}\r\n", value.len()).into_bytes();
+        reply.extend_from_slice(value);
+        reply.extend_from_slice(b"\r\n");
+        reply
+    }
+
+    fn push(kind: &[u8], channel: &[u8], last: Vec<u8>) -> Vec<u8> {
+        let mut reply = b"*3\r\n".to_vec();
+        reply.extend(bulk(kind));
+        reply.extend(bulk(channel));
+        reply.extend(last);
+        reply
+    }
+
+    async fn read_command(
+        reader: &mut BufReader<impl AsyncReadExt + Unpin>,
+    ) -> Option<Vec<Vec<u8>>> {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.ok().filter(|n| *n > 0)?;
+        let count: usize = line.trim().strip_prefix('*')?.parse().ok()?;
+        let mut args = vec![];
+        for _ in 0..count {
+            line.clear();
+            reader.read_line(&mut line).await.ok()?;
+            let len: usize = line.trim().strip_prefix('$')?.parse().ok()?;
+            let mut arg = vec![0; len + 2];
+            reader.read_exact(&mut arg).await.ok()?;
+            arg.truncate(len);
+            args.push(arg);
+        }
+        Some(args)
+    }
+
+    async fn serve(socket: TcpStream, id: u64, channels: Arc<Mutex<Clients>>) {
+        let (reader, mut writer) = socket.into_split();
+        let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+        let writer_task = tokio::spawn(async move {
+            while let Some(reply) = rx.recv().await {
+                if writer.write_all(&reply).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let mut reader = BufReader::new(reader);
+        while let Some(args) = read_command(&mut reader).await {
+            let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+            let mut channels = channels.lock().unwrap();
+            match name.as_str() {
+                "SUBSCRIBE" | "UNSUBSCRIBE" => {
+                    for channel in &args[1..] {
+                        let key = String::from_utf8_lossy(channel).to_string();
+                        let clients = channels.entry(key).or_default();
+                        clients.retain(|(client, _)| *client != id);
+                        if name == "SUBSCRIBE" {
+                            clients.push((id, tx.clone()));
+                        }
+                        let kind = name.to_lowercase().into_bytes();
+                        let _ = tx.send(push(&kind, channel, b":1\r\n".to_vec()));
+                    }
+                }
+                "PUBLISH" => {
+                    let key = String::from_utf8_lossy(&args[1]).to_string();
+                    let clients = channels.get(&key).cloned().unwrap_or_default();
+                    for (_, client) in &clients {
+                        let _ = client.send(push(b"message", &args[1], bulk(&args[2])));
+                    }
+                    let _ = tx.send(format!(":{}\r\n", clients.len()).into_bytes());
+                }
+                "PING" => {
+                    let _ = tx.send(b"+PONG\r\n".to_vec());
+                }
+                _ => {
+                    let _ = tx.send(b"+OK\r\n".to_vec());
+                }
+            }
+        }
+        writer_task.abort();
+    }
+
+    async fn receive(subscription: &mut Subscription) -> String {
+        timeout(TIMEOUT, subscription.recv())
+            .await
+            .expect("no message in time")
+            .expect("subscription closed")
+    }
+
+    async fn assert_ordered(broker: &dyn Broker, subscription: &mut Subscription, topic: &str) {
+        for i in 0..100 {
+            broker.publish(topic, i.to_string()).await.unwrap();
+        }
+        for i in 0..100 {
+            assert_eq!(receive(subscription).await, i.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_broker_keeps_order_for_every_subscriber() {
+        let broker = MemoryBroker::default();
+        let mut first = broker.subscribe("room").await.unwrap();
+        let mut second = broker.subscribe("room").await.unwrap();
+        let mut other = broker.subscribe("other").await.unwrap();
+
+        assert_ordered(&broker, &mut first, "room").await;
+        for i in 0..100 {
+            assert_eq!(receive(&mut second).await, i.to_string());
+        }
+        assert!(other.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn memory_broker_forgets_dropped_subscriptions() {
+        let broker = MemoryBroker::default();
+        drop(broker.subscribe("room").await.unwrap());
+
+        broker.publish("room", "hi".to_string()).await.unwrap();
+        assert!(broker.topics.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn redis_broker_fans_out_between_instances_in_order() {
+        let redis = FakeRedis::start().await;
+        let first = RedisBroker::connect(&redis.url).await;
+        let second = RedisBroker::connect(&redis.url).await;
+        let mut on_first = first.subscribe("room").await.unwrap();
+        let mut on_second = second.subscribe("room").await.unwrap();
+        assert_eq!(redis.subscribers("chat:room"), 2);
+
+        assert_ordered(&second, &mut on_first, "room").await;
+        for i in 0..100 {
+            assert_eq!(receive(&mut on_second).await, i.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn redis_broker_shares_one_channel_per_topic() {
+        let redis = FakeRedis::start().await;
+        let broker = RedisBroker::connect(&redis.url).await;
+        let mut first = broker.subscribe("room").await.unwrap();
+        let second = broker.subscribe("room").await.unwrap();
+        assert_eq!(redis.subscribers("chat:room"), 1);
+
+        drop(second);
+        assert_ordered(&broker, &mut first, "room").await;
+        assert_eq!(redis.subscribers("chat:room"), 1);
+
+        drop(first);
+        broker.publish("room", "bye".to_string()).await.unwrap();
+        timeout(TIMEOUT, async {
+            while redis.subscribers("chat:room") > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("channel wasn't unsubscribed");
+    }
+
+    #[tokio::test]
+    async fn redis_broker_resubscribes_after_reconnect() {
+        let redis = FakeRedis::start().await;
+        let broker = RedisBroker::connect(&redis.url).await;
+        let mut subscription = broker.subscribe("room").await.unwrap();
+
+        redis.restart();
+        timeout(TIMEOUT, async {
+            while redis.subscribers("chat:room") == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("channel wasn't subscribed again");
+
+        // The first command on the dropped publisher connection may fail before it reconnects
+        let _ = broker.publish("room", "lost".to_string()).await;
+        timeout(TIMEOUT, async {
+            while broker.publish("room", "back".to_string()).await.is_err() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("publisher didn't reconnect");
+        let mut received = receive(&mut subscription).await;
+        if received == "lost" {
+            received = receive(&mut subscription).await;
+        }
+        assert_eq!(received, "back");
+    }
+}
@@ -1,154 +1,2101 @@
+use crate::attachment::{Attachment, AttachmentFiles, Upload, MAX_ATTACHMENTS};
+use crate::broker::{instance_id, Bus, Subscription};
+use crate::command::{self, Commands, Input};
 use crate::error::ChatError;
-use crate::utils::Pagination;
-use futures_util::stream::SplitStream;
-use futures_util::StreamExt;
+use crate::heartbeat::{Heartbeat, HeartbeatConfig, HeartbeatEvent};
+use crate::metrics;
+use crate::moderation::{ModerationAction, Role, Sanction, SanctionKind};
+use crate::outbox::{Outbox, OutboxConfig, QueueMetrics, QueueStats};
+use crate::protocol::{ClientEvent, ProtocolQuery, ServerEvent};
+use crate::rate_limit::{FloodGuard, RateLimitConfig, RoomLimits};
+use crate::search::{SearchParams, SearchResults};
+use crate::shutdown::{ConnectionGuard, Shutdown};
+use crate::storage::{InboxEntry, MessageQuery, Room, Store};
+use crate::utils::{HistoryConfig, Pagination};
+use chrono::{DateTime, Utc};
+use futures_util::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
 use tokio::sync::{mpsc, RwLock};
-use warp::ws::{Message, WebSocket};
+use tokio::task::AbortHandle;
+use tokio::time::Instant;
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
+use warp::ws::Message;
 
-#[derive(Debug, Clone)]
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+const DIRECT_CHAT_PREFIX: &str = "dm:";
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+const ROOM_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+const PRESENCE_INTERVAL: Duration = Duration::from_secs(10);
+const PRESENCE_TTL: Duration = Duration::from_secs(30);
+
+type Connections = HashMap<u64, Outbox>;
+
+#[derive(Clone)]
 struct Chat {
     name: Arc<String>,
-    users: Arc<RwLock<HashMap<String, mpsc::UnboundedSender<Message>>>>,
-    messages: Arc<RwLock<Vec<ChatMessage>>>,
+    users: Arc<RwLock<HashMap<String, Connections>>>,
+    last_activity: Arc<RwLock<Instant>>,
+    store: Store,
+    // Only these users may join a direct chat, empty for public rooms
+    members: Arc<Vec<String>>,
+    limits: Arc<Mutex<RoomLimits>>,
+    history: HistoryConfig,
+    bus: Bus,
+    instance: u64,
+    // Users connected to the other instances serving this chat
+    remote: Arc<RwLock<HashMap<u64, RemoteUsers>>>,
+    presence_ttl: Duration,
+    relay: Arc<OnceLock<AbortHandle>>,
+    files: AttachmentFiles,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-pub struct User {
+// Instances announce their users periodically, the ones that stop, e.g. after a crash,
+// are forgotten once the last announcement is too old
+struct RemoteUsers {
+    users: Vec<String>,
+    seen_at: Instant,
+}
+
+// One user can hold several sessions at once, e.g. a few tabs or devices
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub id: u64,
     pub username: String,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
-pub struct IncomingMessage {
-    text: String,
+impl Session {
+    pub fn new(username: String) -> Self {
+        Session {
+            id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            username,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ChatTarget {
+    Room(String),
+    // Direct chat with the given user
+    Direct(String),
+}
+
+impl ChatTarget {
+    fn chat_name(&self, username: &str) -> Result<String, ChatError> {
+        match self {
+            ChatTarget::Room(name) if name.starts_with(DIRECT_CHAT_PREFIX) => {
+                Err(ChatError::InvalidChatName)
+            }
+            ChatTarget::Room(name) => Ok(name.clone()),
+            ChatTarget::Direct(with) if with.trim().is_empty() || with == username => {
+                Err(ChatError::InvalidRecipient(with.clone()))
+            }
+            ChatTarget::Direct(with) => Ok(direct_chat_name(username, with)),
+        }
+    }
+}
+
+// Same name whoever starts the conversation, ':' is escaped to keep it unambiguous
+fn direct_chat_name(username: &str, with: &str) -> String {
+    let escape = |name: &str| name.replace('%', "%25").replace(':', "%3A");
+    let mut members = [escape(username), escape(with)];
+    members.sort();
+    format!("{DIRECT_CHAT_PREFIX}{}:{}", members[0], members[1])
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
+pub enum Recipients {
+    All,
+    ExceptUser(String),
+    // Session of the instance that published the event
+    ExceptSession(u64),
+}
+
+// Everything the instances serving a chat tell each other through the bus
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Envelope {
+    Event {
+        origin: u64,
+        recipients: Recipients,
+        text: String,
+        key: Option<String>,
+    },
+    Close {
+        username: String,
+        body: String,
+        code: u16,
+        reason: String,
+    },
+    // Full list of the users connected to the origin instance
+    Presence {
+        origin: u64,
+        users: Vec<String>,
+    },
+    // Asks the other instances to announce their users
+    Sync {
+        origin: u64,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RoomConfig {
+    pub rate_limit: RateLimitConfig,
+    pub history: HistoryConfig,
+    // Empty rooms are unloaded after being idle for this long
+    pub idle_timeout: Duration,
+    pub cleanup_interval: Duration,
+    // Users of other instances are announced this often and expire after the ttl
+    pub presence_interval: Duration,
+    pub presence_ttl: Duration,
+}
+
+impl Default for RoomConfig {
+    fn default() -> Self {
+        RoomConfig {
+            rate_limit: RateLimitConfig::default(),
+            history: HistoryConfig::default(),
+            idle_timeout: ROOM_IDLE_TIMEOUT,
+            cleanup_interval: CLEANUP_INTERVAL,
+            presence_interval: PRESENCE_INTERVAL,
+            presence_ttl: PRESENCE_TTL,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct NewChat {
+    pub name: String,
+    pub topic: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ChatInfo {
+    #[serde(flatten)]
+    pub room: Room,
+    pub members: usize,
+    pub messages: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum MessageKind {
+    #[default]
+    Text,
+    // Sent with /me, shown as "* alice waves"
+    Action,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, sqlx::FromRow)]
 pub struct ChatMessage {
-    username: String,
-    text: String,
+    pub id: i64,
+    pub username: String,
+    pub text: String,
+    #[serde(default)]
+    pub kind: MessageKind,
+    pub created_at: DateTime<Utc>,
+    pub edited_at: Option<DateTime<Utc>>,
+    // Replies point to the message that started the thread
+    pub parent_id: Option<i64>,
+    // Replies only shown in the thread, they are left out of the room history
+    #[serde(default)]
+    pub thread_only: bool,
+    #[serde(default)]
+    pub reply_count: i64,
+    #[sqlx(skip)]
+    #[serde(default)]
+    pub reactions: Vec<Reaction>,
+    #[sqlx(skip)]
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplyTo {
+    pub parent_id: i64,
+    pub thread_only: bool,
+}
+
+// A message with its replies, newest first like the history
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Thread {
+    pub message: ChatMessage,
+    pub replies: Vec<ChatMessage>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct ThreadParams {
+    pub before: Option<i64>,
+    pub take: Option<u32>,
+}
+
+// Sent along with the text, a command posting a message gets them too
+#[derive(Debug, Default)]
+struct PostOptions {
+    client_id: Option<String>,
+    attachments: Vec<i64>,
+    reply_to: Option<ReplyTo>,
 }
 
 impl ChatMessage {
-    pub fn new(username: String, text: String) -> Self {
-        Self { username, text }
+    pub fn new(id: i64, username: String, text: String, created_at: DateTime<Utc>) -> Self {
+        Self {
+            id,
+            username,
+            text,
+            kind: MessageKind::Text,
+            created_at,
+            edited_at: None,
+            parent_id: None,
+            thread_only: false,
+            reply_count: 0,
+            reactions: vec![],
+            attachments: vec![],
+        }
+    }
+}
+
+// Reactions keep the order in which each emoji was first used
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Reaction {
+    pub emoji: String,
+    pub users: Vec<String>,
+}
+
+const MAX_EMOJI_LENGTH: usize = 16;
+
+async fn search(
+    store: &Store,
+    chat: &str,
+    params: &SearchParams,
+    history: HistoryConfig,
+) -> Result<SearchResults, ChatError> {
+    let query = params.to_query(history)?;
+    let messages = store.search_messages(chat, &query).await?;
+    Ok(SearchResults::new(messages, &query))
+}
+
+fn validate_emoji(emoji: &str) -> Result<(), ChatError> {
+    if emoji.is_empty()
+        || emoji.chars().count() > MAX_EMOJI_LENGTH
+        || emoji.chars().any(char::is_whitespace)
+    {
+        Err(ChatError::InvalidReaction(emoji.to_string()))?
     }
+    Ok(())
 }
 
 impl Chat {
-    pub fn new(name: String) -> Self {
-        Chat {
+    async fn open(
+        name: String,
+        store: Store,
+        members: Vec<String>,
+        config: RoomConfig,
+        bus: Bus,
+        instance: u64,
+        files: AttachmentFiles,
+    ) -> Result<Self, ChatError> {
+        let subscription = bus.subscribe(&name).await?;
+        let chat = Chat {
             name: Arc::new(name),
             users: Default::default(),
-            messages: Default::default(),
+            last_activity: Arc::new(RwLock::new(Instant::now())),
+            store,
+            members: Arc::new(members),
+            limits: Arc::new(Mutex::new(RoomLimits::new(config.rate_limit))),
+            history: config.history,
+            bus,
+            instance,
+            remote: Default::default(),
+            presence_ttl: config.presence_ttl,
+            relay: Default::default(),
+            files,
+        };
+        let relay = chat.clone().relay(subscription, config.presence_interval);
+        let relay = tokio::spawn(relay.instrument(chat.span()));
+        let _ = chat.relay.set(relay.abort_handle());
+        chat.publish(&Envelope::Sync { origin: instance }).await;
+        Ok(chat)
+    }
+
+    fn span(&self) -> Span {
+        info_span!("room", room = %self.name)
+    }
+
+    // Stops receiving events from the bus once the chat is unloaded
+    fn close(&self) {
+        if let Some(relay) = self.relay.get() {
+            relay.abort();
+        }
+    }
+
+    // Also keeps the presence of this instance fresh on the others
+    async fn relay(self, mut subscription: Subscription, presence_interval: Duration) {
+        let start = Instant::now() + presence_interval;
+        let mut presence = tokio::time::interval_at(start, presence_interval);
+        loop {
+            tokio::select! {
+                payload = subscription.recv() => {
+                    let Some(payload) = payload else { break };
+                    match serde_json::from_str(&payload) {
+                        Ok(envelope) => self.deliver(envelope).await,
+                        Err(error) => warn!(%error, "invalid envelope"),
+                    }
+                }
+                _ = presence.tick() => {
+                    self.announce().await;
+                    self.expire_remote().await;
+                }
+            }
         }
     }
 
-    pub async fn disconnect_user(&self, username: &String) {
-        eprintln!("good bye user: {}", username);
-        self.users.write().await.remove(username);
+    async fn publish(&self, envelope: &Envelope) {
+        let payload = serde_json::to_string(envelope).unwrap();
+        if let Err(error) = self.bus.publish(&self.name, payload).await {
+            error!(room = %self.name, %error, "publish failed");
+        }
     }
 
-    pub async fn get_start_messages(&self, _page: Pagination, tx: mpsc::UnboundedSender<Message>) {
-        let messages = self.messages.read().await;
-        let all_messages = serde_json::to_string(&*messages).unwrap();
-        let a = tx.send(Message::text(all_messages));
-        if a.is_err() {
-            println!("{}", a.err().unwrap())
+    async fn deliver(&self, envelope: Envelope) {
+        match envelope {
+            Envelope::Event {
+                origin,
+                recipients,
+                text,
+                key,
+            } => {
+                let skip_session = match recipients {
+                    Recipients::ExceptSession(id) if origin == self.instance => Some(id),
+                    _ => None,
+                };
+                let skip_user = match &recipients {
+                    Recipients::ExceptUser(username) => Some(username.as_str()),
+                    _ => None,
+                };
+                self.push(Message::text(text), key.as_deref(), skip_user, skip_session)
+                    .await;
+            }
+            Envelope::Close {
+                username,
+                body,
+                code,
+                reason,
+            } => {
+                if let Some(connections) = self.users.read().await.get(&username) {
+                    for tx in connections.values() {
+                        close_session(tx, &body, code, &reason);
+                    }
+                }
+            }
+            Envelope::Presence { origin, users } if origin != self.instance => {
+                let changed = {
+                    let mut remote = self.remote.write().await;
+                    let previous = match users.is_empty() {
+                        true => remote.remove(&origin),
+                        false => {
+                            let seen_at = Instant::now();
+                            let current = RemoteUsers {
+                                users: users.clone(),
+                                seen_at,
+                            };
+                            remote.insert(origin, current)
+                        }
+                    };
+                    previous.map(|previous| previous.users).unwrap_or_default() != users
+                };
+                // Periodic announcements mostly repeat what is known already
+                if changed {
+                    self.push_roster().await;
+                }
+            }
+            Envelope::Sync { origin } if origin != self.instance => self.announce().await,
+            _ => {}
         }
     }
 
+    // Hands the message to the local sessions
+    async fn push(
+        &self,
+        message: Message,
+        key: Option<&str>,
+        skip_user: Option<&str>,
+        skip_session: Option<u64>,
+    ) {
+        for (username, connections) in self.users.read().await.iter() {
+            if skip_user == Some(username.as_str()) {
+                continue;
+            }
+            for (session_id, tx) in connections {
+                if skip_session == Some(*session_id) {
+                    continue;
+                }
+                if let Err(error) = tx.push(message.clone(), key) {
+                    // The session is closing or too slow, its own task cleans it up
+                    debug!(session = session_id, %error, "broadcast failed");
+                }
+            }
+        }
+    }
+
+    async fn push_roster(&self) {
+        let roster = ServerEvent::Roster {
+            users: self.usernames().await,
+        };
+        self.push(
+            roster.to_message(),
+            roster.coalesce_key().as_deref(),
+            None,
+            None,
+        )
+        .await;
+    }
+
+    async fn expire_remote(&self) {
+        let expired = {
+            let mut remote = self.remote.write().await;
+            let count = remote.len();
+            remote.retain(|_, remote| remote.seen_at.elapsed() <= self.presence_ttl);
+            remote.len() < count
+        };
+        if expired {
+            self.push_roster().await;
+        }
+    }
+
+    async fn announce(&self) {
+        let users = self.users.read().await.keys().cloned().collect();
+        let presence = Envelope::Presence {
+            origin: self.instance,
+            users,
+        };
+        self.publish(&presence).await;
+    }
+
+    async fn is_remote(&self, username: &str) -> bool {
+        let remote = self.remote.read().await;
+        remote
+            .values()
+            .flat_map(|remote| &remote.users)
+            .any(|user| user == username)
+    }
+
+    fn is_direct(&self) -> bool {
+        !self.members.is_empty()
+    }
+
+    // Every broadcast a user triggers takes a token, like a message
+    fn check_rate(&self, session: &Session) -> Result<(), ChatError> {
+        self.limits.lock().unwrap().check_event(&session.username)
+    }
+
+    async fn mark_read(&self, username: &str, id: i64) -> Result<(), ChatError> {
+        let last_read_id = self.store.mark_read(&self.name, username, id).await?;
+        let read = ServerEvent::Read {
+            username: username.to_string(),
+            last_read_id,
+        };
+        self.broadcast(&read, Recipients::All).await;
+        Ok(())
+    }
+
+    // Messages delivered to a connected member of a direct chat count as read
+    async fn mark_delivered(&self, id: i64) -> Result<(), ChatError> {
+        if self.is_direct() {
+            for username in self.usernames().await {
+                self.mark_read(&username, id).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn mark_all_delivered(&self, username: &str) -> Result<(), ChatError> {
+        if !self.is_direct() {
+            return Ok(());
+        }
+        let query = MessageQuery {
+            before: None,
+            after: None,
+            offset: 0,
+            take: 1,
+        };
+        if let Some(last) = self.store.list_messages(&self.name, query).await?.pop() {
+            self.mark_read(username, last.id).await?;
+        }
+        Ok(())
+    }
+
+    async fn touch(&self) {
+        *self.last_activity.write().await = Instant::now();
+    }
+
+    async fn is_idle(&self, idle_timeout: Duration) -> bool {
+        self.users.read().await.is_empty()
+            && self.last_activity.read().await.elapsed() >= idle_timeout
+    }
+
+    // Returns true for the first session of the user on any instance
+    async fn connect(&self, session: &Session, tx: Outbox) -> bool {
+        let is_first_session = {
+            let mut users = self.users.write().await;
+            let connections = users.entry(session.username.clone()).or_default();
+            connections.insert(session.id, tx);
+            connections.len() == 1
+        };
+        if is_first_session {
+            self.announce().await;
+        }
+        is_first_session && !self.is_remote(&session.username).await
+    }
+
+    pub async fn disconnect(&self, session: &Session) {
+        info!("user left");
+        let is_last_session = {
+            let mut users = self.users.write().await;
+            match users.get_mut(&session.username) {
+                Some(connections) => {
+                    connections.remove(&session.id);
+                    if connections.is_empty() {
+                        users.remove(&session.username);
+                    }
+                    !users.contains_key(&session.username)
+                }
+                None => false,
+            }
+        };
+        self.touch().await;
+        if is_last_session {
+            self.announce().await;
+        }
+        if is_last_session && !self.is_remote(&session.username).await {
+            let leave = ServerEvent::Leave {
+                username: session.username.clone(),
+            };
+            self.broadcast(&leave, Recipients::ExceptUser(session.username.clone()))
+                .await;
+        }
+    }
+
+    pub async fn usernames(&self) -> Vec<String> {
+        let mut usernames: Vec<String> = self.users.read().await.keys().cloned().collect();
+        let remote = self.remote.read().await;
+        usernames.extend(remote.values().flat_map(|remote| remote.users.clone()));
+        usernames.sort();
+        usernames.dedup();
+        usernames
+    }
+
+    // Goes through the bus, so every instance delivers the room events in the same order
+    pub async fn broadcast(&self, event: &ServerEvent, recipients: Recipients) {
+        let envelope = Envelope::Event {
+            origin: self.instance,
+            recipients,
+            text: event.to_json(),
+            key: event.coalesce_key(),
+        };
+        self.publish(&envelope).await;
+    }
+
+    // Resuming clients get the oldest missed messages first and page on from there
+    pub async fn get_start_messages(&self, page: Pagination, tx: &Outbox) -> Result<(), ChatError> {
+        let query = MessageQuery {
+            before: None,
+            after: page.last_seen,
+            offset: page.offset(),
+            take: page.take(self.history),
+        };
+        self.send_history(query, tx).await
+    }
+
+    pub async fn get_history(
+        &self,
+        before: Option<i64>,
+        after: Option<i64>,
+        take: Option<u32>,
+        tx: &Outbox,
+    ) -> Result<(), ChatError> {
+        let query = MessageQuery {
+            before,
+            after,
+            offset: 0,
+            take: self.history.limit_take(take),
+        };
+        self.send_history(query, tx).await
+    }
+
+    // Half of the messages come after the given one, the rest before it
+    async fn send_context(&self, id: i64, take: Option<u32>, tx: &Outbox) -> Result<(), ChatError> {
+        self.get_message(id).await?;
+        let take = self.history.limit_take(take);
+        let mut messages = self
+            .store
+            .list_messages_after(&self.name, id, take / 2)
+            .await?;
+        let query = MessageQuery {
+            before: Some(id + 1),
+            after: None,
+            offset: 0,
+            take: take - messages.len(),
+        };
+        messages.extend(self.store.list_messages(&self.name, query).await?);
+        tx.send_event(&ServerEvent::Context { id, messages })?;
+        Ok(())
+    }
+
+    // One more message than the page is loaded to tell if there are more
+    async fn send_history(&self, query: MessageQuery, tx: &Outbox) -> Result<(), ChatError> {
+        let take = query.take;
+        let (messages, has_more) = match query.after {
+            Some(after) => {
+                let mut messages = self
+                    .store
+                    .list_messages_after(&self.name, after, take + 1)
+                    .await?;
+                // Newest first, so the extra message is the first one
+                let has_more = messages.len() > take;
+                if has_more {
+                    messages.remove(0);
+                }
+                (messages, has_more)
+            }
+            None => {
+                let query = MessageQuery {
+                    take: take + 1,
+                    ..query
+                };
+                let mut messages = self.store.list_messages(&self.name, query).await?;
+                let has_more = messages.len() > take;
+                messages.truncate(take);
+                (messages, has_more)
+            }
+        };
+        let read = self.store.list_read_receipts(&self.name).await?;
+        tx.send_event(&ServerEvent::History {
+            messages,
+            read,
+            has_more,
+        })?;
+        Ok(())
+    }
+
     pub async fn send_messages(
         &self,
-        username: &String,
-        mut user_ws_rx: SplitStream<WebSocket>,
+        session: &Session,
+        mut incoming: impl Stream<Item = Result<Message, warp::Error>> + Unpin,
+        tx: Outbox,
+        heartbeat_config: HeartbeatConfig,
+        commands: &Commands,
     ) -> Result<(), ChatError> {
-        while let Some(result) = user_ws_rx.next().await {
-            let mess = receive_message(result)?;
-            let IncomingMessage { text } = serde_json::from_str::<IncomingMessage>(&mess)?;
-            let message = ChatMessage::new(username.clone(), text.clone().parse().unwrap());
-            self.messages.write().await.insert(0, message.clone());
-
-            for (other_username, tx) in self.users.read().await.iter() {
-                if username != other_username {
-                    let msg = serde_json::to_string(&vec![message.clone()])?;
-                    if let Err(_disconnected) = tx.send(Message::text(msg)) {
-                        // The tx is disconnected
+        let mut heartbeat = Heartbeat::new(heartbeat_config);
+        let mut flood_guard = FloodGuard::new(self.limits.lock().unwrap().config());
+        loop {
+            let result = tokio::select! {
+                biased;
+                _ = tx.aborted() => Err(ChatError::SlowConsumer)?,
+                // Closed from the outside, e.g. the user was kicked
+                _ = tx.closed() => break,
+                result = incoming.next() => result,
+                event = heartbeat.next() => {
+                    match event {
+                        HeartbeatEvent::Ping => {
+                            tx.send(Message::ping(vec![]))?;
+                            heartbeat.ping_sent();
+                        }
+                        HeartbeatEvent::PongTimeout => Err(ChatError::HeartbeatTimeout)?,
+                        HeartbeatEvent::IdleTimeout => Err(ChatError::IdleTimeout)?,
                     }
+                    continue;
+                }
+            };
+            let Some(result) = result else {
+                break;
+            };
+            heartbeat.alive();
+            let Some(mess) = receive_message(result)? else {
+                continue;
+            };
+            heartbeat.active();
+            let event = serde_json::from_str::<ClientEvent>(&mess)?;
+            if let Err(error) = self.handle_event(session, event, &tx, commands).await {
+                if !error.is_recoverable() {
+                    return Err(error);
                 }
+                debug!(%error, code = error.code(), "event failed");
+                metrics::error_sent(&error);
+                tx.send(error.to_request_body())?;
+                flood_guard.record(&error)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn post_or_run(
+        &self,
+        session: &Session,
+        text: &str,
+        options: PostOptions,
+        tx: &Outbox,
+        commands: &Commands,
+    ) -> Result<(), ChatError> {
+        match command::parse(text) {
+            Input::Text(text) => {
+                self.post_message(session, text, MessageKind::Text, &options, tx)
+                    .await
+            }
+            Input::Command { name, args } => {
+                let command = commands
+                    .get(name)
+                    .ok_or_else(|| ChatError::UnknownCommand(name.to_string()))?;
+                let context = CommandContext {
+                    chat: self,
+                    session,
+                    tx,
+                    commands,
+                    options,
+                };
+                command.run(&context, args).await
+            }
+        }
+    }
+
+    async fn handle_event(
+        &self,
+        session: &Session,
+        event: ClientEvent,
+        tx: &Outbox,
+        commands: &Commands,
+    ) -> Result<(), ChatError> {
+        match event {
+            ClientEvent::Message {
+                text,
+                client_id,
+                attachments,
+                parent_id,
+                thread_only,
+            } => {
+                let options = PostOptions {
+                    client_id,
+                    attachments,
+                    reply_to: parent_id.map(|parent_id| ReplyTo {
+                        parent_id,
+                        thread_only,
+                    }),
+                };
+                self.post_or_run(session, &text, options, tx, commands)
+                    .await?;
+            }
+            ClientEvent::Thread { id, before, take } => {
+                let params = ThreadParams { before, take };
+                let thread =
+                    load_thread(&self.store, &self.name, id, &params, self.history).await?;
+                tx.send_event(&ServerEvent::Thread(thread))?;
+            }
+            ClientEvent::History {
+                before,
+                after,
+                take,
+            } => {
+                self.get_history(before, after, take, tx).await?;
+            }
+            ClientEvent::Search(params) => {
+                let results = search(&self.store, &self.name, &params, self.history).await?;
+                tx.send_event(&ServerEvent::Search(results))?;
+            }
+            ClientEvent::Context { id, take } => {
+                self.send_context(id, take, tx).await?;
+            }
+            ClientEvent::Typing => {
+                if !self.limits.lock().unwrap().check_typing(&session.username)
+                    || self.check_not_muted(session).await.is_err()
+                {
+                    return Ok(());
+                }
+                let typing = ServerEvent::Typing {
+                    username: session.username.clone(),
+                };
+                let recipients = Recipients::ExceptUser(session.username.clone());
+                self.broadcast(&typing, recipients).await;
+            }
+            ClientEvent::Read { id } => {
+                // Receipts over the limit are only stored, the next one announces them
+                if self.limits.lock().unwrap().check_signal(&session.username) {
+                    self.mark_read(&session.username, id).await?;
+                } else {
+                    self.store
+                        .mark_read(&self.name, &session.username, id)
+                        .await?;
+                }
+            }
+            ClientEvent::Edit { id, text } => {
+                self.check_not_muted(session).await?;
+                self.limits.lock().unwrap().check_length(&text)?;
+                self.get_modifiable_message(session, id).await?;
+                self.check_rate(session)?;
+                let message = self.store.update_message(&self.name, id, &text).await?;
+                self.broadcast(&ServerEvent::Edited { message }, Recipients::All)
+                    .await;
+            }
+            // Replies are deleted with the message that started the thread
+            ClientEvent::Delete { id } => {
+                let message = self.get_modifiable_message(session, id).await?;
+                self.check_rate(session)?;
+                let query = MessageQuery {
+                    before: None,
+                    after: None,
+                    offset: 0,
+                    take: message.reply_count as usize,
+                };
+                let replies = self.store.list_replies(&self.name, id, query).await?;
+                self.store.delete_message(&self.name, id).await?;
+                self.files.remove(&message.attachments).await;
+                for reply in replies {
+                    self.files.remove(&reply.attachments).await;
+                }
+                self.broadcast(&ServerEvent::Deleted { id }, Recipients::All)
+                    .await;
+                if let Some(parent_id) = message.parent_id {
+                    self.announce_replies(parent_id).await?;
+                }
+            }
+            ClientEvent::React { id, emoji } => {
+                self.check_not_muted(session).await?;
+                validate_emoji(&emoji)?;
+                self.get_message(id).await?;
+                self.check_rate(session)?;
+                let reactions = self
+                    .store
+                    .add_reaction(&self.name, id, &session.username, &emoji)
+                    .await?;
+                self.broadcast(&ServerEvent::Reactions { id, reactions }, Recipients::All)
+                    .await;
+            }
+            ClientEvent::Unreact { id, emoji } => {
+                self.get_message(id).await?;
+                self.check_rate(session)?;
+                let reactions = self
+                    .store
+                    .remove_reaction(&self.name, id, &session.username, &emoji)
+                    .await?;
+                self.broadcast(&ServerEvent::Reactions { id, reactions }, Recipients::All)
+                    .await;
+            }
+            ClientEvent::Moderate {
+                action,
+                username,
+                reason,
+                expires_in,
+            } => {
+                self.moderate(session, action, username, reason, expires_in)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn post_message(
+        &self,
+        session: &Session,
+        text: &str,
+        kind: MessageKind,
+        options: &PostOptions,
+        tx: &Outbox,
+    ) -> Result<(), ChatError> {
+        self.check_not_muted(session).await?;
+        if options.attachments.len() > MAX_ATTACHMENTS {
+            Err(ChatError::TooManyAttachments(MAX_ATTACHMENTS))?
+        }
+        if let Some(reply_to) = options.reply_to {
+            get_thread_root(&self.store, &self.name, reply_to.parent_id).await?;
+        }
+        self.limits
+            .lock()
+            .unwrap()
+            .check_message(&session.username, text)?;
+        let message = self
+            .store
+            .insert_message(
+                &self.name,
+                &session.username,
+                text,
+                kind,
+                &options.attachments,
+                options.reply_to,
+            )
+            .await?;
+        self.touch().await;
+        metrics::message_posted();
+        self.mark_delivered(message.id).await?;
+        tx.send_event(&ServerEvent::Ack {
+            client_id: options.client_id.clone(),
+            id: message.id,
+        })?;
+        let event = match message.thread_only {
+            true => ServerEvent::Reply { message },
+            false => ServerEvent::Message { message },
+        };
+        self.broadcast(&event, Recipients::ExceptSession(session.id))
+            .await;
+        if let Some(reply_to) = options.reply_to {
+            self.announce_replies(reply_to.parent_id).await?;
+        }
+        Ok(())
+    }
+
+    async fn announce_replies(&self, id: i64) -> Result<(), ChatError> {
+        let reply_count = self.get_message(id).await?.reply_count;
+        self.broadcast(&ServerEvent::Replies { id, reply_count }, Recipients::All)
+            .await;
+        Ok(())
+    }
+
+    async fn get_message(&self, id: i64) -> Result<ChatMessage, ChatError> {
+        self.store
+            .get_message(&self.name, id)
+            .await?
+            .ok_or(ChatError::MessageNotFound(id))
+    }
+
+    // Only the author or a moderator is allowed to change a message
+    async fn get_modifiable_message(
+        &self,
+        session: &Session,
+        id: i64,
+    ) -> Result<ChatMessage, ChatError> {
+        let message = self.get_message(id).await?;
+        if message.username != session.username
+            && !self.role(&session.username).await?.can_moderate()
+        {
+            Err(ChatError::MessageForbidden(id))?
+        }
+        Ok(message)
+    }
+
+    async fn role(&self, username: &str) -> Result<Role, ChatError> {
+        self.store.get_role(&self.name, username).await
+    }
+
+    async fn active_sanction(
+        &self,
+        username: &str,
+        kind: SanctionKind,
+    ) -> Result<Option<Sanction>, ChatError> {
+        let sanction = self.store.get_sanction(&self.name, username, kind).await?;
+        match sanction {
+            Some(sanction) if !sanction.is_active() => {
+                self.store
+                    .delete_sanction(&self.name, username, kind)
+                    .await?;
+                Ok(None)
+            }
+            sanction => Ok(sanction),
+        }
+    }
+
+    async fn check_not_banned(&self, username: &str) -> Result<(), ChatError> {
+        if self
+            .active_sanction(username, SanctionKind::Ban)
+            .await?
+            .is_some()
+        {
+            Err(ChatError::Banned(self.name.to_string()))?
+        }
+        Ok(())
+    }
+
+    async fn check_not_muted(&self, session: &Session) -> Result<(), ChatError> {
+        let mute = self.active_sanction(&session.username, SanctionKind::Mute);
+        if mute.await?.is_some() {
+            Err(ChatError::Muted)?
+        }
+        Ok(())
+    }
+
+    async fn moderate(
+        &self,
+        session: &Session,
+        action: ModerationAction,
+        username: String,
+        reason: Option<String>,
+        expires_in: Option<u64>,
+    ) -> Result<(), ChatError> {
+        let role = self.role(&session.username).await?;
+        let target_role = self.role(&username).await?;
+        if self.is_direct()
+            || username == session.username
+            || role < action.required_role()
+            || !role.outranks(target_role)
+        {
+            Err(ChatError::ModerationForbidden(username.clone()))?
+        }
+        self.check_rate(session)?;
+
+        let mut expires_at = None;
+        match action {
+            ModerationAction::Kick => {}
+            ModerationAction::Ban | ModerationAction::Mute => {
+                let kind = match action {
+                    ModerationAction::Ban => SanctionKind::Ban,
+                    _ => SanctionKind::Mute,
+                };
+                let sanction = Sanction::new(
+                    username.clone(),
+                    kind,
+                    reason.clone(),
+                    expires_in,
+                    session.username.clone(),
+                );
+                expires_at = sanction.expires_at;
+                self.store.insert_sanction(&self.name, &sanction).await?;
+            }
+            ModerationAction::Unban => {
+                let kind = SanctionKind::Ban;
+                self.store
+                    .delete_sanction(&self.name, &username, kind)
+                    .await?;
+            }
+            ModerationAction::Unmute => {
+                let kind = SanctionKind::Mute;
+                self.store
+                    .delete_sanction(&self.name, &username, kind)
+                    .await?;
+            }
+            ModerationAction::Promote => {
+                let role = Role::Moderator;
+                self.store.set_role(&self.name, &username, role).await?;
+            }
+            ModerationAction::Demote => {
+                let role = Role::Member;
+                self.store.set_role(&self.name, &username, role).await?;
+            }
+        }
+
+        let text = action.describe(&username, &session.username, reason.as_deref(), expires_at);
+        let system = ServerEvent::System {
+            action,
+            username: username.clone(),
+            by: session.username.clone(),
+            text,
+        };
+        self.broadcast(&system, Recipients::All).await;
+
+        match action {
+            ModerationAction::Kick => {
+                self.kick(&username, ChatError::Kicked(session.username.clone()))
+                    .await
+            }
+            ModerationAction::Ban => {
+                self.kick(&username, ChatError::Banned(self.name.to_string()))
+                    .await
             }
+            _ => {}
         }
         Ok(())
     }
+
+    // Only the sessions of this instance, the others keep running
+    async fn close_local_sessions(&self, error: &ChatError) {
+        let body = error.to_request_json();
+        for tx in self.users.read().await.values().flat_map(HashMap::values) {
+            close_session(tx, &body, error.close_code(), &error.public_message());
+        }
+    }
+
+    // Closes every session of the user on every instance, they are released by their own tasks
+    async fn kick(&self, username: &str, error: ChatError) {
+        let close = Envelope::Close {
+            username: username.to_string(),
+            body: error.to_request_json(),
+            code: error.close_code(),
+            reason: error.public_message(),
+        };
+        self.publish(&close).await;
+    }
+}
+
+// What a command can see and do in the chat it was typed in
+pub struct CommandContext<'a> {
+    chat: &'a Chat,
+    session: &'a Session,
+    tx: &'a Outbox,
+    commands: &'a Commands,
+    options: PostOptions,
 }
 
-fn receive_message(message: Result<Message, warp::Error>) -> Result<String, ChatError> {
+impl CommandContext<'_> {
+    pub fn commands(&self) -> &Commands {
+        self.commands
+    }
+
+    pub async fn room(&self) -> Result<Room, ChatError> {
+        self.chat
+            .store
+            .get_room(&self.chat.name)
+            .await?
+            .ok_or_else(|| ChatError::ChatNotFound(self.chat.name.to_string()))
+    }
+
+    pub async fn role(&self) -> Result<Role, ChatError> {
+        self.chat.role(&self.session.username).await
+    }
+
+    // Users connected to any instance
+    pub async fn usernames(&self) -> Vec<String> {
+        self.chat.usernames().await
+    }
+
+    pub async fn nicknames(&self) -> Result<HashMap<String, String>, ChatError> {
+        self.chat.store.list_nicknames(&self.chat.name).await
+    }
+
+    pub fn reply(&self, text: impl Into<String>) -> Result<(), ChatError> {
+        self.tx
+            .send_event(&ServerEvent::Notice { text: text.into() })
+    }
+
+    pub async fn broadcast(&self, event: &ServerEvent) {
+        self.chat.broadcast(event, Recipients::All).await
+    }
+
+    // Goes through the same checks as a typed message, in the same thread with the attachments
+    pub async fn send_message(&self, text: &str, kind: MessageKind) -> Result<(), ChatError> {
+        self.chat
+            .post_message(self.session, text, kind, &self.options, self.tx)
+            .await
+    }
+
+    // Nobody else in the chat can go by the same name, whatever the case
+    pub async fn set_nickname(&self, nickname: Option<&str>) -> Result<(), ChatError> {
+        self.chat.check_not_muted(self.session).await?;
+        let username = &self.session.username;
+        if let Some(nickname) = nickname {
+            let mut others = self.chat.store.list_members(&self.chat.name).await?;
+            others.extend(self.usernames().await);
+            others.retain(|other| other != username);
+            let nicknames = self.nicknames().await?.into_iter();
+            others.extend(
+                nicknames.filter_map(|(other, nickname)| (other != *username).then_some(nickname)),
+            );
+            let nickname_lowercase = nickname.to_lowercase();
+            if others
+                .iter()
+                .any(|other| other.to_lowercase() == nickname_lowercase)
+            {
+                Err(ChatError::NicknameTaken(nickname.to_string()))?
+            }
+        }
+        self.chat.check_rate(self.session)?;
+        self.chat
+            .store
+            .set_nickname(&self.chat.name, username, nickname)
+            .await?;
+        let nick = ServerEvent::Nick {
+            username: username.clone(),
+            nickname: nickname.map(str::to_string),
+        };
+        self.broadcast(&nick).await;
+        Ok(())
+    }
+
+    pub async fn set_topic(&self, topic: Option<&str>) -> Result<(), ChatError> {
+        self.chat.check_rate(self.session)?;
+        self.chat.store.set_topic(&self.chat.name, topic).await?;
+        let topic = ServerEvent::Topic {
+            topic: topic.map(str::to_string),
+            username: self.session.username.clone(),
+        };
+        self.broadcast(&topic).await;
+        Ok(())
+    }
+
+    // Closes this session only, the other ones of the user stay in the chat
+    pub fn leave(&self) {
+        let _ = self
+            .tx
+            .send(Message::close_with(1000u16, "You left the chat"));
+        self.tx.close();
+    }
+}
+
+// Control frames are answered by the websocket itself and carry no event
+fn receive_message(message: Result<Message, warp::Error>) -> Result<Option<String>, ChatError> {
     let msg = message?;
+    if msg.is_ping() || msg.is_pong() || msg.is_close() {
+        return Ok(None);
+    }
     let msg = msg.to_str().map_err(|_| ChatError::InvalidMessage())?;
-    Ok(msg.to_string())
+    Ok(Some(msg.to_string()))
+}
+
+// Threads only go one level deep, replies can't be replied to
+async fn get_thread_root(store: &Store, chat: &str, id: i64) -> Result<ChatMessage, ChatError> {
+    let message = store
+        .get_message(chat, id)
+        .await?
+        .ok_or(ChatError::MessageNotFound(id))?;
+    match message.parent_id {
+        Some(_) => Err(ChatError::InvalidThread(id)),
+        None => Ok(message),
+    }
+}
+
+async fn load_thread(
+    store: &Store,
+    chat: &str,
+    id: i64,
+    params: &ThreadParams,
+    history: HistoryConfig,
+) -> Result<Thread, ChatError> {
+    let message = get_thread_root(store, chat, id).await?;
+    let query = MessageQuery {
+        before: params.before,
+        after: None,
+        offset: 0,
+        take: history.limit_take(params.take),
+    };
+    let replies = store.list_replies(chat, id, query).await?;
+    Ok(Thread { message, replies })
 }
 
-#[derive(Default, Clone)]
+fn close_session(tx: &Outbox, body: &str, code: u16, reason: &str) {
+    let _ = tx.send(Message::text(body));
+    let _ = tx.send(Message::close_with(code, reason.to_string()));
+    tx.close();
+}
+
+fn close_with_error(tx: &Outbox, error: ChatError) {
+    info!(%error, code = error.code(), "closing connection");
+    metrics::error_sent(&error);
+    let _ = tx.send(error.to_request_body());
+    let _ = tx.send(error.to_close_frame());
+}
+
+#[derive(Clone)]
 pub struct Chats {
     chats: Arc<RwLock<HashMap<String, Chat>>>,
+    store: Store,
+    outbox_config: OutboxConfig,
+    queue_metrics: Arc<QueueMetrics>,
+    heartbeat_config: HeartbeatConfig,
+    room_config: RoomConfig,
+    bus: Bus,
+    instance: u64,
+    files: AttachmentFiles,
+    commands: Arc<Commands>,
+    // Sessions of the server-sent events transport, they get client events over POST
+    streams: Arc<RwLock<HashMap<u64, StreamSession>>>,
+    shutdown: Shutdown,
+}
+
+struct StreamSession {
+    username: String,
+    events: mpsc::Sender<Message>,
 }
 
 impl Chats {
+    pub fn new(
+        store: Store,
+        bus: Bus,
+        outbox_config: OutboxConfig,
+        heartbeat_config: HeartbeatConfig,
+        room_config: RoomConfig,
+        files: AttachmentFiles,
+        commands: Commands,
+    ) -> Self {
+        Chats {
+            chats: Default::default(),
+            store,
+            outbox_config,
+            queue_metrics: Default::default(),
+            heartbeat_config,
+            room_config,
+            bus,
+            instance: instance_id(),
+            files,
+            commands: Arc::new(commands),
+            streams: Default::default(),
+            shutdown: Default::default(),
+        }
+    }
+
+    pub async fn open_stream(&self, session: &Session, events: mpsc::Sender<Message>) {
+        let stream = StreamSession {
+            username: session.username.clone(),
+            events,
+        };
+        self.streams.write().await.insert(session.id, stream);
+    }
+
+    pub async fn close_stream(&self, session_id: u64) {
+        self.streams.write().await.remove(&session_id);
+    }
+
+    // Hands the event to the stream session as if it came over a websocket
+    pub async fn post_event(
+        &self,
+        session_id: u64,
+        username: &str,
+        body: &[u8],
+    ) -> Result<(), ChatError> {
+        serde_json::from_slice::<ClientEvent>(body)?;
+        let text = std::str::from_utf8(body).map_err(|_| ChatError::InvalidMessage())?;
+        let events = match self.streams.read().await.get(&session_id) {
+            // Other users' sessions look the same as missing ones
+            Some(stream) if stream.username == username => stream.events.clone(),
+            _ => Err(ChatError::SessionNotFound(session_id))?,
+        };
+        events
+            .send(Message::text(text))
+            .await
+            .map_err(|_| ChatError::SessionNotFound(session_id))
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.is_started()
+    }
+
+    // None once the server is shutting down
+    pub fn track_connection(&self) -> Option<ConnectionGuard> {
+        self.shutdown.track()
+    }
+
+    // Closes the sessions with a restart notice, waits for them to go and closes the store
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.shutdown.start();
+        let error = ChatError::ServerShutdown;
+        for chat in self.chats.read().await.values() {
+            chat.close_local_sessions(&error).await;
+        }
+        info!(
+            connections = self.shutdown.connections(),
+            "closing connections"
+        );
+        if tokio::time::timeout(timeout, self.shutdown.drained())
+            .await
+            .is_err()
+        {
+            warn!(
+                connections = self.shutdown.connections(),
+                ?timeout,
+                "connections didn't close in time"
+            );
+        }
+        for chat in self.chats.write().await.drain().map(|(_, chat)| chat) {
+            chat.close();
+        }
+        self.store.close().await;
+    }
+
+    pub fn max_attachment_size(&self) -> usize {
+        self.files.max_size()
+    }
+
+    pub fn outbox(&self) -> Outbox {
+        Outbox::new(self.outbox_config, self.queue_metrics.clone())
+    }
+
+    pub fn queue_stats(&self) -> QueueStats {
+        self.queue_metrics.stats()
+    }
+
+    // Users connected to this instance in each public room
+    pub async fn user_counts(&self) -> Vec<(String, usize)> {
+        let mut counts = vec![];
+        for chat in self.chats.read().await.values() {
+            if !chat.is_direct() {
+                counts.push((chat.name.to_string(), chat.users.read().await.len()));
+            }
+        }
+        counts
+    }
+
     pub async fn join(
         &self,
-        req_chat_name: String,
-        username: String,
+        target: ChatTarget,
+        session: Session,
         page: Pagination,
-        user_ws_rx: SplitStream<WebSocket>,
-        tx: mpsc::UnboundedSender<Message>,
+        protocol: ProtocolQuery,
+        incoming: impl Stream<Item = Result<Message, warp::Error>> + Unpin,
+        tx: Outbox,
     ) {
+        let version = match protocol.negotiate() {
+            Ok(version) => version,
+            Err(error) => return close_with_error(&tx, error),
+        };
+
         let chat = self
-            .insert_chat(req_chat_name, username.clone(), tx.clone())
+            .insert_chat(target, &session, version, tx.clone())
             .await;
 
         match chat {
-            Ok(chat) => {
-                chat.get_start_messages(page, tx.clone()).await;
-                let res = chat.send_messages(&username, user_ws_rx).await;
-                if res.is_err() {
-                    let message = res.err().unwrap().to_request_body();
-                    let _ = tx.send(message);
+            // Joined while the sessions were being closed
+            Ok((chat, _, _)) if self.is_shutting_down() => {
+                chat.disconnect(&session).await;
+                close_with_error(&tx, ChatError::ServerShutdown);
+            }
+            Ok((chat, is_first_session, welcome)) => {
+                let span = chat.span();
+                async move {
+                    info!("user joined");
+                    let _ = tx.send_event(&welcome);
+                    let roster = ServerEvent::Roster {
+                        users: chat.usernames().await,
+                    };
+                    let _ = tx.send_event(&roster);
+                    if is_first_session {
+                        let join = ServerEvent::Join {
+                            username: session.username.clone(),
+                        };
+                        let recipients = Recipients::ExceptUser(session.username.clone());
+                        chat.broadcast(&join, recipients).await;
+                    }
+                    let start = match chat.get_start_messages(page, &tx).await {
+                        Ok(()) => chat.mark_all_delivered(&session.username).await,
+                        Err(error) => Err(error),
+                    };
+                    let res = match start {
+                        Ok(()) => {
+                            chat.send_messages(
+                                &session,
+                                incoming,
+                                tx.clone(),
+                                self.heartbeat_config,
+                                &self.commands,
+                            )
+                            .await
+                        }
+                        Err(error) => Err(error),
+                    };
+                    // The session is released however the connection ended
+                    chat.disconnect(&session).await;
+                    if let Err(error) = res {
+                        close_with_error(&tx, error);
+                    }
                 }
-                chat.disconnect_user(&username).await;
+                .instrument(span)
+                .await
             }
-            Err(error) => {
-                let _ = tx.send(error.to_request_body());
+            Err(error) => close_with_error(&tx, error),
+        }
+    }
+
+    pub async fn users(&self, chat_name: &str, username: &str) -> Result<Vec<String>, ChatError> {
+        self.check_readable(chat_name, username).await?;
+        let chat = self.chats.read().await.get(chat_name).cloned();
+        match chat {
+            Some(chat) => Ok(chat.usernames().await),
+            None => Ok(vec![]),
+        }
+    }
+
+    pub async fn list(&self) -> Result<Vec<ChatInfo>, ChatError> {
+        let mut chats_info = vec![];
+        for room in self.store.list_rooms().await? {
+            if !room.direct {
+                chats_info.push(self.chat_info(room).await?);
             }
         }
+        Ok(chats_info)
     }
 
-    async fn insert_chat(
+    pub async fn search(
         &self,
-        chat_name: String,
-        username: String,
-        tx: mpsc::UnboundedSender<Message>,
-    ) -> Result<Chat, ChatError> {
+        chat_name: &str,
+        username: &str,
+        params: SearchParams,
+    ) -> Result<SearchResults, ChatError> {
+        self.check_readable(chat_name, username).await?;
+        search(&self.store, chat_name, &params, self.room_config.history).await
+    }
+
+    pub async fn thread(
+        &self,
+        chat_name: &str,
+        username: &str,
+        id: i64,
+        params: ThreadParams,
+    ) -> Result<Thread, ChatError> {
+        self.check_readable(chat_name, username).await?;
+        let history = self.room_config.history;
+        load_thread(&self.store, chat_name, id, &params, history).await
+    }
+
+    // Direct chats can only be read by their members, banned users can't read rooms
+    async fn check_readable(&self, chat_name: &str, username: &str) -> Result<(), ChatError> {
+        let not_found = || ChatError::ChatNotFound(chat_name.to_string());
+        let room = self
+            .store
+            .get_room(chat_name)
+            .await?
+            .ok_or_else(not_found)?;
+        if room.direct
+            && !self
+                .store
+                .list_members(chat_name)
+                .await?
+                .iter()
+                .any(|m| m == username)
+        {
+            Err(not_found())?
+        }
+        let ban = self
+            .store
+            .get_sanction(chat_name, username, SanctionKind::Ban)
+            .await?;
+        if ban.is_some_and(|ban| ban.is_active()) {
+            Err(ChatError::Banned(chat_name.to_string()))?
+        }
+        Ok(())
+    }
+
+    // The file is stored right away, but the room only sees it once it is sent with a message
+    pub async fn upload(
+        &self,
+        chat_name: &str,
+        username: &str,
+        mut upload: Upload,
+    ) -> Result<Attachment, ChatError> {
+        match self.store.get_room(chat_name).await? {
+            Some(room) if room.archived => Err(ChatError::ChatArchived(chat_name.to_string()))?,
+            Some(_) => {}
+            None => Err(ChatError::ChatNotFound(chat_name.to_string()))?,
+        }
+        if !self.is_member(chat_name, username).await? {
+            Err(ChatError::ChatForbidden(chat_name.to_string()))?
+        }
+        let thumbnail = self.files.check(&mut upload).await?;
+        let (mut attachment, data) = upload.into_attachment(chat_name, username);
+        attachment.thumbnail = thumbnail.is_some();
+        let attachment = self.store.insert_attachment(&attachment).await?;
+        self.files
+            .save(&attachment, &data, thumbnail.as_deref())
+            .await?;
+        Ok(attachment)
+    }
+
+    // Unsent attachments are only visible to the uploader
+    pub async fn download(
+        &self,
+        id: i64,
+        username: &str,
+        thumbnail: bool,
+    ) -> Result<(Attachment, Vec<u8>), ChatError> {
+        let not_found = || ChatError::AttachmentNotFound(id);
+        let attachment = self.store.get_attachment(id).await?.ok_or_else(not_found)?;
+        if (attachment.message_id.is_none() && attachment.username != username)
+            || (thumbnail && !attachment.thumbnail)
+            || !self.is_member(&attachment.chat, username).await?
+        {
+            Err(not_found())?
+        }
+        let data = self.files.read(&attachment, thumbnail).await?;
+        Ok((attachment, data))
+    }
+
+    // Users who have joined the room and aren't banned from it
+    async fn is_member(&self, chat_name: &str, username: &str) -> Result<bool, ChatError> {
+        if !self.store.is_member(chat_name, username).await? {
+            return Ok(false);
+        }
+        let ban = self
+            .store
+            .get_sanction(chat_name, username, SanctionKind::Ban)
+            .await?;
+        Ok(!ban.is_some_and(|ban| ban.is_active()))
+    }
+
+    pub async fn inbox(&self, username: &str) -> Result<Vec<InboxEntry>, ChatError> {
+        self.store.list_inbox(username).await
+    }
+
+    // The creator owns the new room
+    pub async fn create(
+        &self,
+        NewChat { name, topic }: NewChat,
+        creator: &str,
+    ) -> Result<ChatInfo, ChatError> {
+        if name.trim().is_empty() || name.starts_with(DIRECT_CHAT_PREFIX) {
+            Err(ChatError::InvalidChatName)?
+        }
+        let room = {
+            // Holding the lock keeps creation in line with rooms created on join
+            let _chats = self.chats.write().await;
+            let room = self.store.insert_room(&name, topic.as_deref()).await?;
+            self.store.set_role(&name, creator, Role::Owner).await?;
+            room
+        };
+        self.chat_info(room).await
+    }
+
+    pub async fn archive(&self, chat_name: &str, username: &str) -> Result<ChatInfo, ChatError> {
         let mut chats = self.chats.write().await;
+        let mut room = self.get_room(chat_name).await?;
+        self.check_owner(chat_name, username).await?;
+        Self::unload(&mut chats, chat_name).await?;
+        self.store.archive_room(chat_name).await?;
+        room.archived = true;
+        drop(chats);
+        self.chat_info(room).await
+    }
 
-        match chats.get(&chat_name) {
-            Some(chat) => {
-                let mut users = chat.users.write().await;
-                if users.insert(username.clone(), tx.clone()).is_some() {
-                    Err(ChatError::UsernameTaken(chat_name))?
-                }
-                Ok(chat.clone())
+    pub async fn delete(&self, chat_name: &str, username: &str) -> Result<(), ChatError> {
+        let mut chats = self.chats.write().await;
+        self.get_room(chat_name).await?;
+        self.check_owner(chat_name, username).await?;
+        Self::unload(&mut chats, chat_name).await?;
+        self.store.delete_room(chat_name).await?;
+        self.files.remove_chat(chat_name).await;
+        Ok(())
+    }
+
+    // Empty rooms that have been idle for too long are unloaded from memory,
+    // the ones that have never got a message are removed completely
+    pub async fn cleanup(&self) -> Result<(), ChatError> {
+        let idle_timeout = self.room_config.idle_timeout;
+        let mut chats = self.chats.write().await;
+        let mut idle_chats = vec![];
+        for (chat_name, chat) in chats.iter() {
+            if chat.is_idle(idle_timeout).await {
+                idle_chats.push(chat_name.clone());
+            }
+        }
+        for chat_name in idle_chats {
+            if let Some(chat) = chats.remove(&chat_name) {
+                chat.close();
+            }
+            if self.store.count_messages(&chat_name).await? == 0 {
+                self.store.delete_room(&chat_name).await?;
+                self.files.remove_chat(&chat_name).await;
+            }
+            info!(room = %chat_name, "chat was cleaned up");
+        }
+        Ok(())
+    }
+
+    pub async fn run_cleanup(self) {
+        let mut interval = tokio::time::interval(self.room_config.cleanup_interval);
+        loop {
+            interval.tick().await;
+            if let Err(error) = self.cleanup().await {
+                error!(%error, "chats cleanup failed");
             }
+        }
+    }
+
+    // Direct chats are private, so they are hidden from the public room routes
+    async fn get_room(&self, chat_name: &str) -> Result<Room, ChatError> {
+        self.store
+            .get_room(chat_name)
+            .await?
+            .filter(|room| !room.direct)
+            .ok_or_else(|| ChatError::ChatNotFound(chat_name.to_string()))
+    }
+
+    // Archiving and deleting a room is left to its owner
+    async fn check_owner(&self, chat_name: &str, username: &str) -> Result<(), ChatError> {
+        if self.store.get_role(chat_name, username).await? < Role::Owner {
+            Err(ChatError::ModerationForbidden(chat_name.to_string()))?
+        }
+        Ok(())
+    }
+
+    async fn chat_info(&self, room: Room) -> Result<ChatInfo, ChatError> {
+        let members = match self.chats.read().await.get(&room.name) {
+            Some(chat) => chat.usernames().await.len(),
+            None => 0,
+        };
+        let messages = self.store.count_messages(&room.name).await?;
+        Ok(ChatInfo {
+            room,
+            members,
+            messages,
+        })
+    }
+
+    async fn unload(chats: &mut HashMap<String, Chat>, chat_name: &str) -> Result<(), ChatError> {
+        if let Some(chat) = chats.get(chat_name) {
+            if !chat.usernames().await.is_empty() {
+                Err(ChatError::ChatNotEmpty(chat_name.to_string()))?
+            }
+        }
+        if let Some(chat) = chats.remove(chat_name) {
+            chat.close();
+        }
+        Ok(())
+    }
+
+    async fn insert_chat(
+        &self,
+        target: ChatTarget,
+        session: &Session,
+        version: u32,
+        tx: Outbox,
+    ) -> Result<(Chat, bool, ServerEvent), ChatError> {
+        let chat_name = target.chat_name(&session.username)?;
+        let mut chats = self.chats.write().await;
+
+        let chat = match chats.get(&chat_name) {
+            Some(chat) => chat.clone(),
             None => {
-                let chat = Chat::new(chat_name.clone());
-                chat.users
-                    .write()
-                    .await
-                    .insert(username.clone(), tx.clone());
-                chats.insert(chat_name, chat.clone());
-                Ok(chat)
+                let room = match self.store.get_room(&chat_name).await? {
+                    Some(room) if room.archived => Err(ChatError::ChatArchived(chat_name.clone()))?,
+                    Some(room) => room,
+                    None => match &target {
+                        // Whoever opens a new room owns it
+                        ChatTarget::Room(_) => {
+                            let room = self.store.insert_room(&chat_name, None).await?;
+                            let owner = Role::Owner;
+                            self.store
+                                .set_role(&chat_name, &session.username, owner)
+                                .await?;
+                            room
+                        }
+                        ChatTarget::Direct(with) => {
+                            let members = [session.username.as_str(), with.as_str()];
+                            self.store.insert_direct_room(&chat_name, members).await?
+                        }
+                    },
+                };
+                let members = match room.direct {
+                    true => self.store.list_members(&chat_name).await?,
+                    false => vec![],
+                };
+                let chat = Chat::open(
+                    chat_name.clone(),
+                    self.store.clone(),
+                    members,
+                    self.room_config,
+                    self.bus.clone(),
+                    self.instance,
+                    self.files.clone(),
+                )
+                .await?;
+                chats.insert(chat_name.clone(), chat.clone());
+                chat
+            }
+        };
+        if chat.is_direct() && !chat.members.contains(&session.username) {
+            Err(ChatError::ChatForbidden(chat_name))?
+        }
+        chat.check_not_banned(&session.username).await?;
+        self.store.add_member(&chat.name, &session.username).await?;
+        let welcome = ServerEvent::Welcome {
+            version,
+            session: session.id,
+            chat: chat.name.to_string(),
+            username: session.username.clone(),
+            role: chat.role(&session.username).await?,
+            topic: self
+                .store
+                .get_room(&chat.name)
+                .await?
+                .and_then(|room| room.topic),
+            nicknames: self.store.list_nicknames(&chat.name).await?,
+        };
+        let is_first_session = chat.connect(session, tx).await;
+        Ok((chat, is_first_session, welcome))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broker::tests::FakeRedis;
+    use crate::broker::{MemoryBroker, RedisBroker};
+    use crate::storage::MemoryStore;
+    use tokio::time::timeout;
+
+    async fn open(name: &str, store: &Store, bus: &Bus, instance: u64) -> Chat {
+        open_with(name, store, bus, instance, RoomConfig::default()).await
+    }
+
+    async fn open_with(
+        name: &str,
+        store: &Store,
+        bus: &Bus,
+        instance: u64,
+        config: RoomConfig,
+    ) -> Chat {
+        Chat::open(
+            name.to_string(),
+            store.clone(),
+            vec![],
+            config,
+            bus.clone(),
+            instance,
+            AttachmentFiles::new(Default::default()),
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn connect(chat: &Chat, username: &str) -> (Session, Outbox) {
+        let session = Session::new(username.to_string());
+        let outbox = Outbox::new(OutboxConfig::default(), Default::default());
+        chat.connect(&session, outbox.clone()).await;
+        (session, outbox)
+    }
+
+    async fn next_event(outbox: &Outbox) -> serde_json::Value {
+        let message = timeout(Duration::from_secs(5), outbox.recv())
+            .await
+            .expect("no event in time")
+            .unwrap();
+        serde_json::from_str(message.to_str().unwrap()).unwrap()
+    }
+
+    // Skips whatever the sessions got while the instances were syncing
+    async fn drain(outbox: &Outbox) {
+        while let Ok(Some(_)) = timeout(Duration::from_millis(50), outbox.recv()).await {}
+    }
+
+    async fn wait_for_roster(chat: &Chat, users: &[&str]) {
+        timeout(Duration::from_secs(5), async {
+            while chat.usernames().await != users {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("roster didn't converge");
+    }
+
+    #[tokio::test]
+    async fn instances_sharing_a_bus_see_each_other() {
+        let store: Store = Arc::new(MemoryStore::default());
+        let bus: Bus = Arc::new(MemoryBroker::default());
+        let first = open("room", &store, &bus, 1).await;
+        let (alice, alice_rx) = connect(&first, "alice").await;
+        let second = open("room", &store, &bus, 2).await;
+        let (_, bob_rx) = connect(&second, "bob").await;
+        wait_for_roster(&first, &["alice", "bob"]).await;
+        wait_for_roster(&second, &["alice", "bob"]).await;
+        drain(&alice_rx).await;
+        drain(&bob_rx).await;
+
+        for i in 0..20 {
+            let message = ServerEvent::Typing {
+                username: format!("user{i}"),
+            };
+            first
+                .broadcast(&message, Recipients::ExceptSession(alice.id))
+                .await;
+        }
+        for i in 0..20 {
+            assert_eq!(next_event(&bob_rx).await["username"], format!("user{i}"));
+        }
+        assert!(timeout(Duration::from_millis(50), alice_rx.recv())
+            .await
+            .is_err());
+
+        first
+            .kick("bob", ChatError::Kicked("alice".to_string()))
+            .await;
+        assert_eq!(next_event(&bob_rx).await["code"], "kicked");
+        let close = bob_rx.recv().await.unwrap();
+        assert!(close.is_close());
+        assert_eq!(bob_rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn users_of_a_stopped_instance_expire() {
+        let redis = FakeRedis::start().await;
+        let store: Store = Arc::new(MemoryStore::default());
+        let config = RoomConfig {
+            presence_interval: Duration::from_millis(50),
+            presence_ttl: Duration::from_millis(200),
+            ..RoomConfig::default()
+        };
+        let first_bus: Bus = Arc::new(RedisBroker::connect(&redis.url).await);
+        let second_bus: Bus = Arc::new(RedisBroker::connect(&redis.url).await);
+        let first = open_with("room", &store, &first_bus, 1, config).await;
+        let (_, alice_rx) = connect(&first, "alice").await;
+        let second = open_with("room", &store, &second_bus, 2, config).await;
+        connect(&second, "bob").await;
+        wait_for_roster(&first, &["alice", "bob"]).await;
+
+        // Announcements keep bob there for longer than the ttl
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(first.usernames().await, ["alice", "bob"]);
+        drain(&alice_rx).await;
+
+        // Stops without telling anyone, as if the instance crashed
+        second.close();
+        wait_for_roster(&first, &["alice"]).await;
+        let roster = next_event(&alice_rx).await;
+        assert_eq!(roster["type"], "roster");
+        assert_eq!(roster["users"], serde_json::json!(["alice"]));
+    }
+
+    #[tokio::test]
+    async fn commands_are_run_instead_of_being_sent() {
+        let store: Store = Arc::new(MemoryStore::default());
+        let bus: Bus = Arc::new(MemoryBroker::default());
+        store.insert_room("room", None).await.unwrap();
+        store.add_member("room", "alice").await.unwrap();
+        let chat = open("room", &store, &bus, 1).await;
+        let (alice, alice_rx) = connect(&chat, "alice").await;
+        let (_, bob_rx) = connect(&chat, "bob").await;
+        wait_for_roster(&chat, &["alice", "bob"]).await;
+        drain(&alice_rx).await;
+        drain(&bob_rx).await;
+
+        let commands = Commands::default();
+        let send = |text: &str| ClientEvent::Message {
+            text: text.to_string(),
+            client_id: None,
+            attachments: vec![],
+            parent_id: None,
+            thread_only: false,
+        };
+        let unknown = chat
+            .handle_event(&alice, send("/dance"), &alice_rx, &commands)
+            .await;
+        assert!(matches!(unknown, Err(ChatError::UnknownCommand(name)) if name == "dance"));
+
+        chat.handle_event(&alice, send("/me waves"), &alice_rx, &commands)
+            .await
+            .unwrap();
+        let message = next_event(&bob_rx).await;
+        assert_eq!(message["message"]["text"], "waves");
+        assert_eq!(message["message"]["kind"], "action");
+
+        chat.handle_event(&alice, send("/nick Ally"), &alice_rx, &commands)
+            .await
+            .unwrap();
+        assert_eq!(next_event(&bob_rx).await["nickname"], "Ally");
+        drain(&alice_rx).await;
+        chat.handle_event(&alice, send("/who"), &alice_rx, &commands)
+            .await
+            .unwrap();
+        let notice = next_event(&alice_rx).await;
+        assert_eq!(notice["text"], "Online (2): Ally (alice), bob");
+        assert!(timeout(Duration::from_millis(50), bob_rx.recv())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn every_broadcast_spends_a_token() {
+        let store: Store = Arc::new(MemoryStore::default());
+        let bus: Bus = Arc::new(MemoryBroker::default());
+        store.insert_room("room", None).await.unwrap();
+        let chat = open("room", &store, &bus, 1).await;
+        let (alice, alice_rx) = connect(&chat, "alice").await;
+        let commands = Commands::default();
+        let message = store
+            .insert_message("room", "alice", "hi", MessageKind::Text, &[], None)
+            .await
+            .unwrap();
+
+        let id = message.id;
+        let events = [
+            ClientEvent::React {
+                id,
+                emoji: "👍".to_string(),
+            },
+            ClientEvent::Unreact {
+                id,
+                emoji: "👍".to_string(),
+            },
+            ClientEvent::Edit {
+                id,
+                text: "hello".to_string(),
+            },
+            ClientEvent::Message {
+                text: "/nick Ally".to_string(),
+                client_id: None,
+                attachments: vec![],
+                parent_id: None,
+                thread_only: false,
+            },
+        ];
+        let mut limited = 0;
+        for event in events.into_iter().cycle().take(8) {
+            let result = chat.handle_event(&alice, event, &alice_rx, &commands).await;
+            if let Err(error) = result {
+                assert!(matches!(error, ChatError::RateLimited(_)));
+                limited += 1;
+            }
+        }
+        assert_eq!(limited, 3);
+        let delete = ClientEvent::Delete { id };
+        let result = chat
+            .handle_event(&alice, delete, &alice_rx, &commands)
+            .await;
+        assert!(matches!(result, Err(ChatError::RateLimited(_))));
+
+        // Receipts over the limit are still stored
+        for id in 1..=20 {
+            let read = ClientEvent::Read { id };
+            chat.handle_event(&alice, read, &alice_rx, &commands)
+                .await
+                .unwrap();
+        }
+        let receipts = store.list_read_receipts("room").await.unwrap();
+        assert_eq!(receipts[0].last_read_id, 20);
+        drain(&alice_rx).await;
+        let read = ClientEvent::Read { id: 21 };
+        chat.handle_event(&alice, read, &alice_rx, &commands)
+            .await
+            .unwrap();
+        assert!(timeout(Duration::from_millis(50), alice_rx.recv())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn nicknames_are_unique_and_rate_limited() {
+        let store: Store = Arc::new(MemoryStore::default());
+        let bus: Bus = Arc::new(MemoryBroker::default());
+        store.insert_room("room", None).await.unwrap();
+        for username in ["alice", "bob", "carol"] {
+            store.add_member("room", username).await.unwrap();
+        }
+        let chat = open("room", &store, &bus, 1).await;
+        let (alice, alice_rx) = connect(&chat, "alice").await;
+        let (bob, bob_rx) = connect(&chat, "bob").await;
+        let commands = Commands::default();
+        let nick = |nickname: &str| ClientEvent::Message {
+            text: format!("/nick {nickname}"),
+            client_id: None,
+            attachments: vec![],
+            parent_id: None,
+            thread_only: false,
+        };
+
+        chat.handle_event(&alice, nick("Ally"), &alice_rx, &commands)
+            .await
+            .unwrap();
+        // Other members' nicknames and usernames, online or not
+        for taken in ["ally", "Alice", "CAROL"] {
+            let result = chat
+                .handle_event(&bob, nick(taken), &bob_rx, &commands)
+                .await;
+            assert!(matches!(result, Err(ChatError::NicknameTaken(name)) if name == taken));
+        }
+        // Users can go by their own name in another case
+        chat.handle_event(&alice, nick("ALICE"), &alice_rx, &commands)
+            .await
+            .unwrap();
+        chat.handle_event(&bob, nick("Ally"), &bob_rx, &commands)
+            .await
+            .unwrap();
+
+        let mut limited = false;
+        for i in 0..10 {
+            let result = chat
+                .handle_event(&alice, nick(&format!("A{i}")), &alice_rx, &commands)
+                .await;
+            if matches!(result, Err(ChatError::RateLimited(_))) {
+                limited = true;
+                break;
             }
+            result.unwrap();
         }
+        assert!(limited);
     }
 }
@@ -0,0 +1,126 @@
+use crate::auth::Identity;
+use crate::chat::{Chats, NewChat, ThreadParams};
+use crate::metrics;
+use crate::search::SearchParams;
+use warp::http::header::CONTENT_TYPE;
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::{Rejection, Reply};
+
+pub async fn get_users(
+    chat_name: String,
+    identity: Identity,
+    chats: Chats,
+) -> Result<impl Reply, Rejection> {
+    let users = chats
+        .users(&chat_name, &identity.username)
+        .await
+        .map_err(warp::reject::custom)?;
+    Ok(warp::reply::json(&users))
+}
+
+pub async fn list_chats(chats: Chats) -> Result<impl Reply, Rejection> {
+    let chats_info = chats.list().await.map_err(warp::reject::custom)?;
+    Ok(warp::reply::json(&chats_info))
+}
+
+pub async fn create_chat(
+    new_chat: NewChat,
+    identity: Identity,
+    chats: Chats,
+) -> Result<impl Reply, Rejection> {
+    let chat_info = chats
+        .create(new_chat, &identity.username)
+        .await
+        .map_err(warp::reject::custom)?;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&chat_info),
+        StatusCode::CREATED,
+    ))
+}
+
+pub async fn archive_chat(
+    chat_name: String,
+    identity: Identity,
+    chats: Chats,
+) -> Result<impl Reply, Rejection> {
+    let chat_info = chats
+        .archive(&chat_name, &identity.username)
+        .await
+        .map_err(warp::reject::custom)?;
+    Ok(warp::reply::json(&chat_info))
+}
+
+pub async fn delete_chat(
+    chat_name: String,
+    identity: Identity,
+    chats: Chats,
+) -> Result<impl Reply, Rejection> {
+    chats
+        .delete(&chat_name, &identity.username)
+        .await
+        .map_err(warp::reject::custom)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn search_chat(
+    chat_name: String,
+    params: SearchParams,
+    identity: Identity,
+    chats: Chats,
+) -> Result<impl Reply, Rejection> {
+    let results = chats
+        .search(&chat_name, &identity.username, params)
+        .await
+        .map_err(warp::reject::custom)?;
+    Ok(warp::reply::json(&results))
+}
+
+pub async fn get_thread(
+    chat_name: String,
+    id: i64,
+    params: ThreadParams,
+    identity: Identity,
+    chats: Chats,
+) -> Result<impl Reply, Rejection> {
+    let thread = chats
+        .thread(&chat_name, &identity.username, id, params)
+        .await
+        .map_err(warp::reject::custom)?;
+    Ok(warp::reply::json(&thread))
+}
+
+pub async fn get_queue_stats(chats: Chats) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&chats.queue_stats()))
+}
+
+pub async fn get_metrics(chats: Chats) -> Result<impl Reply, Rejection> {
+    let users = chats.user_counts().await;
+    Ok(warp::reply::with_header(
+        metrics::render(&users),
+        CONTENT_TYPE,
+        metrics::CONTENT_TYPE,
+    ))
+}
+
+pub async fn get_inbox(identity: Identity, chats: Chats) -> Result<impl Reply, Rejection> {
+    let inbox = chats
+        .inbox(&identity.username)
+        .await
+        .map_err(warp::reject::custom)?;
+    Ok(warp::reply::json(&inbox))
+}
+
+// Client events of the server-sent events transport
+pub async fn post_event(
+    session_id: u64,
+    identity: Identity,
+    body: Bytes,
+    chats: Chats,
+) -> Result<impl Reply, Rejection> {
+    chats
+        .post_event(session_id, &identity.username, &body)
+        .await
+        .map_err(warp::reject::custom)?;
+    Ok(StatusCode::ACCEPTED)
+}
@@ -1,32 +1,85 @@
-use crate::chat::Chats;
+use crate::auth::{self, Identity};
+use crate::chat::{ChatTarget, Chats, Session};
+use crate::error::ChatError;
+use crate::protocol::ProtocolQuery;
 use crate::utils::Pagination;
-use futures_util::{SinkExt, StreamExt, TryFutureExt};
-use tokio::sync::mpsc;
-use tokio_stream::wrappers::UnboundedReceiverStream;
-use warp::ws::WebSocket;
+use futures_util::{SinkExt, StreamExt};
+use tracing::{info_span, warn, Instrument};
+use warp::http::header::SEC_WEBSOCKET_PROTOCOL;
+use warp::http::HeaderValue;
+use warp::reply::Response;
+use warp::ws::{WebSocket, Ws};
+use warp::Reply;
+
+pub fn upgrade(
+    ws: Ws,
+    chats: Chats,
+    target: ChatTarget,
+    identity: Identity,
+    page: Pagination,
+    protocol: ProtocolQuery,
+) -> Response {
+    if chats.is_shutting_down() {
+        return ChatError::ServerShutdown.to_response();
+    }
+    let mut response = ws
+        .on_upgrade(move |socket| {
+            on_user_connection(socket, chats, target, identity.username, page, protocol)
+        })
+        .into_response();
+    if identity.bearer_protocol {
+        response.headers_mut().insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(auth::BEARER_PROTOCOL),
+        );
+    }
+    response
+}
 
 pub async fn on_user_connection(
-    ws: WebSocket,
+    mut ws: WebSocket,
     chats: Chats,
-    chat_name: String,
+    target: ChatTarget,
     username: String,
     page: Pagination,
+    protocol: ProtocolQuery,
 ) {
+    // Counted until the last frame is written, the shutdown waits for it
+    let Some(_connection) = chats.track_connection() else {
+        let _ = ws.send(ChatError::ServerShutdown.to_close_frame()).await;
+        return;
+    };
+    let session = Session::new(username);
+    let span = info_span!(
+        "connection",
+        session = session.id,
+        user = %session.username,
+        transport = "websocket"
+    );
     let (mut user_ws_tx, user_ws_rx) = ws.split();
-    let (tx, rx) = mpsc::unbounded_channel();
-    let mut rx = UnboundedReceiverStream::new(rx);
+    let outbox = chats.outbox();
+    let rx = outbox.clone();
 
-    tokio::task::spawn(async move {
-        while let Some(message) = rx.next().await {
-            user_ws_tx
-                .send(message)
-                .unwrap_or_else(|e| {
-                    eprintln!("websocket send error: {}", e);
-                })
-                .await;
+    let writer = tokio::task::spawn(
+        async move {
+            while let Some(message) = rx.recv().await {
+                // A stalled socket must not hold the writer once the outbox is aborted
+                let result = tokio::select! {
+                    result = user_ws_tx.send(message) => result,
+                    _ = rx.aborted() => break,
+                };
+                if let Err(e) = result {
+                    warn!(error = %e, "websocket send failed");
+                    break;
+                }
+            }
         }
-    });
+        .instrument(span.clone()),
+    );
     chats
-        .join(chat_name.clone(), username, page, user_ws_rx, tx)
+        .join(target, session, page, protocol, user_ws_rx, outbox.clone())
+        .instrument(span)
         .await;
+    outbox.close();
+    let _ = writer.await;
 }
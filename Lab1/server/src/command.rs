@@ -0,0 +1,306 @@
+use crate::chat::{CommandContext, MessageKind};
+use crate::error::ChatError;
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+const COMMAND_PREFIX: char = '/';
+const MAX_NICKNAME_LENGTH: usize = 32;
+const MAX_TOPIC_LENGTH: usize = 256;
+
+// Server side handler of `/name args` typed into the chat box
+#[async_trait]
+pub trait Command: Send + Sync {
+    // Lowercase, without the slash
+    fn name(&self) -> &'static str;
+
+    // Shown by /help and when the arguments are wrong, e.g. "/nick [name]"
+    fn usage(&self) -> &'static str;
+
+    fn description(&self) -> &'static str;
+
+    async fn run(&self, context: &CommandContext<'_>, args: &str) -> Result<(), ChatError>;
+}
+
+#[derive(Clone)]
+pub struct Commands {
+    commands: BTreeMap<&'static str, Arc<dyn Command>>,
+}
+
+impl Commands {
+    pub fn empty() -> Self {
+        Commands {
+            commands: BTreeMap::new(),
+        }
+    }
+
+    // Replaces the command with the same name, built-in ones included
+    pub fn register(mut self, command: impl Command + 'static) -> Self {
+        self.commands.insert(command.name(), Arc::new(command));
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Command> {
+        self.commands
+            .get(name.to_lowercase().as_str())
+            .map(|command| command.as_ref())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &dyn Command> {
+        self.commands.values().map(|command| command.as_ref())
+    }
+}
+
+impl Default for Commands {
+    fn default() -> Self {
+        Commands::empty()
+            .register(Me)
+            .register(Nick)
+            .register(Topic)
+            .register(Who)
+            .register(Help)
+            .register(Leave)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Input<'a> {
+    Text(&'a str),
+    Command { name: &'a str, args: &'a str },
+}
+
+// `/name args` runs a command, `//text` is sent as `/text`
+pub fn parse(text: &str) -> Input<'_> {
+    let Some(rest) = text.strip_prefix(COMMAND_PREFIX) else {
+        return Input::Text(text);
+    };
+    if rest.starts_with(COMMAND_PREFIX) {
+        return Input::Text(rest);
+    }
+    let (name, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    match name.is_empty() {
+        true => Input::Text(text),
+        false => Input::Command {
+            name,
+            args: args.trim(),
+        },
+    }
+}
+
+struct Me;
+
+#[async_trait]
+impl Command for Me {
+    fn name(&self) -> &'static str {
+        "me"
+    }
+
+    fn usage(&self) -> &'static str {
+        "/me <action>"
+    }
+
+    fn description(&self) -> &'static str {
+        "Describe what you are doing"
+    }
+
+    async fn run(&self, context: &CommandContext<'_>, args: &str) -> Result<(), ChatError> {
+        if args.is_empty() {
+            Err(ChatError::InvalidCommand(self.usage()))?
+        }
+        context.send_message(args, MessageKind::Action).await
+    }
+}
+
+struct Nick;
+
+#[async_trait]
+impl Command for Nick {
+    fn name(&self) -> &'static str {
+        "nick"
+    }
+
+    fn usage(&self) -> &'static str {
+        "/nick [name]"
+    }
+
+    fn description(&self) -> &'static str {
+        "Change how you are shown in this chat, without a name the nickname is reset"
+    }
+
+    async fn run(&self, context: &CommandContext<'_>, args: &str) -> Result<(), ChatError> {
+        if args.chars().count() > MAX_NICKNAME_LENGTH || args.chars().any(char::is_control) {
+            Err(ChatError::InvalidCommand(self.usage()))?
+        }
+        let nickname = Some(args).filter(|nickname| !nickname.is_empty());
+        context.set_nickname(nickname).await
+    }
+}
+
+struct Topic;
+
+#[async_trait]
+impl Command for Topic {
+    fn name(&self) -> &'static str {
+        "topic"
+    }
+
+    fn usage(&self) -> &'static str {
+        "/topic [text | -]"
+    }
+
+    fn description(&self) -> &'static str {
+        "Show the topic, moderators can change it or clear it with -"
+    }
+
+    async fn run(&self, context: &CommandContext<'_>, args: &str) -> Result<(), ChatError> {
+        if args.is_empty() {
+            let text = match context.room().await?.topic {
+                Some(topic) => format!("Topic: {topic}"),
+                None => "There is no topic".to_string(),
+            };
+            return context.reply(text);
+        }
+        if args.chars().count() > MAX_TOPIC_LENGTH {
+            Err(ChatError::InvalidCommand(self.usage()))?
+        }
+        if !context.role().await?.can_moderate() {
+            Err(ChatError::CommandForbidden(self.name()))?
+        }
+        let topic = Some(args).filter(|topic| *topic != "-");
+        context.set_topic(topic).await
+    }
+}
+
+struct Who;
+
+#[async_trait]
+impl Command for Who {
+    fn name(&self) -> &'static str {
+        "who"
+    }
+
+    fn usage(&self) -> &'static str {
+        "/who"
+    }
+
+    fn description(&self) -> &'static str {
+        "List the users in the chat"
+    }
+
+    async fn run(&self, context: &CommandContext<'_>, _: &str) -> Result<(), ChatError> {
+        let nicknames = context.nicknames().await?;
+        let users: Vec<String> = context
+            .usernames()
+            .await
+            .into_iter()
+            .map(|username| match nicknames.get(&username) {
+                Some(nickname) => format!("{nickname} ({username})"),
+                None => username,
+            })
+            .collect();
+        context.reply(format!("Online ({}): {}", users.len(), users.join(", ")))
+    }
+}
+
+struct Help;
+
+#[async_trait]
+impl Command for Help {
+    fn name(&self) -> &'static str {
+        "help"
+    }
+
+    fn usage(&self) -> &'static str {
+        "/help"
+    }
+
+    fn description(&self) -> &'static str {
+        "List the commands"
+    }
+
+    async fn run(&self, context: &CommandContext<'_>, _: &str) -> Result<(), ChatError> {
+        let lines: Vec<String> = context
+            .commands()
+            .iter()
+            .map(|command| format!("{} - {}", command.usage(), command.description()))
+            .collect();
+        context.reply(lines.join("\n"))
+    }
+}
+
+struct Leave;
+
+#[async_trait]
+impl Command for Leave {
+    fn name(&self) -> &'static str {
+        "leave"
+    }
+
+    fn usage(&self) -> &'static str {
+        "/leave"
+    }
+
+    fn description(&self) -> &'static str {
+        "Leave the chat"
+    }
+
+    async fn run(&self, context: &CommandContext<'_>, _: &str) -> Result<(), ChatError> {
+        context.leave();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_parsed_from_the_text() {
+        assert_eq!(
+            parse("/me  waves at you "),
+            Input::Command {
+                name: "me",
+                args: "waves at you"
+            }
+        );
+        assert_eq!(
+            parse("/who"),
+            Input::Command {
+                name: "who",
+                args: ""
+            }
+        );
+        assert_eq!(parse("//me"), Input::Text("/me"));
+        assert_eq!(parse("/ hello"), Input::Text("/ hello"));
+        assert_eq!(parse("a /me"), Input::Text("a /me"));
+    }
+
+    #[test]
+    fn registered_commands_replace_the_built_in_ones() {
+        struct Shrug;
+
+        #[async_trait]
+        impl Command for Shrug {
+            fn name(&self) -> &'static str {
+                "help"
+            }
+
+            fn usage(&self) -> &'static str {
+                "/help"
+            }
+
+            fn description(&self) -> &'static str {
+                "¯\\_(ツ)_/¯"
+            }
+
+            async fn run(&self, _: &CommandContext<'_>, _: &str) -> Result<(), ChatError> {
+                Ok(())
+            }
+        }
+
+        let commands = Commands::default().register(Shrug);
+        assert_eq!(commands.get("HELP").unwrap().description(), "¯\\_(ツ)_/¯");
+        assert_eq!(commands.iter().count(), 6);
+        assert!(commands.get("nope").is_none());
+    }
+}
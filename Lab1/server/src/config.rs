@@ -0,0 +1,453 @@
+use crate::attachment::AttachmentConfig;
+use crate::chat::RoomConfig;
+use crate::error::ConfigError;
+use crate::heartbeat::HeartbeatConfig;
+use crate::logging::LogConfig;
+use crate::outbox::OutboxConfig;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+pub type ConfigResult<T> = Result<T, ConfigError>;
+
+// Path of the optional TOML file, it takes the same names as the env variables
+const CONFIG_FILE: &str = "CONFIG_FILE";
+const DEFAULT_PORT: u16 = 3030;
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn load_config() -> ConfigResult<Config> {
+    let source = Source::load()?;
+    Config::load(&source)
+}
+
+trait ConfigLoader {
+    fn load(source: &Source) -> ConfigResult<Self>
+    where
+        Self: Sized;
+}
+
+// Env variables take precedence over the file
+struct Source {
+    vars: HashMap<String, String>,
+    file: toml::Table,
+}
+
+impl Source {
+    fn load() -> ConfigResult<Self> {
+        let vars: HashMap<String, String> = env::vars().collect();
+        let file = match vars.get(CONFIG_FILE).filter(|path| !path.trim().is_empty()) {
+            Some(path) => {
+                let file_error = |message: String| ConfigError::File {
+                    path: path.clone(),
+                    message,
+                };
+                fs::read_to_string(path)
+                    .map_err(|error| file_error(error.to_string()))?
+                    .parse()
+                    .map_err(|error: toml::de::Error| file_error(error.message().to_string()))?
+            }
+            None => toml::Table::new(),
+        };
+        Ok(Source { vars, file })
+    }
+
+    // Empty values count as unset, so the example config can be copied as is
+    fn get(&self, name: &'static str) -> Option<String> {
+        let value = match (self.vars.get(name), self.file.get(name)) {
+            (Some(value), _) if !value.trim().is_empty() => value.clone(),
+            (_, Some(toml::Value::String(value))) => value.clone(),
+            (_, Some(toml::Value::Array(values))) => values
+                .iter()
+                .map(|value| match value {
+                    toml::Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(","),
+            (_, Some(value)) => value.to_string(),
+            (_, None) => return None,
+        };
+        Some(value.trim().to_string()).filter(|value| !value.is_empty())
+    }
+
+    fn require(&self, name: &'static str) -> ConfigResult<String> {
+        self.get(name)
+            .ok_or_else(|| invalid(name, "is required".to_string()))
+    }
+
+    fn parse<T>(&self, name: &'static str, default: T) -> ConfigResult<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        match self.get(name) {
+            Some(value) => value
+                .parse()
+                .map_err(|error| invalid(name, format!("{value:?} is invalid: {error}"))),
+            None => Ok(default),
+        }
+    }
+
+    fn positive<T>(&self, name: &'static str, default: T) -> ConfigResult<T>
+    where
+        T: FromStr + PartialOrd + Default,
+        T::Err: Display,
+    {
+        let value = self.parse(name, default)?;
+        // NaN isn't greater either
+        if value.partial_cmp(&T::default()) != Some(Ordering::Greater) {
+            Err(invalid(name, "must be greater than zero".to_string()))?
+        }
+        Ok(value)
+    }
+
+    fn secs(&self, name: &'static str, default: Duration) -> ConfigResult<Duration> {
+        Ok(Duration::from_secs(self.positive(name, default.as_secs())?))
+    }
+
+    // Comma separated values
+    fn list(&self, name: &'static str) -> Vec<String> {
+        self.get(name)
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+fn invalid(name: &'static str, message: String) -> ConfigError {
+    ConfigError::Invalid { name, message }
+}
+
+#[allow(non_snake_case)]
+pub struct Config {
+    pub SERVER: ServerConfig,
+    pub JWT: JWTConfig,
+    pub STORAGE: StorageConfig,
+    pub OUTBOX: OutboxConfig,
+    pub HEARTBEAT: HeartbeatConfig,
+    pub ROOMS: RoomConfig,
+    pub ATTACHMENTS: AttachmentConfig,
+    pub LOG: LogConfig,
+}
+
+impl ConfigLoader for Config {
+    fn load(source: &Source) -> ConfigResult<Self>
+    where
+        Self: Sized,
+    {
+        Ok(Config {
+            SERVER: ServerConfig::load(source)?,
+            JWT: JWTConfig::load(source)?,
+            STORAGE: StorageConfig::load(source)?,
+            OUTBOX: OutboxConfig::load(source)?,
+            HEARTBEAT: HeartbeatConfig::load(source)?,
+            ROOMS: RoomConfig::load(source)?,
+            ATTACHMENTS: AttachmentConfig::load(source)?,
+            LOG: LogConfig::load(source)?,
+        })
+    }
+}
+
+#[allow(non_snake_case)]
+pub struct ServerConfig {
+    pub HOST: IpAddr,
+    pub PORT: u16,
+    // The server speaks https and wss when it is set
+    pub TLS: Option<TlsConfig>,
+    // Browser origins allowed to call the API and open sockets, any when empty
+    pub ALLOWED_ORIGINS: Vec<String>,
+    // Connections still open after it are dropped on shutdown
+    pub SHUTDOWN_TIMEOUT: Duration,
+}
+
+impl ServerConfig {
+    pub fn address(&self) -> SocketAddr {
+        SocketAddr::new(self.HOST, self.PORT)
+    }
+}
+
+impl ConfigLoader for ServerConfig {
+    fn load(source: &Source) -> ConfigResult<Self>
+    where
+        Self: Sized,
+    {
+        let host = source.parse("SERVER_HOST", IpAddr::V4(Ipv4Addr::LOCALHOST))?;
+        let port = source.parse("SERVER_PORT", DEFAULT_PORT)?;
+        let origins = source.list("ALLOWED_ORIGINS");
+        for origin in &origins {
+            let is_origin = (origin.starts_with("http://") || origin.starts_with("https://"))
+                && !origin.ends_with('/');
+            if !is_origin {
+                Err(invalid(
+                    "ALLOWED_ORIGINS",
+                    format!("{origin:?} isn't an origin like https://example.com"),
+                ))?
+            }
+        }
+        Ok(ServerConfig {
+            HOST: host,
+            PORT: port,
+            TLS: Option::<TlsConfig>::load(source)?,
+            ALLOWED_ORIGINS: origins,
+            SHUTDOWN_TIMEOUT: source.secs("SHUTDOWN_TIMEOUT_SECS", SHUTDOWN_TIMEOUT)?,
+        })
+    }
+}
+
+#[allow(non_snake_case)]
+pub struct TlsConfig {
+    pub CERT_PATH: PathBuf,
+    pub KEY_PATH: PathBuf,
+}
+
+impl ConfigLoader for Option<TlsConfig> {
+    fn load(source: &Source) -> ConfigResult<Self>
+    where
+        Self: Sized,
+    {
+        let (cert_path, key_path) = match (source.get("TLS_CERT_PATH"), source.get("TLS_KEY_PATH"))
+        {
+            (Some(cert_path), Some(key_path)) => {
+                (PathBuf::from(cert_path), PathBuf::from(key_path))
+            }
+            (None, None) => return Ok(None),
+            (Some(_), None) => Err(invalid(
+                "TLS_KEY_PATH",
+                "is required with TLS_CERT_PATH".to_string(),
+            ))?,
+            (None, Some(_)) => Err(invalid(
+                "TLS_CERT_PATH",
+                "is required with TLS_KEY_PATH".to_string(),
+            ))?,
+        };
+        for (name, path) in [("TLS_CERT_PATH", &cert_path), ("TLS_KEY_PATH", &key_path)] {
+            if !path.is_file() {
+                Err(invalid(name, format!("{} isn't a file", path.display())))?
+            }
+        }
+        Ok(Some(TlsConfig {
+            CERT_PATH: cert_path,
+            KEY_PATH: key_path,
+        }))
+    }
+}
+
+#[allow(non_snake_case)]
+pub struct JWTConfig {
+    pub SECRET: String,
+}
+
+impl ConfigLoader for JWTConfig {
+    fn load(source: &Source) -> ConfigResult<Self>
+    where
+        Self: Sized,
+    {
+        Ok(JWTConfig {
+            SECRET: source.require("JWT_SECRET")?,
+        })
+    }
+}
+
+// Data stays in memory of a single instance without them
+#[allow(non_snake_case)]
+pub struct StorageConfig {
+    pub DATABASE_URL: Option<String>,
+    pub REDIS_URL: Option<String>,
+}
+
+impl ConfigLoader for StorageConfig {
+    fn load(source: &Source) -> ConfigResult<Self>
+    where
+        Self: Sized,
+    {
+        Ok(StorageConfig {
+            DATABASE_URL: source.get("DATABASE_URL"),
+            REDIS_URL: source.get("REDIS_URL"),
+        })
+    }
+}
+
+impl ConfigLoader for OutboxConfig {
+    fn load(source: &Source) -> ConfigResult<Self>
+    where
+        Self: Sized,
+    {
+        let default = OutboxConfig::default();
+        Ok(OutboxConfig {
+            capacity: source.positive("OUTBOX_CAPACITY", default.capacity)?,
+            policy: source.parse("OUTBOX_POLICY", default.policy)?,
+        })
+    }
+}
+
+impl ConfigLoader for HeartbeatConfig {
+    fn load(source: &Source) -> ConfigResult<Self>
+    where
+        Self: Sized,
+    {
+        let default = HeartbeatConfig::default();
+        Ok(HeartbeatConfig {
+            ping_interval: source.secs("PING_INTERVAL_SECS", default.ping_interval)?,
+            pong_timeout: source.secs("PONG_TIMEOUT_SECS", default.pong_timeout)?,
+            idle_timeout: source.secs("CLIENT_IDLE_TIMEOUT_SECS", default.idle_timeout)?,
+        })
+    }
+}
+
+impl ConfigLoader for RoomConfig {
+    fn load(source: &Source) -> ConfigResult<Self>
+    where
+        Self: Sized,
+    {
+        let mut config = RoomConfig::default();
+        let history = &mut config.history;
+        history.max_take = source.positive("HISTORY_MAX_TAKE", history.max_take)?;
+        history.default_take = source.positive("HISTORY_DEFAULT_TAKE", history.default_take)?;
+        if history.default_take > history.max_take {
+            Err(invalid(
+                "HISTORY_DEFAULT_TAKE",
+                format!(
+                    "can't be greater than HISTORY_MAX_TAKE ({})",
+                    history.max_take
+                ),
+            ))?
+        }
+        let limits = &mut config.rate_limit;
+        limits.max_message_length =
+            source.positive("MAX_MESSAGE_LENGTH", limits.max_message_length)?;
+        limits.user_burst = source.positive("USER_MESSAGE_BURST", limits.user_burst)?;
+        limits.user_per_sec = source.positive("USER_MESSAGES_PER_SEC", limits.user_per_sec)?;
+        limits.room_burst = source.positive("ROOM_MESSAGE_BURST", limits.room_burst)?;
+        limits.room_per_sec = source.positive("ROOM_MESSAGES_PER_SEC", limits.room_per_sec)?;
+        limits.signal_burst = source.positive("USER_SIGNAL_BURST", limits.signal_burst)?;
+        limits.signal_per_sec = source.positive("USER_SIGNALS_PER_SEC", limits.signal_per_sec)?;
+        config.idle_timeout = source.secs("ROOM_IDLE_TIMEOUT_SECS", config.idle_timeout)?;
+        config.cleanup_interval =
+            source.secs("ROOM_CLEANUP_INTERVAL_SECS", config.cleanup_interval)?;
+        config.presence_interval =
+            source.secs("PRESENCE_INTERVAL_SECS", config.presence_interval)?;
+        config.presence_ttl = source.secs("PRESENCE_TTL_SECS", config.presence_ttl)?;
+        if config.presence_ttl <= config.presence_interval {
+            Err(invalid(
+                "PRESENCE_TTL_SECS",
+                "has to be longer than PRESENCE_INTERVAL_SECS".to_string(),
+            ))?
+        }
+        Ok(config)
+    }
+}
+
+impl ConfigLoader for AttachmentConfig {
+    fn load(source: &Source) -> ConfigResult<Self>
+    where
+        Self: Sized,
+    {
+        let default = AttachmentConfig::default();
+        Ok(AttachmentConfig {
+            dir: source.parse("ATTACHMENTS_DIR", default.dir)?,
+            max_size: source.positive("MAX_ATTACHMENT_SIZE", default.max_size)?,
+        })
+    }
+}
+
+impl ConfigLoader for LogConfig {
+    fn load(source: &Source) -> ConfigResult<Self>
+    where
+        Self: Sized,
+    {
+        let default = LogConfig::default();
+        let config = LogConfig {
+            filter: source.get("LOG_FILTER").unwrap_or(default.filter),
+            format: source.parse("LOG_FORMAT", default.format)?,
+        };
+        config
+            .env_filter()
+            .map_err(|error| invalid("LOG_FILTER", error))?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(vars: &[(&str, &str)], file: &str) -> Source {
+        Source {
+            vars: vars
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            file: file.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn env_variables_override_the_file() {
+        let source = source(
+            &[
+                ("JWT_SECRET", "secret"),
+                ("SERVER_PORT", "8080"),
+                ("DATABASE_URL", ""),
+            ],
+            r#"
+            SERVER_HOST = "0.0.0.0"
+            SERVER_PORT = 9090
+            DATABASE_URL = "sqlite:chat.db"
+            ALLOWED_ORIGINS = ["http://localhost:5173", "https://chat.example.com"]
+            HISTORY_MAX_TAKE = 100
+            "#,
+        );
+        let config = Config::load(&source).unwrap();
+        assert_eq!(config.SERVER.address().to_string(), "0.0.0.0:8080");
+        assert_eq!(config.SERVER.ALLOWED_ORIGINS.len(), 2);
+        assert!(config.SERVER.TLS.is_none());
+        assert_eq!(
+            config.STORAGE.DATABASE_URL.as_deref(),
+            Some("sqlite:chat.db")
+        );
+        assert_eq!(config.ROOMS.history.max_take, 100);
+        assert_eq!(config.ROOMS.history.default_take, 50);
+    }
+
+    #[test]
+    fn invalid_settings_are_named() {
+        let cases = [
+            ("", "JWT_SECRET"),
+            ("SERVER_PORT = 70000", "SERVER_PORT"),
+            ("OUTBOX_POLICY = \"never\"", "OUTBOX_POLICY"),
+            ("PING_INTERVAL_SECS = 0", "PING_INTERVAL_SECS"),
+            ("HISTORY_DEFAULT_TAKE = 500", "HISTORY_DEFAULT_TAKE"),
+            ("PRESENCE_TTL_SECS = 5", "PRESENCE_TTL_SECS"),
+            (
+                "ALLOWED_ORIGINS = \"https://chat.example.com/\"",
+                "ALLOWED_ORIGINS",
+            ),
+            ("TLS_CERT_PATH = \"cert.pem\"", "TLS_KEY_PATH"),
+            ("LOG_FORMAT = \"xml\"", "LOG_FORMAT"),
+            ("LOG_FILTER = \"code=loud\"", "LOG_FILTER"),
+        ];
+        for (file, name) in cases {
+            let secret = match name {
+                "JWT_SECRET" => "",
+                _ => "secret",
+            };
+            let source = source(&[("JWT_SECRET", secret)], file);
+            match Config::load(&source) {
+                Err(ConfigError::Invalid { name: invalid, .. }) => assert_eq!(invalid, name),
+                _ => panic!("{file:?} should be rejected"),
+            }
+        }
+    }
+}
@@ -1,15 +1,28 @@
-use serde_json::json;
+use crate::metrics;
+use crate::protocol::ServerEvent;
+use serde::Serialize;
+use std::time::Duration;
 use thiserror::Error;
-use tokio::sync::mpsc::error::SendError;
-use warp::ws;
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{ws, Rejection, Reply};
 
 #[derive(Error, Debug)]
 pub enum ChatError {
-    #[error("User with name {0} already exist in this chat")]
-    UsernameTaken(String),
-
     #[error("Failed to send a message")]
-    Disconnect(#[from] SendError<ws::Message>),
+    Disconnect(),
+
+    #[error("Connection is too slow to keep up with the chat")]
+    SlowConsumer,
+
+    #[error("Client didn't answer the ping in time")]
+    HeartbeatTimeout,
+
+    #[error("Connection was idle for too long")]
+    IdleTimeout,
+
+    #[error("Server is restarting, reconnect in a moment")]
+    ServerShutdown,
 
     #[error(transparent)]
     InternalError(#[from] warp::Error),
@@ -19,21 +32,369 @@ pub enum ChatError {
 
     #[error("Body format is wrong")]
     InvalidMessageBody(#[from] serde_json::Error),
+
+    #[error("Protocol version {0} is not supported")]
+    UnsupportedVersion(u32),
+
+    #[error(transparent)]
+    Storage(#[from] sqlx::Error),
+
+    #[error(transparent)]
+    Broker(#[from] redis::RedisError),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("Chat {0} not found")]
+    ChatNotFound(String),
+
+    #[error("Chat {0} already exist")]
+    ChatAlreadyExist(String),
+
+    #[error("Chat {0} still has connected users")]
+    ChatNotEmpty(String),
+
+    #[error("Chat {0} is archived")]
+    ChatArchived(String),
+
+    #[error("You aren't a member of chat {0}")]
+    ChatForbidden(String),
+
+    #[error("Can't start a direct chat with {0:?}")]
+    InvalidRecipient(String),
+
+    #[error("You are banned from chat {0}")]
+    Banned(String),
+
+    #[error("You were kicked from the chat by {0}")]
+    Kicked(String),
+
+    #[error("You are muted in this chat")]
+    Muted,
+
+    #[error("You aren't allowed to moderate {0}")]
+    ModerationForbidden(String),
+
+    #[error("You are sending messages too fast, retry in {} ms", .0.as_millis())]
+    RateLimited(Duration),
+
+    #[error("Message is longer than {0} characters")]
+    MessageTooLong(usize),
+
+    #[error("The same message was sent too many times")]
+    DuplicateMessage,
+
+    #[error("Disconnected for flooding the chat")]
+    Flooding,
+
+    #[error("Message {0} not found")]
+    MessageNotFound(i64),
+
+    #[error("You aren't allowed to modify message {0}")]
+    MessageForbidden(i64),
+
+    #[error("Message {0} is a reply, threads start at the first message")]
+    InvalidThread(i64),
+
+    #[error("Reaction {0:?} is not valid")]
+    InvalidReaction(String),
+
+    #[error("Search query must have from 1 to {0} words")]
+    InvalidSearch(usize),
+
+    #[error("Attachment {0} not found")]
+    AttachmentNotFound(i64),
+
+    #[error("Session {0} not found")]
+    SessionNotFound(u64),
+
+    #[error("Attachment {0} can't be sent with this message")]
+    AttachmentForbidden(i64),
+
+    #[error("A message can't have more than {0} attachments")]
+    TooManyAttachments(usize),
+
+    #[error("Attachment is larger than {0} bytes")]
+    AttachmentTooLarge(usize),
+
+    #[error("Attachments of type {0:?} aren't supported")]
+    UnsupportedAttachment(String),
+
+    #[error("Upload is invalid: {0}")]
+    InvalidUpload(String),
+
+    #[error("Chat name is empty or reserved")]
+    InvalidChatName,
+
+    #[error("Unknown command /{0}, try /help")]
+    UnknownCommand(String),
+
+    #[error("Usage: {0}")]
+    InvalidCommand(&'static str),
+
+    #[error("You aren't allowed to use /{0}")]
+    CommandForbidden(&'static str),
+
+    #[error("Nickname {0} is already used in this chat")]
+    NicknameTaken(String),
+
+    #[error("There isn't token in the request. Try to add ?token=xxx, Authorization: Bearer xxx or Sec-WebSocket-Protocol: bearer, xxx")]
+    MissingToken,
+
+    #[error("Token is invalid: {0}")]
+    InvalidToken(#[from] jsonwebtoken::errors::Error),
 }
 
+impl warp::reject::Reject for ChatError {}
+
 impl ChatError {
-    pub fn to_request_body(&self) -> ws::Message {
-        let message = match self {
-            ChatError::UsernameTaken(_)
-            | ChatError::Disconnect(_)
+    pub fn code(&self) -> &'static str {
+        match self {
+            ChatError::Disconnect() => "disconnect",
+            ChatError::SlowConsumer => "slow_consumer",
+            ChatError::HeartbeatTimeout => "heartbeat_timeout",
+            ChatError::IdleTimeout => "idle_timeout",
+            ChatError::ServerShutdown => "server_shutdown",
+            ChatError::InternalError(_) => "internal_error",
+            ChatError::InvalidMessage() => "invalid_message",
+            ChatError::InvalidMessageBody(_) => "invalid_message_body",
+            ChatError::UnsupportedVersion(_) => "unsupported_version",
+            ChatError::Storage(_) => "storage",
+            ChatError::Broker(_) => "broker",
+            ChatError::Io(_) => "io",
+            ChatError::ChatNotFound(_) => "chat_not_found",
+            ChatError::ChatAlreadyExist(_) => "chat_already_exist",
+            ChatError::ChatNotEmpty(_) => "chat_not_empty",
+            ChatError::ChatArchived(_) => "chat_archived",
+            ChatError::ChatForbidden(_) => "chat_forbidden",
+            ChatError::InvalidRecipient(_) => "invalid_recipient",
+            ChatError::Banned(_) => "banned",
+            ChatError::Kicked(_) => "kicked",
+            ChatError::Muted => "muted",
+            ChatError::ModerationForbidden(_) => "moderation_forbidden",
+            ChatError::RateLimited(_) => "rate_limited",
+            ChatError::MessageTooLong(_) => "message_too_long",
+            ChatError::DuplicateMessage => "duplicate_message",
+            ChatError::Flooding => "flooding",
+            ChatError::MessageNotFound(_) => "message_not_found",
+            ChatError::InvalidThread(_) => "invalid_thread",
+            ChatError::MessageForbidden(_) => "message_forbidden",
+            ChatError::InvalidReaction(_) => "invalid_reaction",
+            ChatError::InvalidSearch(_) => "invalid_search",
+            ChatError::AttachmentNotFound(_) => "attachment_not_found",
+            ChatError::SessionNotFound(_) => "session_not_found",
+            ChatError::AttachmentForbidden(_) => "attachment_forbidden",
+            ChatError::TooManyAttachments(_) => "too_many_attachments",
+            ChatError::AttachmentTooLarge(_) => "attachment_too_large",
+            ChatError::UnsupportedAttachment(_) => "unsupported_attachment",
+            ChatError::InvalidUpload(_) => "invalid_upload",
+            ChatError::InvalidChatName => "invalid_chat_name",
+            ChatError::UnknownCommand(_) => "unknown_command",
+            ChatError::InvalidCommand(_) => "invalid_command",
+            ChatError::CommandForbidden(_) => "command_forbidden",
+            ChatError::NicknameTaken(_) => "nickname_taken",
+            ChatError::MissingToken => "missing_token",
+            ChatError::InvalidToken(_) => "invalid_token",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ChatError::ChatAlreadyExist(_)
+            | ChatError::ChatNotEmpty(_)
+            | ChatError::NicknameTaken(_) => StatusCode::CONFLICT,
+            ChatError::InvalidMessage()
+            | ChatError::InvalidMessageBody(_)
+            | ChatError::UnsupportedVersion(_)
+            | ChatError::InvalidReaction(_)
+            | ChatError::InvalidThread(_)
+            | ChatError::InvalidRecipient(_)
+            | ChatError::InvalidSearch(_)
+            | ChatError::TooManyAttachments(_)
+            | ChatError::InvalidUpload(_)
+            | ChatError::InvalidChatName
+            | ChatError::UnknownCommand(_)
+            | ChatError::InvalidCommand(_) => StatusCode::BAD_REQUEST,
+            ChatError::ChatArchived(_)
+            | ChatError::ChatForbidden(_)
+            | ChatError::Banned(_)
+            | ChatError::Kicked(_)
+            | ChatError::Muted
+            | ChatError::ModerationForbidden(_)
+            | ChatError::MessageForbidden(_)
+            | ChatError::AttachmentForbidden(_)
+            | ChatError::CommandForbidden(_) => StatusCode::FORBIDDEN,
+            ChatError::HeartbeatTimeout | ChatError::IdleTimeout => StatusCode::REQUEST_TIMEOUT,
+            ChatError::MissingToken | ChatError::InvalidToken(_) => StatusCode::UNAUTHORIZED,
+            ChatError::ChatNotFound(_)
+            | ChatError::MessageNotFound(_)
+            | ChatError::AttachmentNotFound(_)
+            | ChatError::SessionNotFound(_) => StatusCode::NOT_FOUND,
+            ChatError::RateLimited(_) | ChatError::DuplicateMessage | ChatError::Flooding => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            ChatError::MessageTooLong(_) | ChatError::AttachmentTooLarge(_) => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            ChatError::UnsupportedAttachment(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ChatError::ServerShutdown => StatusCode::SERVICE_UNAVAILABLE,
+            ChatError::Disconnect()
+            | ChatError::SlowConsumer
+            | ChatError::InternalError(_)
+            | ChatError::Storage(_)
+            | ChatError::Broker(_)
+            | ChatError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn public_message(&self) -> String {
+        match self {
+            ChatError::Disconnect()
+            | ChatError::SlowConsumer
+            | ChatError::HeartbeatTimeout
+            | ChatError::IdleTimeout
+            | ChatError::ServerShutdown
             | ChatError::InvalidMessage()
-            | ChatError::InvalidMessageBody(_) => self.to_string(),
+            | ChatError::InvalidMessageBody(_)
+            | ChatError::UnsupportedVersion(_)
+            | ChatError::ChatNotFound(_)
+            | ChatError::ChatAlreadyExist(_)
+            | ChatError::ChatNotEmpty(_)
+            | ChatError::ChatArchived(_)
+            | ChatError::ChatForbidden(_)
+            | ChatError::InvalidRecipient(_)
+            | ChatError::Banned(_)
+            | ChatError::Kicked(_)
+            | ChatError::Muted
+            | ChatError::ModerationForbidden(_)
+            | ChatError::RateLimited(_)
+            | ChatError::MessageTooLong(_)
+            | ChatError::DuplicateMessage
+            | ChatError::Flooding
+            | ChatError::MessageNotFound(_)
+            | ChatError::MessageForbidden(_)
+            | ChatError::InvalidReaction(_)
+            | ChatError::InvalidThread(_)
+            | ChatError::InvalidSearch(_)
+            | ChatError::AttachmentNotFound(_)
+            | ChatError::SessionNotFound(_)
+            | ChatError::AttachmentForbidden(_)
+            | ChatError::TooManyAttachments(_)
+            | ChatError::AttachmentTooLarge(_)
+            | ChatError::UnsupportedAttachment(_)
+            | ChatError::InvalidUpload(_)
+            | ChatError::InvalidChatName
+            | ChatError::UnknownCommand(_)
+            | ChatError::InvalidCommand(_)
+            | ChatError::CommandForbidden(_)
+            | ChatError::NicknameTaken(_)
+            | ChatError::MissingToken
+            | ChatError::InvalidToken(_) => self.to_string(),
             _ => "Something went wrong".to_string(),
+        }
+    }
+
+    // The connection stays open after these, the client only gets an error event
+    pub fn is_recoverable(&self) -> bool {
+        matches!(
+            self,
+            ChatError::MessageNotFound(_)
+                | ChatError::MessageForbidden(_)
+                | ChatError::InvalidReaction(_)
+                | ChatError::InvalidThread(_)
+                | ChatError::InvalidSearch(_)
+                | ChatError::AttachmentForbidden(_)
+                | ChatError::TooManyAttachments(_)
+                | ChatError::Muted
+                | ChatError::ModerationForbidden(_)
+                | ChatError::RateLimited(_)
+                | ChatError::MessageTooLong(_)
+                | ChatError::DuplicateMessage
+                | ChatError::UnknownCommand(_)
+                | ChatError::InvalidCommand(_)
+                | ChatError::CommandForbidden(_)
+                | ChatError::NicknameTaken(_)
+        )
+    }
+
+    // Counted against the session, see `FloodGuard`
+    pub fn is_limit_violation(&self) -> bool {
+        matches!(
+            self,
+            ChatError::RateLimited(_) | ChatError::MessageTooLong(_) | ChatError::DuplicateMessage
+        )
+    }
+
+    pub fn to_request_json(&self) -> String {
+        ServerEvent::Error {
+            code: self.code().to_string(),
+            message: self.public_message(),
+            fatal: !self.is_recoverable(),
+        }
+        .to_json()
+    }
+
+    pub fn to_request_body(&self) -> ws::Message {
+        ws::Message::text(self.to_request_json())
+    }
+
+    // Codes 4000-4999 are reserved for applications by RFC 6455
+    pub fn close_code(&self) -> u16 {
+        match self {
+            ChatError::HeartbeatTimeout => 4000,
+            ChatError::IdleTimeout => 4001,
+            ChatError::SlowConsumer => 4002,
+            ChatError::Kicked(_) => 4003,
+            ChatError::Banned(_) => 4004,
+            ChatError::Flooding => 4005,
+            ChatError::InvalidMessage() => 1003,
+            // Service Restart, clients should reconnect
+            ChatError::ServerShutdown => 1012,
+            ChatError::InvalidMessageBody(_) => 1007,
+            ChatError::Disconnect()
+            | ChatError::InternalError(_)
+            | ChatError::Storage(_)
+            | ChatError::Broker(_)
+            | ChatError::Io(_) => 1011,
+            _ => 1008,
+        }
+    }
+
+    pub fn to_close_frame(&self) -> ws::Message {
+        ws::Message::close_with(self.close_code(), self.public_message())
+    }
+
+    pub fn to_response(&self) -> Response {
+        let body = ResponseBody {
+            code: self.code(),
+            message: self.public_message(),
         };
-        let body = serde_json::to_string(&json!({
-            "error_message": message,
-        }))
-        .unwrap();
-        ws::Message::text(body)
+        warp::reply::with_status(warp::reply::json(&body), self.status()).into_response()
     }
 }
+
+#[derive(Serialize, Debug)]
+struct ResponseBody<'a> {
+    code: &'a str,
+    message: String,
+}
+
+pub async fn handle_rejection(rejection: Rejection) -> Result<Response, Rejection> {
+    match rejection.find::<ChatError>() {
+        Some(error) => {
+            metrics::error_sent(error);
+            Ok(error.to_response())
+        }
+        None => Err(rejection),
+    }
+}
+
+// Reported once at startup, the server doesn't start with an invalid config
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Can't read config file {path:?}: {message}")]
+    File { path: String, message: String },
+
+    #[error("Problem with {name} setting: {message}")]
+    Invalid { name: &'static str, message: String },
+}
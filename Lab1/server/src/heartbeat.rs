@@ -0,0 +1,141 @@
+use std::time::Duration;
+use tokio::time::{sleep_until, Instant};
+
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+const PONG_TIMEOUT: Duration = Duration::from_secs(10);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    pub ping_interval: Duration,
+    pub pong_timeout: Duration,
+    // Time without any client event, pongs don't count
+    pub idle_timeout: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            ping_interval: PING_INTERVAL,
+            pong_timeout: PONG_TIMEOUT,
+            idle_timeout: IDLE_TIMEOUT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeartbeatEvent {
+    Ping,
+    PongTimeout,
+    IdleTimeout,
+}
+
+pub struct Heartbeat {
+    config: HeartbeatConfig,
+    next_ping: Instant,
+    pong_deadline: Option<Instant>,
+    last_activity: Instant,
+}
+
+impl Heartbeat {
+    pub fn new(config: HeartbeatConfig) -> Self {
+        let now = Instant::now();
+        Heartbeat {
+            config,
+            next_ping: now + config.ping_interval,
+            pong_deadline: None,
+            last_activity: now,
+        }
+    }
+
+    // Any frame from the client proves the connection is still alive
+    pub fn alive(&mut self) {
+        self.pong_deadline = None;
+    }
+
+    pub fn active(&mut self) {
+        self.last_activity = Instant::now();
+    }
+
+    pub fn ping_sent(&mut self) {
+        let now = Instant::now();
+        self.next_ping = now + self.config.ping_interval;
+        if self.pong_deadline.is_none() {
+            self.pong_deadline = Some(now + self.config.pong_timeout);
+        }
+    }
+
+    // Resolves when the next heartbeat action is due
+    pub async fn next(&self) -> HeartbeatEvent {
+        let idle_deadline = self.last_activity + self.config.idle_timeout;
+        let (deadline, event) = match self.pong_deadline {
+            Some(pong_deadline) if pong_deadline <= self.next_ping => {
+                (pong_deadline, HeartbeatEvent::PongTimeout)
+            }
+            _ => (self.next_ping, HeartbeatEvent::Ping),
+        };
+        if idle_deadline <= deadline {
+            sleep_until(idle_deadline).await;
+            return HeartbeatEvent::IdleTimeout;
+        }
+        sleep_until(deadline).await;
+        event
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> HeartbeatConfig {
+        HeartbeatConfig {
+            ping_interval: Duration::from_millis(20),
+            pong_timeout: Duration::from_millis(10),
+            idle_timeout: Duration::from_secs(5),
+        }
+    }
+
+    #[tokio::test]
+    async fn unanswered_pings_time_out() {
+        let mut heartbeat = Heartbeat::new(config());
+        assert_eq!(heartbeat.next().await, HeartbeatEvent::Ping);
+        heartbeat.ping_sent();
+        assert_eq!(heartbeat.next().await, HeartbeatEvent::PongTimeout);
+    }
+
+    #[tokio::test]
+    async fn answered_pings_are_repeated() {
+        let mut heartbeat = Heartbeat::new(config());
+        heartbeat.ping_sent();
+        heartbeat.alive();
+        let sent_at = Instant::now();
+        assert_eq!(heartbeat.next().await, HeartbeatEvent::Ping);
+        assert!(sent_at.elapsed() >= Duration::from_millis(15));
+    }
+
+    #[tokio::test]
+    async fn pongs_dont_count_as_activity() {
+        let config = HeartbeatConfig {
+            idle_timeout: Duration::from_millis(50),
+            ..config()
+        };
+        let mut heartbeat = Heartbeat::new(config);
+        let started_at = Instant::now();
+        loop {
+            match heartbeat.next().await {
+                HeartbeatEvent::Ping => {
+                    heartbeat.ping_sent();
+                    heartbeat.alive();
+                }
+                event => {
+                    assert_eq!(event, HeartbeatEvent::IdleTimeout);
+                    break;
+                }
+            }
+        }
+        assert!(started_at.elapsed() >= Duration::from_millis(50));
+
+        heartbeat.active();
+        assert_eq!(heartbeat.next().await, HeartbeatEvent::Ping);
+    }
+}
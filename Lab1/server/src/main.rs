@@ -1,29 +1,91 @@
+mod attachment;
+mod attachment_controller;
+mod auth;
+mod broker;
 mod chat;
+mod chat_controller;
 mod chat_service;
+mod command;
+mod config;
 mod error;
+mod heartbeat;
+mod logging;
+mod metrics;
+mod moderation;
+mod outbox;
+mod protocol;
+mod rate_limit;
+mod routes;
+mod search;
+mod shutdown;
+mod sse_service;
+mod storage;
 mod utils;
 
-use crate::chat::{Chats, User};
-use crate::utils::Pagination;
+use crate::attachment::AttachmentFiles;
+use crate::broker::{Bus, MemoryBroker, RedisBroker};
+use crate::chat::Chats;
+use crate::command::Commands;
+use crate::config::load_config;
+use crate::storage::{MemoryStore, SqliteStore, Store};
 
-use warp::Filter;
+use futures_util::FutureExt;
+use std::sync::Arc;
+use tracing::info;
 
 #[tokio::main]
 async fn main() {
-    let chats = Chats::default();
-    let chats = warp::any().map(move || chats.clone());
+    let config = load_config().unwrap_or_else(|error| panic!("Invalid configuration: {error}"));
+    logging::init(&config.LOG);
+    let store: Store = match &config.STORAGE.DATABASE_URL {
+        Some(url) => Arc::new(SqliteStore::connect_and_migrate(url).await),
+        None => Arc::new(MemoryStore::default()),
+    };
+    // Instances sharing a redis server serve the same rooms
+    let bus: Bus = match &config.STORAGE.REDIS_URL {
+        Some(url) => Arc::new(RedisBroker::connect(url).await),
+        None => Arc::new(MemoryBroker::default()),
+    };
+    let chats = Chats::new(
+        store,
+        bus,
+        config.OUTBOX,
+        config.HEARTBEAT,
+        config.ROOMS,
+        AttachmentFiles::new(config.ATTACHMENTS.clone()),
+        // Custom commands are added here with `.register(...)`
+        Commands::default(),
+    );
+    tokio::spawn(chats.clone().run_cleanup());
+    let routes = routes::routes(
+        chats.clone(),
+        Arc::new(config.JWT.SECRET.clone()),
+        config.ATTACHMENTS.max_size,
+        &config.SERVER.ALLOWED_ORIGINS,
+    );
 
-    let chat = warp::path("chat")
-        .and(warp::ws())
-        .and(chats)
-        .and(warp::path::param())
-        .and(warp::query::<User>())
-        .and(warp::query::<Pagination>())
-        .map(|ws: warp::ws::Ws, chats, chat_name, user: User, page| {
-            ws.on_upgrade(move |socket| {
-                chat_service::on_user_connection(socket, chats, chat_name, user.username, page)
-            })
-        });
-
-    warp::serve(chat).run(([127, 0, 0, 1], 3030)).await;
+    // The listener stops on the signal, upgraded sockets are closed by the chats
+    let signal = shutdown::signal_received().boxed().shared();
+    let address = config.SERVER.address();
+    let server = match &config.SERVER.TLS {
+        Some(tls) => {
+            let (address, server) = warp::serve(routes)
+                .tls()
+                .cert_path(&tls.CERT_PATH)
+                .key_path(&tls.KEY_PATH)
+                .bind_with_graceful_shutdown(address, signal.clone());
+            info!("listening on https://{address}");
+            server.boxed()
+        }
+        None => {
+            let (address, server) =
+                warp::serve(routes).bind_with_graceful_shutdown(address, signal.clone());
+            info!("listening on http://{address}");
+            server.boxed()
+        }
+    };
+    tokio::spawn(server);
+    signal.await;
+    info!("shutting down");
+    chats.shutdown(config.SERVER.SHUTDOWN_TIMEOUT).await;
 }
@@ -0,0 +1,91 @@
+use crate::error::ChatError;
+use prometheus::{Encoder, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder};
+use std::sync::{LazyLock, Mutex};
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+struct Metrics {
+    registry: Registry,
+    users: IntGaugeVec,
+    messages: IntCounter,
+    errors: IntCounterVec,
+    // Scrapes reset the user gauges, one at a time keeps them whole
+    scrape: Mutex<()>,
+}
+
+// Process wide, errors are also counted where no chat is at hand
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
+    let users = IntGaugeVec::new(
+        Opts::new(
+            "chat_connected_users",
+            "Users connected to the room through this instance",
+        ),
+        &["room"],
+    )
+    .unwrap();
+    let messages = IntCounter::new(
+        "chat_messages_total",
+        "Messages posted, its rate is the messages per second",
+    )
+    .unwrap();
+    let errors = IntCounterVec::new(
+        Opts::new(
+            "chat_errors_total",
+            "Errors sent to the clients by ChatError variant",
+        ),
+        &["error"],
+    )
+    .unwrap();
+    let registry = Registry::new();
+    registry.register(Box::new(users.clone())).unwrap();
+    registry.register(Box::new(messages.clone())).unwrap();
+    registry.register(Box::new(errors.clone())).unwrap();
+    Metrics {
+        registry,
+        users,
+        messages,
+        errors,
+        scrape: Mutex::new(()),
+    }
+});
+
+pub fn message_posted() {
+    METRICS.messages.inc();
+}
+
+pub fn error_sent(error: &ChatError) {
+    METRICS.errors.with_label_values(&[error.code()]).inc();
+}
+
+// Users are counted on each scrape, rooms nobody is in anymore don't show up
+pub fn render(users: &[(String, usize)]) -> String {
+    let _scrape = METRICS.scrape.lock().unwrap();
+    METRICS.users.reset();
+    for (room, count) in users {
+        METRICS.users.with_label_values(&[room]).set(*count as i64);
+    }
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&METRICS.registry.gather(), &mut buffer)
+        .unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_the_prometheus_text_format() {
+        error_sent(&ChatError::Muted);
+        let users = [("general".to_string(), 2), ("random".to_string(), 1)];
+        let text = render(&users);
+        assert!(text.contains("# TYPE chat_connected_users gauge"));
+        assert!(text.contains("chat_connected_users{room=\"general\"} 2"));
+        assert!(text.contains("# TYPE chat_messages_total counter"));
+        assert!(text.contains("chat_errors_total{error=\"muted\"}"));
+
+        let text = render(&users[1..]);
+        assert!(!text.contains("room=\"general\""));
+    }
+}
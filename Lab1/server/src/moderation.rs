@@ -0,0 +1,125 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(
+    Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, sqlx::Type,
+)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum Role {
+    Member,
+    Moderator,
+    Owner,
+}
+
+impl Role {
+    pub fn can_moderate(self) -> bool {
+        self >= Role::Moderator
+    }
+
+    // Moderators can only act on plain members, the owner on everyone else
+    pub fn outranks(self, other: Role) -> bool {
+        self.can_moderate() && self > other
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum SanctionKind {
+    Ban,
+    Mute,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, sqlx::FromRow)]
+pub struct Sanction {
+    pub username: String,
+    pub kind: SanctionKind,
+    pub reason: Option<String>,
+    // Never expires when empty
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Sanction {
+    pub fn new(
+        username: String,
+        kind: SanctionKind,
+        reason: Option<String>,
+        expires_in: Option<u64>,
+        created_by: String,
+    ) -> Self {
+        let created_at = Utc::now();
+        Sanction {
+            username,
+            kind,
+            reason,
+            // Durations too long to represent never expire
+            expires_at: expires_in
+                .and_then(|secs| Duration::try_seconds(i64::try_from(secs).ok()?))
+                .and_then(|duration| created_at.checked_add_signed(duration)),
+            created_by,
+            created_at,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.expires_at
+            .is_none_or(|expires_at| expires_at > Utc::now())
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ModerationAction {
+    Kick,
+    Ban,
+    Unban,
+    Mute,
+    Unmute,
+    Promote,
+    Demote,
+}
+
+impl ModerationAction {
+    // Changing roles is left to the owner
+    pub fn required_role(self) -> Role {
+        match self {
+            ModerationAction::Promote | ModerationAction::Demote => Role::Owner,
+            _ => Role::Moderator,
+        }
+    }
+
+    fn past_tense(self) -> &'static str {
+        match self {
+            ModerationAction::Kick => "kicked",
+            ModerationAction::Ban => "banned",
+            ModerationAction::Unban => "unbanned",
+            ModerationAction::Mute => "muted",
+            ModerationAction::Unmute => "unmuted",
+            ModerationAction::Promote => "promoted to moderator",
+            ModerationAction::Demote => "demoted to member",
+        }
+    }
+
+    pub fn describe(
+        self,
+        username: &str,
+        by: &str,
+        reason: Option<&str>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> String {
+        let mut text = format!("{username} was {} by {by}", self.past_tense());
+        if let Some(expires_at) = expires_at {
+            text.push_str(&format!(
+                " until {}",
+                expires_at.format("%Y-%m-%d %H:%M UTC")
+            ));
+        }
+        if let Some(reason) = reason {
+            text.push_str(&format!(": {reason}"));
+        }
+        text
+    }
+}
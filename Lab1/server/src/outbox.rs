@@ -0,0 +1,325 @@
+use crate::error::ChatError;
+use crate::protocol::ServerEvent;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{watch, Notify};
+use warp::ws::Message;
+
+const DEFAULT_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    DropOldest,
+    Disconnect,
+    // Ephemeral events (typing, roster) replace their queued predecessor,
+    // the consumer is disconnected if the queue is still full
+    Coalesce,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "drop_oldest" => Ok(OverflowPolicy::DropOldest),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            "coalesce" => Ok(OverflowPolicy::Coalesce),
+            _ => Err(format!("Unknown overflow policy: {policy}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OutboxConfig {
+    pub capacity: usize,
+    pub policy: OverflowPolicy,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        OutboxConfig {
+            capacity: DEFAULT_CAPACITY,
+            policy: OverflowPolicy::Coalesce,
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct QueueMetrics {
+    connections: AtomicUsize,
+    queued: AtomicUsize,
+    max_depth: AtomicUsize,
+    dropped: AtomicU64,
+    slow_consumers: AtomicU64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct QueueStats {
+    pub connections: usize,
+    pub queued: usize,
+    pub max_depth: usize,
+    pub dropped: u64,
+    pub slow_consumers: u64,
+}
+
+impl QueueMetrics {
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            connections: self.connections.load(Ordering::Relaxed),
+            queued: self.queued.load(Ordering::Relaxed),
+            max_depth: self.max_depth.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            slow_consumers: self.slow_consumers.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Open,
+    // Already queued messages are still delivered
+    Closing,
+    Aborted,
+}
+
+struct Queued {
+    message: Message,
+    key: Option<String>,
+}
+
+struct Inner {
+    queue: Mutex<VecDeque<Queued>>,
+    notify: Notify,
+    state: watch::Sender<State>,
+    config: OutboxConfig,
+    metrics: Arc<QueueMetrics>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        let left = self.queue.get_mut().map_or(0, |queue| queue.len());
+        self.metrics.queued.fetch_sub(left, Ordering::Relaxed);
+        self.metrics.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Bounded queue of frames waiting to be written to one websocket
+#[derive(Clone)]
+pub struct Outbox {
+    inner: Arc<Inner>,
+}
+
+impl Outbox {
+    pub fn new(config: OutboxConfig, metrics: Arc<QueueMetrics>) -> Self {
+        metrics.connections.fetch_add(1, Ordering::Relaxed);
+        let (state, _) = watch::channel(State::Open);
+        Outbox {
+            inner: Arc::new(Inner {
+                queue: Mutex::new(VecDeque::with_capacity(config.capacity)),
+                notify: Notify::new(),
+                state,
+                config,
+                metrics,
+            }),
+        }
+    }
+
+    pub fn send(&self, message: Message) -> Result<(), ChatError> {
+        self.push(message, None)
+    }
+
+    pub fn send_event(&self, event: &ServerEvent) -> Result<(), ChatError> {
+        self.push(event.to_message(), event.coalesce_key().as_deref())
+    }
+
+    pub fn push(&self, message: Message, key: Option<&str>) -> Result<(), ChatError> {
+        if *self.inner.state.borrow() != State::Open {
+            Err(ChatError::Disconnect())?
+        }
+        let metrics = &self.inner.metrics;
+        let mut queue = self.inner.queue.lock().unwrap();
+
+        if self.inner.config.policy == OverflowPolicy::Coalesce && key.is_some() {
+            if let Some(queued) = queue.iter_mut().find(|q| q.key.as_deref() == key) {
+                queued.message = message;
+                return Ok(());
+            }
+        }
+
+        if queue.len() >= self.inner.config.capacity {
+            match self.inner.config.policy {
+                OverflowPolicy::DropOldest => {
+                    queue.pop_front();
+                    metrics.queued.fetch_sub(1, Ordering::Relaxed);
+                    metrics.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                OverflowPolicy::Disconnect | OverflowPolicy::Coalesce => {
+                    metrics.queued.fetch_sub(queue.len(), Ordering::Relaxed);
+                    metrics
+                        .dropped
+                        .fetch_add(queue.len() as u64, Ordering::Relaxed);
+                    metrics.slow_consumers.fetch_add(1, Ordering::Relaxed);
+                    queue.clear();
+                    drop(queue);
+                    self.abort();
+                    return Err(ChatError::SlowConsumer);
+                }
+            }
+        }
+
+        queue.push_back(Queued {
+            message,
+            key: key.map(str::to_string),
+        });
+        metrics.queued.fetch_add(1, Ordering::Relaxed);
+        metrics.max_depth.fetch_max(queue.len(), Ordering::Relaxed);
+        drop(queue);
+        self.inner.notify.notify_one();
+        Ok(())
+    }
+
+    // Returns None once the outbox is closed and drained, or aborted
+    pub async fn recv(&self) -> Option<Message> {
+        loop {
+            {
+                let state = *self.inner.state.borrow();
+                if state == State::Aborted {
+                    return None;
+                }
+                let mut queue = self.inner.queue.lock().unwrap();
+                if let Some(queued) = queue.pop_front() {
+                    self.inner.metrics.queued.fetch_sub(1, Ordering::Relaxed);
+                    return Some(queued.message);
+                }
+                if state == State::Closing {
+                    return None;
+                }
+            }
+            self.inner.notify.notified().await;
+        }
+    }
+
+    pub fn close(&self) {
+        self.set_state(State::Closing);
+    }
+
+    pub fn abort(&self) {
+        self.set_state(State::Aborted);
+    }
+
+    // Resolves once the outbox stops accepting messages
+    pub async fn closed(&self) {
+        let mut state = self.inner.state.subscribe();
+        let _ = state.wait_for(|state| *state != State::Open).await;
+    }
+
+    pub async fn aborted(&self) {
+        let mut state = self.inner.state.subscribe();
+        let _ = state.wait_for(|state| *state == State::Aborted).await;
+    }
+
+    fn set_state(&self, new_state: State) {
+        self.inner.state.send_if_modified(|state| {
+            let is_upgrade = *state == State::Open || new_state == State::Aborted;
+            if is_upgrade {
+                *state = new_state;
+            }
+            is_upgrade
+        });
+        self.inner.notify.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    fn outbox(policy: OverflowPolicy) -> (Outbox, Arc<QueueMetrics>) {
+        let metrics = Arc::new(QueueMetrics::default());
+        let config = OutboxConfig {
+            capacity: 2,
+            policy,
+        };
+        (Outbox::new(config, metrics.clone()), metrics)
+    }
+
+    async fn texts(outbox: &Outbox) -> Vec<String> {
+        let mut texts = vec![];
+        while let Ok(Some(message)) = timeout(Duration::from_millis(20), outbox.recv()).await {
+            texts.push(message.to_str().unwrap().to_string());
+        }
+        texts
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_makes_room() {
+        let (outbox, metrics) = outbox(OverflowPolicy::DropOldest);
+        for text in ["one", "two", "three"] {
+            outbox.send(Message::text(text)).unwrap();
+        }
+        let stats = metrics.stats();
+        assert_eq!((stats.queued, stats.max_depth, stats.dropped), (2, 2, 1));
+        assert_eq!(texts(&outbox).await, ["two", "three"]);
+        assert_eq!(metrics.stats().queued, 0);
+    }
+
+    #[tokio::test]
+    async fn slow_consumers_are_disconnected() {
+        let (outbox, metrics) = outbox(OverflowPolicy::Disconnect);
+        outbox.send(Message::text("one")).unwrap();
+        outbox.send(Message::text("two")).unwrap();
+        let overflow = outbox.send(Message::text("three"));
+        assert!(matches!(overflow, Err(ChatError::SlowConsumer)));
+        assert!(outbox.send(Message::text("four")).is_err());
+
+        let stats = metrics.stats();
+        assert_eq!(
+            (stats.queued, stats.dropped, stats.slow_consumers),
+            (0, 2, 1)
+        );
+        assert_eq!(outbox.recv().await, None);
+        timeout(Duration::from_secs(1), outbox.aborted())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn keyed_events_replace_their_predecessor() {
+        let (outbox, metrics) = outbox(OverflowPolicy::Coalesce);
+        outbox
+            .push(Message::text("alice typing"), Some("typing:alice"))
+            .unwrap();
+        outbox.send(Message::text("hello")).unwrap();
+        outbox
+            .push(Message::text("alice typing again"), Some("typing:alice"))
+            .unwrap();
+        assert_eq!(metrics.stats().dropped, 0);
+        assert_eq!(texts(&outbox).await, ["alice typing again", "hello"]);
+
+        outbox.send(Message::text("one")).unwrap();
+        outbox.send(Message::text("two")).unwrap();
+        let overflow = outbox.push(Message::text("bob typing"), Some("typing:bob"));
+        assert!(matches!(overflow, Err(ChatError::SlowConsumer)));
+    }
+
+    #[tokio::test]
+    async fn closed_outboxes_are_drained_first() {
+        let (outbox, metrics) = outbox(OverflowPolicy::Coalesce);
+        outbox.send(Message::text("bye")).unwrap();
+        outbox.close();
+        assert!(outbox.send(Message::text("too late")).is_err());
+        timeout(Duration::from_secs(1), outbox.closed())
+            .await
+            .unwrap();
+        assert_eq!(outbox.recv().await, Some(Message::text("bye")));
+        assert_eq!(outbox.recv().await, None);
+
+        drop(outbox);
+        assert_eq!(metrics.stats().connections, 0);
+    }
+}
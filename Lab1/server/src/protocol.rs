@@ -0,0 +1,204 @@
+use crate::chat::{ChatMessage, Reaction, Thread};
+use crate::error::ChatError;
+use crate::moderation::{ModerationAction, Role};
+use crate::search::{SearchParams, SearchResults};
+use crate::storage::ReadReceipt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use warp::ws::Message;
+
+pub const PROTOCOL_VERSION: u32 = 1;
+const SUPPORTED_VERSIONS: RangeInclusive<u32> = 1..=PROTOCOL_VERSION;
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ProtocolQuery {
+    pub version: Option<u32>,
+}
+
+impl ProtocolQuery {
+    pub fn negotiate(&self) -> Result<u32, ChatError> {
+        let version = self.version.unwrap_or(PROTOCOL_VERSION);
+        if SUPPORTED_VERSIONS.contains(&version) {
+            Ok(version)
+        } else {
+            Err(ChatError::UnsupportedVersion(version))
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ClientEvent {
+    Message {
+        text: String,
+        client_id: Option<String>,
+        // Ids of the files uploaded beforehand
+        #[serde(default)]
+        attachments: Vec<i64>,
+        // Posts the message as a reply in the thread of this one
+        parent_id: Option<i64>,
+        // Keeps the reply out of the room stream
+        #[serde(default)]
+        thread_only: bool,
+    },
+    // Pages back from `before`, or on from `after` through missed messages
+    History {
+        before: Option<i64>,
+        after: Option<i64>,
+        take: Option<u32>,
+    },
+    Search(SearchParams),
+    // Messages around the given one, e.g. to show a search result
+    Context {
+        id: i64,
+        take: Option<u32>,
+    },
+    // The message and its replies, paged like the history
+    Thread {
+        id: i64,
+        before: Option<i64>,
+        take: Option<u32>,
+    },
+    // Ephemeral, other users are notified but it is never stored
+    Typing,
+    Read {
+        id: i64,
+    },
+    Edit {
+        id: i64,
+        text: String,
+    },
+    Delete {
+        id: i64,
+    },
+    React {
+        id: i64,
+        emoji: String,
+    },
+    Unreact {
+        id: i64,
+        emoji: String,
+    },
+    Moderate {
+        action: ModerationAction,
+        username: String,
+        reason: Option<String>,
+        // Seconds until a ban or mute expires, forever when empty
+        expires_in: Option<u64>,
+    },
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ServerEvent {
+    Welcome {
+        version: u32,
+        // Client events of the server-sent events transport are posted to it
+        session: u64,
+        chat: String,
+        username: String,
+        role: Role,
+        topic: Option<String>,
+        // Only the members who have set one
+        nicknames: HashMap<String, String>,
+    },
+    Message {
+        message: ChatMessage,
+    },
+    // Thread only replies, clients show them in the open thread only
+    Reply {
+        message: ChatMessage,
+    },
+    Replies {
+        id: i64,
+        reply_count: i64,
+    },
+    Thread(Thread),
+    History {
+        messages: Vec<ChatMessage>,
+        // Last message id each user has read in the room
+        read: Vec<ReadReceipt>,
+        // Older messages are left before the page, or newer ones after an `after` page
+        has_more: bool,
+    },
+    Search(SearchResults),
+    Context {
+        id: i64,
+        messages: Vec<ChatMessage>,
+    },
+    Roster {
+        users: Vec<String>,
+    },
+    Join {
+        username: String,
+    },
+    Leave {
+        username: String,
+    },
+    Typing {
+        username: String,
+    },
+    Read {
+        username: String,
+        last_read_id: i64,
+    },
+    Ack {
+        client_id: Option<String>,
+        id: i64,
+    },
+    Edited {
+        message: ChatMessage,
+    },
+    Deleted {
+        id: i64,
+    },
+    Reactions {
+        id: i64,
+        reactions: Vec<Reaction>,
+    },
+    System {
+        action: ModerationAction,
+        username: String,
+        by: String,
+        text: String,
+    },
+    Topic {
+        topic: Option<String>,
+        username: String,
+    },
+    Nick {
+        username: String,
+        nickname: Option<String>,
+    },
+    // Command output, only the user who ran the command gets it
+    Notice {
+        text: String,
+    },
+    Error {
+        code: String,
+        message: String,
+        // The server closes the connection after a fatal error
+        fatal: bool,
+    },
+}
+
+impl ServerEvent {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn to_message(&self) -> Message {
+        Message::text(self.to_json())
+    }
+
+    // Events with the same key only matter in their latest state
+    pub fn coalesce_key(&self) -> Option<String> {
+        match self {
+            ServerEvent::Roster { .. } => Some("roster".to_string()),
+            ServerEvent::Typing { username } => Some(format!("typing:{username}")),
+            ServerEvent::Read { username, .. } => Some(format!("read:{username}")),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,348 @@
+use crate::error::ChatError;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use tokio::time::Instant;
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitConfig {
+    pub user_burst: u32,
+    pub user_per_sec: f64,
+    pub room_burst: u32,
+    pub room_per_sec: f64,
+    // Typing notifications and read receipts are cheaper, they have their own bucket
+    pub signal_burst: u32,
+    pub signal_per_sec: f64,
+    pub max_message_length: usize,
+    // The same text can't be repeated more than `max_duplicates` times within the window
+    pub duplicate_window: Duration,
+    pub max_duplicates: usize,
+    // Sessions that break the limits this often are disconnected
+    pub max_violations: usize,
+    pub violation_window: Duration,
+    // At most one typing notification per user in this interval
+    pub typing_interval: Duration,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            user_burst: 5,
+            user_per_sec: 1.0,
+            room_burst: 50,
+            room_per_sec: 20.0,
+            signal_burst: 10,
+            signal_per_sec: 2.0,
+            max_message_length: 4000,
+            duplicate_window: Duration::from_secs(30),
+            max_duplicates: 2,
+            max_violations: 5,
+            violation_window: Duration::from_secs(60),
+            typing_interval: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    per_sec: f64,
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: u32, per_sec: f64) -> Self {
+        TokenBucket {
+            capacity: capacity as f64,
+            per_sec,
+            tokens: capacity as f64,
+            updated_at: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.capacity);
+        self.updated_at = now;
+    }
+
+    fn has_token(&mut self, now: Instant) -> Result<(), ChatError> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            return Ok(());
+        }
+        let wait = (1.0 - self.tokens) / self.per_sec;
+        Err(ChatError::RateLimited(Duration::from_secs_f64(wait)))
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+#[derive(Debug)]
+struct UserLimits {
+    bucket: TokenBucket,
+    signals: TokenBucket,
+    recent: VecDeque<(String, Instant)>,
+    typing_at: Option<Instant>,
+}
+
+// Limits shared by every session in a room
+#[derive(Debug)]
+pub struct RoomLimits {
+    config: RateLimitConfig,
+    bucket: TokenBucket,
+    users: HashMap<String, UserLimits>,
+    pruned_at: Instant,
+}
+
+impl RoomLimits {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RoomLimits {
+            config,
+            bucket: TokenBucket::new(config.room_burst, config.room_per_sec),
+            users: HashMap::new(),
+            pruned_at: Instant::now(),
+        }
+    }
+
+    pub fn config(&self) -> RateLimitConfig {
+        self.config
+    }
+
+    pub fn check_length(&self, text: &str) -> Result<(), ChatError> {
+        if text.chars().count() > self.config.max_message_length {
+            Err(ChatError::MessageTooLong(self.config.max_message_length))?
+        }
+        Ok(())
+    }
+
+    // Takes a token from both buckets only when the message passes every check
+    pub fn check_message(&mut self, username: &str, text: &str) -> Result<(), ChatError> {
+        let now = Instant::now();
+        self.prune(now);
+        self.check_length(text)?;
+
+        let config = self.config;
+        let user = user_limits(&mut self.users, config, username);
+        while user
+            .recent
+            .front()
+            .is_some_and(|(_, at)| now.duration_since(*at) > config.duplicate_window)
+        {
+            user.recent.pop_front();
+        }
+        let duplicates = user.recent.iter().filter(|(t, _)| t == text).count();
+        // Files sent without a caption aren't duplicates of each other
+        if !text.is_empty() && duplicates >= config.max_duplicates {
+            Err(ChatError::DuplicateMessage)?
+        }
+        user.bucket.has_token(now)?;
+        self.bucket.has_token(now)?;
+
+        user.bucket.tokens -= 1.0;
+        self.bucket.tokens -= 1.0;
+        user.recent.push_back((text.to_string(), now));
+        Ok(())
+    }
+
+    // Edits, reactions, commands and any other broadcast cost as much as a message
+    pub fn check_event(&mut self, username: &str) -> Result<(), ChatError> {
+        let now = Instant::now();
+        self.prune(now);
+        let user = user_limits(&mut self.users, self.config, username);
+        user.bucket.has_token(now)?;
+        self.bucket.has_token(now)?;
+
+        user.bucket.tokens -= 1.0;
+        self.bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    // Extra typing notifications are dropped silently
+    pub fn check_typing(&mut self, username: &str) -> bool {
+        let now = Instant::now();
+        let interval = self.config.typing_interval;
+        let user = user_limits(&mut self.users, self.config, username);
+        if user
+            .typing_at
+            .is_some_and(|at| now.duration_since(at) < interval)
+        {
+            return false;
+        }
+        user.typing_at = Some(now);
+        self.check_signal(username)
+    }
+
+    // Clients send these on their own, so going over the limit isn't a violation,
+    // the extra ones just aren't broadcast
+    pub fn check_signal(&mut self, username: &str) -> bool {
+        let now = Instant::now();
+        self.prune(now);
+        let user = user_limits(&mut self.users, self.config, username);
+        if user.signals.has_token(now).is_err() {
+            return false;
+        }
+        user.signals.tokens -= 1.0;
+        true
+    }
+
+    fn prune(&mut self, now: Instant) {
+        if now.duration_since(self.pruned_at) < PRUNE_INTERVAL {
+            return;
+        }
+        let window = self.config.duplicate_window;
+        self.users.retain(|_, user| {
+            let is_recent = user
+                .recent
+                .back()
+                .is_some_and(|(_, at)| now.duration_since(*at) <= window);
+            is_recent || !user.bucket.is_full(now) || !user.signals.is_full(now)
+        });
+        self.pruned_at = now;
+    }
+}
+
+fn user_limits<'a>(
+    users: &'a mut HashMap<String, UserLimits>,
+    config: RateLimitConfig,
+    username: &str,
+) -> &'a mut UserLimits {
+    users
+        .entry(username.to_string())
+        .or_insert_with(|| UserLimits {
+            bucket: TokenBucket::new(config.user_burst, config.user_per_sec),
+            signals: TokenBucket::new(config.signal_burst, config.signal_per_sec),
+            recent: VecDeque::new(),
+            typing_at: None,
+        })
+}
+
+// Counts limit violations of a single session
+#[derive(Debug)]
+pub struct FloodGuard {
+    config: RateLimitConfig,
+    violations: VecDeque<Instant>,
+}
+
+impl FloodGuard {
+    pub fn new(config: RateLimitConfig) -> Self {
+        FloodGuard {
+            config,
+            violations: VecDeque::new(),
+        }
+    }
+
+    pub fn record(&mut self, error: &ChatError) -> Result<(), ChatError> {
+        if !error.is_limit_violation() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let window = self.config.violation_window;
+        self.violations
+            .retain(|at| now.duration_since(*at) <= window);
+        self.violations.push_back(now);
+        if self.violations.len() >= self.config.max_violations {
+            Err(ChatError::Flooding)?
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> RateLimitConfig {
+        RateLimitConfig {
+            user_burst: 2,
+            user_per_sec: 0.01,
+            room_burst: 3,
+            room_per_sec: 0.01,
+            signal_burst: 2,
+            signal_per_sec: 0.01,
+            max_message_length: 10,
+            max_duplicates: 1,
+            max_violations: 3,
+            ..RateLimitConfig::default()
+        }
+    }
+
+    #[test]
+    fn messages_spend_the_user_and_the_room_tokens() {
+        let mut limits = RoomLimits::new(config());
+        limits.check_message("alice", "one").unwrap();
+        limits.check_message("alice", "two").unwrap();
+        let limited = limits.check_message("alice", "three");
+        assert!(matches!(limited, Err(ChatError::RateLimited(_))));
+
+        limits.check_message("bob", "one").unwrap();
+        let limited = limits.check_message("bob", "two");
+        assert!(matches!(limited, Err(ChatError::RateLimited(_))));
+    }
+
+    #[test]
+    fn refused_messages_cost_nothing() {
+        let mut limits = RoomLimits::new(config());
+        let long = limits.check_message("alice", "far too long");
+        assert!(matches!(long, Err(ChatError::MessageTooLong(10))));
+        limits.check_message("alice", "hi").unwrap();
+        let duplicate = limits.check_message("alice", "hi");
+        assert!(matches!(duplicate, Err(ChatError::DuplicateMessage)));
+        // Captionless files aren't duplicates
+        limits.check_message("alice", "").unwrap();
+    }
+
+    #[test]
+    fn other_broadcasts_cost_as_much_as_messages() {
+        let mut limits = RoomLimits::new(config());
+        limits.check_event("alice").unwrap();
+        limits.check_message("alice", "hi").unwrap();
+        let limited = limits.check_event("alice");
+        assert!(matches!(limited, Err(ChatError::RateLimited(_))));
+        limits.check_event("bob").unwrap();
+        assert!(limits.check_event("bob").is_err());
+    }
+
+    #[test]
+    fn signals_have_a_bucket_of_their_own() {
+        let mut limits = RoomLimits::new(config());
+        assert!(limits.check_typing("alice"));
+        assert!(!limits.check_typing("alice"));
+        assert!(limits.check_signal("alice"));
+        assert!(!limits.check_signal("alice"));
+        limits.check_message("alice", "hi").unwrap();
+    }
+
+    #[test]
+    fn tokens_come_back_over_time() {
+        let config = RateLimitConfig {
+            user_burst: 1,
+            user_per_sec: 50.0,
+            ..config()
+        };
+        let mut limits = RoomLimits::new(config);
+        limits.check_event("alice").unwrap();
+        let Err(ChatError::RateLimited(wait)) = limits.check_event("alice") else {
+            panic!("the second event should be limited");
+        };
+        assert!(wait <= Duration::from_millis(20));
+        std::thread::sleep(Duration::from_millis(30));
+        limits.check_event("alice").unwrap();
+    }
+
+    #[test]
+    fn repeated_violations_are_flooding() {
+        let mut guard = FloodGuard::new(config());
+        let limited = ChatError::RateLimited(Duration::from_secs(1));
+        guard.record(&limited).unwrap();
+        guard.record(&ChatError::Muted).unwrap();
+        guard.record(&ChatError::DuplicateMessage).unwrap();
+        let flooding = guard.record(&limited);
+        assert!(matches!(flooding, Err(ChatError::Flooding)));
+    }
+}
@@ -0,0 +1,1088 @@
+use crate::attachment_controller;
+use crate::auth;
+use crate::chat::{ChatTarget, Chats, ThreadParams};
+use crate::chat_controller;
+use crate::chat_service;
+use crate::error;
+use crate::protocol::ProtocolQuery;
+use crate::search::SearchParams;
+use crate::sse_service;
+use crate::utils::Pagination;
+use std::sync::Arc;
+use warp::http::{header, Method};
+use warp::{Filter, Rejection, Reply};
+
+// Every route of the server, the tests drive them without binding a port
+pub fn routes(
+    chats: Chats,
+    jwt_secret: Arc<String>,
+    max_attachment_size: usize,
+    allowed_origins: &[String],
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    // Room for the multipart boundaries and headers around the file
+    let max_upload_length = max_attachment_size as u64 + 16 * 1024;
+    let chats = warp::any().map(move || chats.clone());
+
+    let list_chats = warp::path!("chat")
+        .and(warp::get())
+        .and(chats.clone())
+        .and_then(chat_controller::list_chats);
+
+    let create_chat = warp::path!("chat")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(auth::authenticate(jwt_secret.clone()))
+        .and(chats.clone())
+        .and_then(chat_controller::create_chat);
+
+    let archive_chat = warp::path!("chat" / String / "archive")
+        .and(warp::post())
+        .and(auth::authenticate(jwt_secret.clone()))
+        .and(chats.clone())
+        .and_then(chat_controller::archive_chat);
+
+    let delete_chat = warp::path!("chat" / String)
+        .and(warp::delete())
+        .and(auth::authenticate(jwt_secret.clone()))
+        .and(chats.clone())
+        .and_then(chat_controller::delete_chat);
+
+    let users = warp::path!("chat" / String / "users")
+        .and(warp::get())
+        .and(auth::authenticate(jwt_secret.clone()))
+        .and(chats.clone())
+        .and_then(chat_controller::get_users);
+
+    let search = warp::path!("chat" / String / "search")
+        .and(warp::get())
+        .and(warp::query::<SearchParams>())
+        .and(auth::authenticate(jwt_secret.clone()))
+        .and(chats.clone())
+        .and_then(chat_controller::search_chat);
+
+    let thread = warp::path!("chat" / String / "messages" / i64 / "thread")
+        .and(warp::get())
+        .and(warp::query::<ThreadParams>())
+        .and(auth::authenticate(jwt_secret.clone()))
+        .and(chats.clone())
+        .and_then(chat_controller::get_thread);
+
+    let upload_attachment = warp::path!("chat" / String / "attachments")
+        .and(warp::post())
+        .and(auth::authenticate(jwt_secret.clone()))
+        .and(warp::multipart::form().max_length(max_upload_length))
+        .and(chats.clone())
+        .and_then(attachment_controller::upload_attachment);
+
+    let attachment = warp::path!("attachments" / i64)
+        .and(warp::get())
+        .and(auth::authenticate(jwt_secret.clone()))
+        .and(chats.clone())
+        .and_then(attachment_controller::get_attachment);
+
+    let thumbnail = warp::path!("attachments" / i64 / "thumbnail")
+        .and(warp::get())
+        .and(auth::authenticate(jwt_secret.clone()))
+        .and(chats.clone())
+        .and_then(attachment_controller::get_thumbnail);
+
+    let queue_stats = warp::path!("stats" / "queues")
+        .and(warp::get())
+        .and(chats.clone())
+        .and_then(chat_controller::get_queue_stats);
+
+    let metrics = warp::path!("metrics")
+        .and(warp::get())
+        .and(chats.clone())
+        .and_then(chat_controller::get_metrics);
+
+    let inbox = warp::path!("inbox")
+        .and(warp::get())
+        .and(auth::authenticate(jwt_secret.clone()))
+        .and(chats.clone())
+        .and_then(chat_controller::get_inbox);
+
+    let chat = warp::path("chat")
+        .and(warp::ws())
+        .and(chats.clone())
+        .and(warp::path::param().map(ChatTarget::Room))
+        .and(warp::path::end())
+        .and(auth::authenticate(jwt_secret.clone()))
+        .and(warp::query::<Pagination>())
+        .and(warp::query::<ProtocolQuery>())
+        .map(chat_service::upgrade);
+
+    let chat_events = warp::path("chat")
+        .and(warp::get())
+        .and(chats.clone())
+        .and(warp::path::param().map(ChatTarget::Room))
+        .and(warp::path!("events"))
+        .and(auth::authenticate(jwt_secret.clone()))
+        .and(warp::query::<Pagination>())
+        .and(warp::query::<ProtocolQuery>())
+        .map(sse_service::connect);
+
+    let direct_chat_events = warp::path("dm")
+        .and(warp::get())
+        .and(chats.clone())
+        .and(warp::path::param().map(ChatTarget::Direct))
+        .and(warp::path!("events"))
+        .and(auth::authenticate(jwt_secret.clone()))
+        .and(warp::query::<Pagination>())
+        .and(warp::query::<ProtocolQuery>())
+        .map(sse_service::connect);
+
+    let post_event = warp::path!("sessions" / u64 / "events")
+        .and(warp::post())
+        .and(auth::authenticate(jwt_secret.clone()))
+        .and(warp::body::content_length_limit(1024 * 64))
+        .and(warp::body::bytes())
+        .and(chats.clone())
+        .and_then(chat_controller::post_event);
+
+    let direct_chat = warp::path("dm")
+        .and(warp::ws())
+        .and(chats)
+        .and(warp::path::param().map(ChatTarget::Direct))
+        .and(warp::path::end())
+        .and(auth::authenticate(jwt_secret))
+        .and(warp::query::<Pagination>())
+        .and(warp::query::<ProtocolQuery>())
+        .map(chat_service::upgrade);
+
+    list_chats
+        .or(create_chat)
+        .or(archive_chat)
+        .or(delete_chat)
+        .or(users)
+        .or(search)
+        .or(thread)
+        .or(upload_attachment)
+        .or(attachment)
+        .or(thumbnail)
+        .or(queue_stats)
+        .or(metrics)
+        .or(inbox)
+        .or(chat)
+        .or(direct_chat)
+        .or(chat_events)
+        .or(direct_chat_events)
+        .or(post_event)
+        .recover(error::handle_rejection)
+        .with(cors(allowed_origins))
+}
+
+// Browsers send the origin with socket upgrades too, so the same list guards them
+fn cors(origins: &[String]) -> warp::cors::Builder {
+    let cors = warp::cors()
+        .allow_methods([Method::GET, Method::POST, Method::DELETE])
+        .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE]);
+    match origins.is_empty() {
+        true => cors.allow_any_origin(),
+        false => cors.allow_origins(origins.iter().map(String::as_str)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attachment::AttachmentFiles;
+    use crate::auth::Claims;
+    use crate::broker::{Bus, MemoryBroker};
+    use crate::chat::Session;
+    use crate::command::Commands;
+    use crate::heartbeat::HeartbeatConfig;
+    use crate::outbox::{OutboxConfig, OverflowPolicy};
+    use crate::storage::{MemoryStore, Store};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::{json, Value};
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpStream;
+    use tokio::time::timeout;
+    use warp::test::WsClient;
+
+    const SECRET: &str = "secret";
+
+    fn chats() -> Chats {
+        chats_with(Default::default(), Default::default())
+    }
+
+    fn chats_with(outbox: OutboxConfig, heartbeat: HeartbeatConfig) -> Chats {
+        let store: Store = Arc::new(MemoryStore::default());
+        let bus: Bus = Arc::new(MemoryBroker::default());
+        Chats::new(
+            store,
+            bus,
+            outbox,
+            heartbeat,
+            Default::default(),
+            AttachmentFiles::new(Default::default()),
+            Commands::default(),
+        )
+    }
+
+    fn server(
+        chats: &Chats,
+    ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + 'static {
+        routes(chats.clone(), Arc::new(SECRET.to_string()), 1024, &[])
+    }
+
+    fn token(username: &str) -> String {
+        let now = chrono::Utc::now().timestamp();
+        let claims = Claims {
+            iss: username.to_string(),
+            sub: username.to_string(),
+            iat: now,
+            exp: now + 3600,
+        };
+        let key = EncodingKey::from_secret(SECRET.as_bytes());
+        encode(&Header::default(), &claims, &key).unwrap()
+    }
+
+    async fn create_room(chats: &Chats, name: &str) {
+        create_room_as(chats, name, "admin").await
+    }
+
+    async fn create_room_as(chats: &Chats, name: &str, creator: &str) {
+        let response = warp::test::request()
+            .method("POST")
+            .path("/chat")
+            .header("authorization", format!("Bearer {}", token(creator)))
+            .json(&json!({ "name": name }))
+            .reply(&server(chats))
+            .await;
+        assert_eq!(response.status(), 201);
+    }
+
+    async fn join(chats: &Chats, room: &str, username: &str) -> WsClient {
+        connect(chats, &format!("/chat/{room}"), username).await
+    }
+
+    async fn connect(chats: &Chats, path: &str, username: &str) -> WsClient {
+        warp::test::ws()
+            .path(&format!("{path}?token={}", token(username)))
+            .handshake(server(chats))
+            .await
+            .expect("handshake failed")
+    }
+
+    async fn get_json(chats: &Chats, path: &str, username: &str) -> Value {
+        let response = warp::test::request()
+            .path(path)
+            .header("authorization", format!("Bearer {}", token(username)))
+            .reply(&server(chats))
+            .await;
+        assert_eq!(response.status(), 200);
+        serde_json::from_slice(response.body()).unwrap()
+    }
+
+    async fn moderate(client: &mut WsClient, action: &str, username: &str) {
+        let event = json!({ "type": "moderate", "action": action, "username": username });
+        send(client, event).await;
+    }
+
+    // Skips the events the scenario doesn't look at, pings included
+    async fn expect(client: &mut WsClient, event_type: &str) -> Value {
+        timeout(Duration::from_secs(5), async {
+            loop {
+                let message = client.recv().await.expect("socket closed");
+                let Ok(text) = message.to_str() else {
+                    continue;
+                };
+                let event: Value = serde_json::from_str(text).unwrap();
+                if event["type"] == event_type {
+                    return event;
+                }
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("no {event_type} event in time"))
+    }
+
+    async fn send(client: &mut WsClient, event: Value) {
+        client.send_text(event.to_string()).await;
+    }
+
+    async fn say(client: &mut WsClient, text: &str) -> i64 {
+        send(client, json!({ "type": "message", "text": text })).await;
+        expect(client, "ack").await["id"].as_i64().unwrap()
+    }
+
+    async fn assert_silent(client: &mut WsClient, event_type: &str) {
+        let event = timeout(Duration::from_millis(200), expect(client, event_type)).await;
+        assert!(event.is_err(), "unexpected {event_type} event");
+    }
+
+    #[tokio::test]
+    async fn joining_sends_the_welcome_and_announces_the_user() {
+        let chats = chats();
+        create_room(&chats, "room").await;
+        let mut alice = join(&chats, "room", "alice").await;
+        let welcome = expect(&mut alice, "welcome").await;
+        assert_eq!(welcome["chat"], "room");
+        assert_eq!(welcome["username"], "alice");
+
+        let mut bob = join(&chats, "room", "bob").await;
+        expect(&mut bob, "welcome").await;
+        assert_eq!(
+            expect(&mut bob, "roster").await["users"],
+            json!(["alice", "bob"])
+        );
+        assert_eq!(expect(&mut alice, "join").await["username"], "bob");
+        assert_eq!(
+            get_json(&chats, "/chat/room/users", "alice").await,
+            json!(["alice", "bob"])
+        );
+    }
+
+    #[tokio::test]
+    async fn unsupported_versions_and_missing_tokens_are_refused() {
+        let chats = chats();
+        let path = format!("/chat/room?version=99&token={}", token("alice"));
+        let mut client = warp::test::ws()
+            .path(&path)
+            .handshake(server(&chats))
+            .await
+            .unwrap();
+        let error = expect(&mut client, "error").await;
+        assert_eq!(error["code"], "unsupported_version");
+        assert_eq!(error["fatal"], true);
+
+        let handshake = warp::test::ws()
+            .path("/chat/room")
+            .handshake(server(&chats))
+            .await;
+        assert!(handshake.is_err());
+        let response = warp::test::request()
+            .path("/chat/room/users")
+            .reply(&server(&chats))
+            .await;
+        assert_eq!(response.status(), 401);
+    }
+
+    #[tokio::test]
+    async fn messages_are_broadcast_to_the_room() {
+        let chats = chats();
+        create_room(&chats, "room").await;
+        let mut alice = join(&chats, "room", "alice").await;
+        let mut bob = join(&chats, "room", "bob").await;
+        expect(&mut alice, "join").await;
+
+        send(
+            &mut alice,
+            json!({ "type": "message", "text": "hello", "client_id": "1" }),
+        )
+        .await;
+        let ack = expect(&mut alice, "ack").await;
+        assert_eq!(ack["client_id"], "1");
+        let message = expect(&mut bob, "message").await["message"].clone();
+        assert_eq!(message["id"], ack["id"]);
+        assert_eq!(message["username"], "alice");
+        assert_eq!(message["text"], "hello");
+        // The sender has the ack, its own message isn't echoed
+        assert_silent(&mut alice, "message").await;
+    }
+
+    #[tokio::test]
+    async fn history_is_sent_on_join_and_on_request() {
+        let chats = chats();
+        create_room(&chats, "room").await;
+        let mut alice = join(&chats, "room", "alice").await;
+        let mut ids = vec![];
+        for text in ["one", "two", "three"] {
+            ids.push(say(&mut alice, text).await);
+        }
+
+        let mut bob = join(&chats, "room", "bob").await;
+        let history = expect(&mut bob, "history").await;
+        let texts: Vec<&str> = history["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| message["text"].as_str().unwrap())
+            .collect();
+        // Newest first
+        assert_eq!(texts, ["three", "two", "one"]);
+        assert_eq!(history["has_more"], false);
+
+        send(
+            &mut bob,
+            json!({ "type": "history", "before": ids[2], "take": 1 }),
+        )
+        .await;
+        let page = expect(&mut bob, "history").await;
+        assert_eq!(page["messages"].as_array().unwrap().len(), 1);
+        assert_eq!(page["messages"][0]["id"], ids[1]);
+        assert_eq!(page["has_more"], true);
+
+        // A reconnecting client only gets what it has missed
+        let path = format!("/chat/room?last_seen={}&token={}", ids[1], token("carol"));
+        let mut carol = warp::test::ws()
+            .path(&path)
+            .handshake(server(&chats))
+            .await
+            .unwrap();
+        let missed = expect(&mut carol, "history").await;
+        assert_eq!(missed["messages"].as_array().unwrap().len(), 1);
+        assert_eq!(missed["messages"][0]["id"], ids[2]);
+    }
+
+    #[tokio::test]
+    async fn resuming_pages_through_every_missed_message() {
+        let chats = chats();
+        create_room(&chats, "room").await;
+        let mut alice = join(&chats, "room", "alice").await;
+        let mut ids = vec![];
+        for text in ["one", "two", "three", "four", "five"] {
+            ids.push(say(&mut alice, text).await);
+        }
+
+        let path = format!(
+            "/chat/room?last_seen={}&take=2&token={}",
+            ids[0],
+            token("bob")
+        );
+        let mut bob = warp::test::ws()
+            .path(&path)
+            .handshake(server(&chats))
+            .await
+            .unwrap();
+        // The oldest missed messages come first, still newest first within the page
+        let missed = expect(&mut bob, "history").await;
+        assert_eq!(missed["messages"][0]["id"], ids[2]);
+        assert_eq!(missed["messages"][1]["id"], ids[1]);
+        assert_eq!(missed["has_more"], true);
+
+        let next = json!({ "type": "history", "after": ids[2], "take": 2 });
+        send(&mut bob, next).await;
+        let missed = expect(&mut bob, "history").await;
+        assert_eq!(missed["messages"][0]["id"], ids[4]);
+        assert_eq!(missed["messages"][1]["id"], ids[3]);
+        assert_eq!(missed["has_more"], false);
+    }
+
+    #[tokio::test]
+    async fn sessions_of_the_same_username_are_one_user() {
+        let chats = chats();
+        create_room(&chats, "room").await;
+        let mut bob = join(&chats, "room", "bob").await;
+        let mut first = join(&chats, "room", "alice").await;
+        expect(&mut bob, "join").await;
+        let mut second = join(&chats, "room", "alice").await;
+        assert_eq!(
+            expect(&mut second, "roster").await["users"],
+            json!(["alice", "bob"])
+        );
+        assert_silent(&mut bob, "join").await;
+
+        say(&mut bob, "hi alice").await;
+        assert_eq!(
+            expect(&mut first, "message").await["message"]["text"],
+            "hi alice"
+        );
+        assert_eq!(
+            expect(&mut second, "message").await["message"]["text"],
+            "hi alice"
+        );
+
+        // Other sessions of the sender get its messages too
+        say(&mut first, "hi bob").await;
+        assert_eq!(
+            expect(&mut second, "message").await["message"]["text"],
+            "hi bob"
+        );
+
+        drop(first);
+        assert_silent(&mut bob, "leave").await;
+        assert_eq!(
+            get_json(&chats, "/chat/room/users", "alice").await,
+            json!(["alice", "bob"])
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_json_closes_the_connection() {
+        let chats = chats();
+        create_room(&chats, "room").await;
+        let mut alice = join(&chats, "room", "alice").await;
+        expect(&mut alice, "history").await;
+
+        alice.send_text("{not json").await;
+        let error = expect(&mut alice, "error").await;
+        assert_eq!(error["code"], "invalid_message_body");
+        assert_eq!(error["fatal"], true);
+        // The test client swallows the close frame, only the end of the stream is seen
+        timeout(Duration::from_secs(5), alice.recv_closed())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn disconnected_users_leave_the_room() {
+        let chats = chats();
+        create_room(&chats, "room").await;
+        let mut alice = join(&chats, "room", "alice").await;
+        let bob = join(&chats, "room", "bob").await;
+        expect(&mut alice, "join").await;
+
+        drop(bob);
+        assert_eq!(expect(&mut alice, "leave").await["username"], "bob");
+        assert_eq!(
+            get_json(&chats, "/chat/room/users", "alice").await,
+            json!(["alice"])
+        );
+    }
+
+    #[tokio::test]
+    async fn events_are_only_posted_to_open_streams() {
+        let chats = chats();
+        let mut alice = join(&chats, "room", "alice").await;
+        let session = expect(&mut alice, "welcome").await["session"].clone();
+
+        // Socket sessions take their events over the socket
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!("/sessions/{session}/events"))
+            .header("authorization", format!("Bearer {}", token("alice")))
+            .body(json!({ "type": "typing" }).to_string())
+            .reply(&server(&chats))
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    // Reads the raw response, every event is a `data:` line of its own
+    async fn expect_sse(stream: &mut BufReader<TcpStream>, event_type: &str) -> Value {
+        timeout(Duration::from_secs(5), async {
+            loop {
+                let mut line = String::new();
+                let read = stream.read_line(&mut line).await.unwrap();
+                assert!(read > 0, "stream closed");
+                let Some(data) = line.trim_end().strip_prefix("data:") else {
+                    continue;
+                };
+                let event: Value = serde_json::from_str(data).unwrap();
+                if event["type"] == event_type {
+                    return event;
+                }
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("no {event_type} event in time"))
+    }
+
+    #[tokio::test]
+    async fn posted_events_are_broadcast_to_the_stream() {
+        let chats = chats();
+        create_room(&chats, "room").await;
+        let (address, serving) = warp::serve(server(&chats)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(serving);
+        let mut bob = join(&chats, "room", "bob").await;
+        expect(&mut bob, "welcome").await;
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let request = format!(
+            "GET /chat/room/events?token={} HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            token("alice")
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut stream = BufReader::new(stream);
+        let welcome = expect_sse(&mut stream, "welcome").await;
+        assert_eq!(welcome["username"], "alice");
+        assert_eq!(expect(&mut bob, "join").await["username"], "alice");
+
+        let event = json!({ "type": "message", "text": "over sse", "client_id": "1" });
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!("/sessions/{}/events", welcome["session"]))
+            .header("authorization", format!("Bearer {}", token("alice")))
+            .body(event.to_string())
+            .reply(&server(&chats))
+            .await;
+        assert_eq!(response.status(), 202);
+        let ack = expect_sse(&mut stream, "ack").await;
+        assert_eq!(ack["client_id"], "1");
+        let message = expect(&mut bob, "message").await["message"].clone();
+        assert_eq!(message["id"], ack["id"]);
+        assert_eq!(message["text"], "over sse");
+
+        // Broadcasts of the others come down the stream
+        say(&mut bob, "over the socket").await;
+        let message = expect_sse(&mut stream, "message").await["message"].clone();
+        assert_eq!(message["text"], "over the socket");
+        assert_eq!(message["username"], "bob");
+    }
+
+    #[tokio::test]
+    async fn metrics_count_users_messages_and_errors() {
+        let chats = chats();
+        let mut alice = join(&chats, "metrics", "alice").await;
+        let _bob = join(&chats, "metrics", "bob").await;
+        expect(&mut alice, "join").await;
+        say(&mut alice, "hello").await;
+        send(&mut alice, json!({ "type": "delete", "id": 1000 })).await;
+        assert_eq!(
+            expect(&mut alice, "error").await["code"],
+            "message_not_found"
+        );
+
+        let response = warp::test::request()
+            .path("/metrics")
+            .reply(&server(&chats))
+            .await;
+        assert_eq!(response.status(), 200);
+        let text = String::from_utf8(response.body().to_vec()).unwrap();
+        assert!(text.contains("chat_connected_users{room=\"metrics\"} 2"));
+        assert!(text.contains("chat_messages_total "));
+        assert!(text.contains("chat_errors_total{error=\"message_not_found\"}"));
+    }
+
+    #[tokio::test]
+    async fn replies_are_kept_in_threads() {
+        let chats = chats();
+        let mut alice = join(&chats, "threads", "alice").await;
+        let mut bob = join(&chats, "threads", "bob").await;
+        expect(&mut alice, "join").await;
+        let root = say(&mut alice, "lunch?").await;
+
+        let reply = |text: &str, thread_only: bool| json!({ "type": "message", "text": text, "parent_id": root, "thread_only": thread_only });
+        send(&mut bob, reply("sure", false)).await;
+        let message = expect(&mut alice, "message").await["message"].clone();
+        assert_eq!(message["parent_id"], root);
+        let replies = expect(&mut alice, "replies").await;
+        assert_eq!(
+            replies,
+            json!({ "type": "replies", "id": root, "reply_count": 1 })
+        );
+
+        send(&mut bob, reply("at noon", true)).await;
+        let answer = expect(&mut bob, "ack").await["id"].clone();
+        assert_eq!(
+            expect(&mut alice, "reply").await["message"]["text"],
+            "at noon"
+        );
+        assert_eq!(expect(&mut alice, "replies").await["reply_count"], 2);
+        assert_silent(&mut alice, "message").await;
+
+        // Threads are one level deep
+        let nested = json!({ "type": "message", "text": "ok", "parent_id": answer });
+        send(&mut alice, nested).await;
+        assert_eq!(expect(&mut alice, "error").await["code"], "invalid_thread");
+
+        send(&mut alice, json!({ "type": "thread", "id": root })).await;
+        let thread = expect(&mut alice, "thread").await;
+        assert_eq!(thread["message"]["reply_count"], 2);
+        assert_eq!(thread["replies"][0]["text"], "at noon");
+        assert_eq!(thread["replies"][1]["text"], "sure");
+
+        send(&mut alice, json!({ "type": "history" })).await;
+        let history = expect(&mut alice, "history").await;
+        let texts: Vec<&str> = history["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| message["text"].as_str().unwrap())
+            .collect();
+        assert_eq!(texts, ["sure", "lunch?"]);
+
+        let response = warp::test::request()
+            .path(&format!("/chat/threads/messages/{root}/thread?take=1"))
+            .header("authorization", format!("Bearer {}", token("carol")))
+            .reply(&server(&chats))
+            .await;
+        assert_eq!(response.status(), 200);
+        let thread: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(thread["replies"].as_array().unwrap().len(), 1);
+
+        // The replies go with the message that started the thread
+        send(&mut alice, json!({ "type": "delete", "id": root })).await;
+        assert_eq!(expect(&mut bob, "deleted").await["id"], root);
+        send(&mut alice, json!({ "type": "history" })).await;
+        assert_eq!(expect(&mut alice, "history").await["messages"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn created_rooms_are_owned_by_their_creator() {
+        let chats = chats();
+        create_room_as(&chats, "owned", "alice").await;
+        let mut alice = join(&chats, "owned", "alice").await;
+        assert_eq!(expect(&mut alice, "welcome").await["role"], "owner");
+        let mut bob = join(&chats, "owned", "bob").await;
+        assert_eq!(expect(&mut bob, "welcome").await["role"], "member");
+        let mut carol = join(&chats, "owned", "carol").await;
+        expect(&mut carol, "welcome").await;
+
+        let promote = json!({ "type": "moderate", "action": "promote", "username": "bob" });
+        send(&mut alice, promote).await;
+        assert_eq!(expect(&mut bob, "system").await["action"], "promote");
+        expect(&mut alice, "system").await;
+
+        let ban = json!({ "type": "moderate", "action": "ban", "username": "carol" });
+        send(&mut bob, ban).await;
+        assert_eq!(expect(&mut alice, "system").await["action"], "ban");
+        assert_eq!(expect(&mut carol, "error").await["code"], "banned");
+    }
+
+    #[tokio::test]
+    async fn rooms_are_managed_by_their_owner() {
+        let chats = chats();
+        let manage = |method: &str, path: &str, username: Option<&str>| {
+            let request = warp::test::request().method(method).path(path);
+            match username {
+                Some(username) => {
+                    request.header("authorization", format!("Bearer {}", token(username)))
+                }
+                None => request,
+            }
+        };
+        let anonymous = manage("POST", "/chat", None)
+            .json(&json!({ "name": "owned" }))
+            .reply(&server(&chats))
+            .await;
+        assert_eq!(anonymous.status(), 401);
+        create_room_as(&chats, "owned", "alice").await;
+
+        for (method, path) in [("POST", "/chat/owned/archive"), ("DELETE", "/chat/owned")] {
+            let response = manage(method, path, None).reply(&server(&chats)).await;
+            assert_eq!(response.status(), 401);
+            let response = manage(method, path, Some("bob"))
+                .reply(&server(&chats))
+                .await;
+            assert_eq!(response.status(), 403);
+        }
+
+        let response = manage("POST", "/chat/owned/archive", Some("alice"))
+            .reply(&server(&chats))
+            .await;
+        assert_eq!(response.status(), 200);
+        let info: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(info["archived"], true);
+        let response = manage("DELETE", "/chat/owned", Some("alice"))
+            .reply(&server(&chats))
+            .await;
+        assert_eq!(response.status(), 204);
+    }
+
+    #[tokio::test]
+    async fn messages_can_be_edited_reacted_to_and_deleted() {
+        let chats = chats();
+        create_room(&chats, "room").await;
+        let mut alice = join(&chats, "room", "alice").await;
+        let mut bob = join(&chats, "room", "bob").await;
+        expect(&mut alice, "join").await;
+        let id = say(&mut alice, "helo").await;
+
+        send(
+            &mut alice,
+            json!({ "type": "edit", "id": id, "text": "hello" }),
+        )
+        .await;
+        let edited = expect(&mut bob, "edited").await["message"].clone();
+        assert_eq!(
+            (edited["id"].as_i64(), &edited["text"]),
+            (Some(id), &json!("hello"))
+        );
+        assert!(edited["edited_at"].is_string());
+
+        // Only the author changes a message
+        send(
+            &mut bob,
+            json!({ "type": "edit", "id": id, "text": "mine" }),
+        )
+        .await;
+        assert_eq!(expect(&mut bob, "error").await["code"], "message_forbidden");
+        send(&mut bob, json!({ "type": "delete", "id": id })).await;
+        assert_eq!(expect(&mut bob, "error").await["code"], "message_forbidden");
+
+        send(
+            &mut bob,
+            json!({ "type": "react", "id": id, "emoji": "👍" }),
+        )
+        .await;
+        let reactions = expect(&mut alice, "reactions").await;
+        assert_eq!(
+            reactions["reactions"],
+            json!([{ "emoji": "👍", "users": ["bob"] }])
+        );
+        send(
+            &mut bob,
+            json!({ "type": "unreact", "id": id, "emoji": "👍" }),
+        )
+        .await;
+        assert_eq!(
+            expect(&mut alice, "reactions").await["reactions"],
+            json!([])
+        );
+        send(
+            &mut bob,
+            json!({ "type": "react", "id": id, "emoji": "no way" }),
+        )
+        .await;
+        assert_eq!(expect(&mut bob, "error").await["code"], "invalid_reaction");
+
+        send(&mut alice, json!({ "type": "delete", "id": id })).await;
+        assert_eq!(expect(&mut bob, "deleted").await["id"], id);
+        send(&mut bob, json!({ "type": "history" })).await;
+        assert_eq!(expect(&mut bob, "history").await["messages"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn direct_messages_show_up_in_the_inbox() {
+        let chats = chats();
+        let mut alice = connect(&chats, "/dm/bob", "alice").await;
+        let welcome = expect(&mut alice, "welcome").await;
+        assert_eq!(welcome["chat"], "dm:alice:bob");
+        say(&mut alice, "psst").await;
+        let id = say(&mut alice, "are you there?").await;
+
+        let inbox = get_json(&chats, "/inbox", "bob").await;
+        assert_eq!(inbox[0]["chat"], "dm:alice:bob");
+        assert_eq!(inbox[0]["with"], "alice");
+        assert_eq!(inbox[0]["unread"], 2);
+        assert_eq!(inbox[0]["last_message"]["text"], "are you there?");
+        // Direct chats aren't listed with the rooms
+        let response = warp::test::request()
+            .path("/chat")
+            .reply(&server(&chats))
+            .await;
+        assert_eq!(response.body().as_ref(), b"[]");
+
+        // Joining counts everything as delivered
+        let mut bob = connect(&chats, "/dm/alice", "bob").await;
+        let history = expect(&mut bob, "history").await;
+        assert_eq!(history["messages"][0]["id"], id);
+        // Alice has already read her own messages
+        let read = loop {
+            let read = expect(&mut alice, "read").await;
+            if read["username"] == "bob" {
+                break read;
+            }
+        };
+        assert_eq!(read["last_read_id"], id);
+        assert_eq!(get_json(&chats, "/inbox", "bob").await[0]["unread"], 0);
+
+        // Nobody else gets into the conversation
+        let carol = warp::test::ws()
+            .path(&format!("/chat/dm:alice:bob?token={}", token("carol")))
+            .handshake(server(&chats))
+            .await;
+        let mut carol = carol.unwrap();
+        assert_eq!(
+            expect(&mut carol, "error").await["code"],
+            "invalid_chat_name"
+        );
+        let response = warp::test::request()
+            .path("/chat/dm:alice:bob/users")
+            .header("authorization", format!("Bearer {}", token("carol")))
+            .reply(&server(&chats))
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn moderators_mute_kick_and_ban() {
+        let chats = chats();
+        create_room_as(&chats, "room", "alice").await;
+        let mut alice = join(&chats, "room", "alice").await;
+        let mut bob = join(&chats, "room", "bob").await;
+        expect(&mut bob, "welcome").await;
+
+        moderate(&mut bob, "kick", "alice").await;
+        assert_eq!(
+            expect(&mut bob, "error").await["code"],
+            "moderation_forbidden"
+        );
+
+        moderate(&mut alice, "mute", "bob").await;
+        assert_eq!(expect(&mut bob, "system").await["action"], "mute");
+        send(&mut bob, json!({ "type": "message", "text": "hey" })).await;
+        let error = expect(&mut bob, "error").await;
+        assert_eq!(
+            (&error["code"], &error["fatal"]),
+            (&json!("muted"), &json!(false))
+        );
+        moderate(&mut alice, "unmute", "bob").await;
+        expect(&mut bob, "system").await;
+        say(&mut bob, "thanks").await;
+
+        moderate(&mut alice, "kick", "bob").await;
+        assert_eq!(expect(&mut bob, "error").await["code"], "kicked");
+        assert!(bob.recv_closed().await.is_ok());
+        assert_eq!(expect(&mut alice, "leave").await["username"], "bob");
+
+        // Kicked users can come back, banned ones can't
+        let mut bob = join(&chats, "room", "bob").await;
+        expect(&mut bob, "welcome").await;
+        moderate(&mut alice, "ban", "bob").await;
+        assert_eq!(expect(&mut bob, "error").await["code"], "banned");
+        assert!(bob.recv_closed().await.is_ok());
+        let mut bob = join(&chats, "room", "bob").await;
+        let error = expect(&mut bob, "error").await;
+        assert_eq!(
+            (&error["code"], &error["fatal"]),
+            (&json!("banned"), &json!(true))
+        );
+        let response = warp::test::request()
+            .path("/chat/room/users")
+            .header("authorization", format!("Bearer {}", token("bob")))
+            .reply(&server(&chats))
+            .await;
+        assert_eq!(response.status(), 403);
+    }
+
+    #[tokio::test]
+    async fn flooding_closes_the_connection() {
+        let chats = chats();
+        create_room(&chats, "room").await;
+        let mut alice = join(&chats, "room", "alice").await;
+        for i in 0..10 {
+            send(
+                &mut alice,
+                json!({ "type": "message", "text": format!("spam {i}") }),
+            )
+            .await;
+        }
+        for _ in 0..5 {
+            expect(&mut alice, "ack").await;
+        }
+        for _ in 0..5 {
+            let error = expect(&mut alice, "error").await;
+            assert_eq!(
+                (&error["code"], &error["fatal"]),
+                (&json!("rate_limited"), &json!(false))
+            );
+        }
+        let error = expect(&mut alice, "error").await;
+        assert_eq!(
+            (&error["code"], &error["fatal"]),
+            (&json!("flooding"), &json!(true))
+        );
+        assert!(alice.recv_closed().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn typing_and_read_receipts_reach_the_others() {
+        let chats = chats();
+        create_room(&chats, "room").await;
+        let mut alice = join(&chats, "room", "alice").await;
+        let mut bob = join(&chats, "room", "bob").await;
+        expect(&mut alice, "join").await;
+
+        send(&mut alice, json!({ "type": "typing" })).await;
+        assert_eq!(expect(&mut bob, "typing").await["username"], "alice");
+        assert_silent(&mut alice, "typing").await;
+        // Extra notifications within the interval are dropped
+        send(&mut alice, json!({ "type": "typing" })).await;
+        assert_silent(&mut bob, "typing").await;
+
+        let id = say(&mut alice, "read me").await;
+        send(&mut bob, json!({ "type": "read", "id": id })).await;
+        let read = expect(&mut alice, "read").await;
+        assert_eq!(
+            (&read["username"], read["last_read_id"].as_i64()),
+            (&json!("bob"), Some(id))
+        );
+
+        let mut carol = join(&chats, "room", "carol").await;
+        let history = expect(&mut carol, "history").await;
+        assert_eq!(
+            history["read"],
+            json!([{ "username": "bob", "last_read_id": id }])
+        );
+    }
+
+    #[tokio::test]
+    async fn idle_connections_are_closed() {
+        let heartbeat = HeartbeatConfig {
+            ping_interval: Duration::from_millis(50),
+            pong_timeout: Duration::from_millis(100),
+            idle_timeout: Duration::from_millis(400),
+        };
+        let chats = chats_with(Default::default(), heartbeat);
+        create_room(&chats, "room").await;
+        let mut alice = join(&chats, "room", "alice").await;
+        let ping = timeout(Duration::from_secs(5), async {
+            loop {
+                if alice.recv().await.unwrap().is_ping() {
+                    break;
+                }
+            }
+        });
+        assert!(ping.await.is_ok(), "no ping in time");
+
+        // Pongs keep the connection, only events count as activity
+        let error = expect(&mut alice, "error").await;
+        assert_eq!(
+            (&error["code"], &error["fatal"]),
+            (&json!("idle_timeout"), &json!(true))
+        );
+        assert!(alice.recv_closed().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn overflowing_outboxes_follow_their_policy() {
+        for policy in [OverflowPolicy::DropOldest, OverflowPolicy::Disconnect] {
+            let outbox = OutboxConfig {
+                capacity: 4,
+                policy,
+            };
+            let chats = chats_with(outbox, Default::default());
+            create_room(&chats, "room").await;
+
+            // Nothing reads bob's outbox, as if his socket had stalled
+            let bob = chats.outbox();
+            let session = tokio::spawn({
+                let (chats, bob) = (chats.clone(), bob.clone());
+                async move {
+                    let page = Pagination {
+                        take: None,
+                        offset: None,
+                        last_seen: None,
+                    };
+                    let target = ChatTarget::Room("room".to_string());
+                    let session = Session::new("bob".to_string());
+                    let protocol = ProtocolQuery { version: None };
+                    let incoming = futures_util::stream::pending();
+                    chats
+                        .join(target, session, page, protocol, incoming, bob)
+                        .await
+                }
+            });
+            let mut alice = join(&chats, "room", "alice").await;
+            let roster = expect(&mut alice, "roster").await;
+            assert_eq!(roster["users"], json!(["alice", "bob"]));
+            // Welcome, roster, history and alice's join already fill it up
+            say(&mut alice, "hi").await;
+
+            let stats = get_json(&chats, "/stats/queues", "alice").await;
+            match policy {
+                OverflowPolicy::DropOldest => {
+                    assert_eq!(
+                        (&stats["dropped"], &stats["slow_consumers"]),
+                        (&json!(1), &json!(0))
+                    );
+                    let mut events = vec![];
+                    while let Ok(Some(message)) =
+                        timeout(Duration::from_millis(100), bob.recv()).await
+                    {
+                        let event: Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+                        events.push(event["type"].as_str().unwrap().to_string());
+                    }
+                    assert_eq!(events, ["roster", "history", "join", "message"]);
+                    session.abort();
+                }
+                _ => {
+                    assert_eq!(
+                        (&stats["dropped"], &stats["slow_consumers"]),
+                        (&json!(4), &json!(1))
+                    );
+                    assert!(timeout(Duration::from_secs(5), session).await.is_ok());
+                    assert_eq!(expect(&mut alice, "leave").await["username"], "bob");
+                    assert_eq!(bob.recv().await, None);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use crate::chat::ChatMessage;
+use crate::error::ChatError;
+use crate::storage::SearchQuery;
+use crate::utils::HistoryConfig;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+const MAX_TERMS: usize = 16;
+const SNIPPET_WORDS: usize = 16;
+// Words kept in front of the first match when the message is cut
+const SNIPPET_LEAD: usize = 4;
+const ELLIPSIS: &str = "…";
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SearchParams {
+    pub query: String,
+    pub author: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub take: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+impl SearchParams {
+    pub fn to_query(&self, history: HistoryConfig) -> Result<SearchQuery, ChatError> {
+        let terms = terms(&self.query);
+        if terms.is_empty() || terms.len() > MAX_TERMS {
+            Err(ChatError::InvalidSearch(MAX_TERMS))?
+        }
+        Ok(SearchQuery {
+            terms,
+            author: self.author.clone(),
+            from: self.from,
+            to: self.to,
+            offset: self.offset.unwrap_or(0) as usize,
+            take: history.limit_take(self.take),
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SnippetPart {
+    pub text: String,
+    pub highlight: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SearchResult {
+    pub message: ChatMessage,
+    pub snippet: Vec<SnippetPart>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SearchResults {
+    pub results: Vec<SearchResult>,
+    pub has_more: bool,
+}
+
+impl SearchResults {
+    // Expects one message more than a page to tell whether there is another one
+    pub fn new(mut messages: Vec<ChatMessage>, query: &SearchQuery) -> Self {
+        let has_more = messages.len() > query.take;
+        messages.truncate(query.take);
+        let results = messages
+            .into_iter()
+            .map(|message| SearchResult {
+                snippet: snippet(&message.text, &query.terms),
+                message,
+            })
+            .collect();
+        SearchResults { results, has_more }
+    }
+}
+
+// Words are runs of letters and digits, compared case-insensitively
+fn words(text: &str) -> Vec<Range<usize>> {
+    let mut words = vec![];
+    let mut start = None;
+    for (at, c) in text.char_indices() {
+        match (c.is_alphanumeric(), start) {
+            (true, None) => start = Some(at),
+            (false, Some(from)) => {
+                words.push(from..at);
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(from) = start {
+        words.push(from..text.len());
+    }
+    words
+}
+
+pub fn terms(query: &str) -> Vec<String> {
+    let mut terms: Vec<String> = vec![];
+    for word in words(query) {
+        let term = query[word].to_lowercase();
+        if !terms.contains(&term) {
+            terms.push(term);
+        }
+    }
+    terms
+}
+
+fn is_match(word: &str, terms: &[String]) -> bool {
+    let word = word.to_lowercase();
+    terms.iter().any(|term| word.starts_with(term.as_str()))
+}
+
+// Every term has to be the prefix of some word of the text
+pub fn matches(text: &str, terms: &[String]) -> bool {
+    let words: Vec<String> = words(text)
+        .into_iter()
+        .map(|word| text[word].to_lowercase())
+        .collect();
+    terms
+        .iter()
+        .all(|term| words.iter().any(|word| word.starts_with(term.as_str())))
+}
+
+fn push_text(parts: &mut Vec<SnippetPart>, text: &str) {
+    match parts.last_mut() {
+        Some(part) if !part.highlight => part.text.push_str(text),
+        _ if text.is_empty() => {}
+        _ => parts.push(SnippetPart {
+            text: text.to_string(),
+            highlight: false,
+        }),
+    }
+}
+
+// A piece of the text around the first match with every match highlighted
+pub fn snippet(text: &str, terms: &[String]) -> Vec<SnippetPart> {
+    let words = words(text);
+    let hits: Vec<bool> = words
+        .iter()
+        .map(|word| is_match(&text[word.clone()], terms))
+        .collect();
+    let first_hit = hits.iter().position(|hit| *hit).unwrap_or(0);
+    let start = first_hit
+        .saturating_sub(SNIPPET_LEAD)
+        .min(words.len().saturating_sub(SNIPPET_WORDS));
+    let end = words.len().min(start + SNIPPET_WORDS);
+
+    let mut parts = vec![];
+    let mut at = match start {
+        0 => 0,
+        _ => {
+            push_text(&mut parts, ELLIPSIS);
+            words[start].start
+        }
+    };
+    for (word, _) in words[start..end]
+        .iter()
+        .zip(&hits[start..end])
+        .filter(|(_, hit)| **hit)
+    {
+        push_text(&mut parts, &text[at..word.start]);
+        parts.push(SnippetPart {
+            text: text[word.clone()].to_string(),
+            highlight: true,
+        });
+        at = word.end;
+    }
+    if end < words.len() {
+        push_text(&mut parts, &text[at..words[end - 1].end]);
+        push_text(&mut parts, ELLIPSIS);
+    } else {
+        push_text(&mut parts, &text[at..]);
+    }
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(parts: &[SnippetPart]) -> String {
+        parts
+            .iter()
+            .map(|part| match part.highlight {
+                true => format!("[{}]", part.text),
+                false => part.text.clone(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn terms_ignore_case_and_punctuation() {
+        assert_eq!(terms("  Hello, WORLD!! hello"), ["hello", "world"]);
+        assert!(terms(" ?! ").is_empty());
+    }
+
+    #[test]
+    fn every_term_must_prefix_a_word() {
+        let terms = terms("rust rele");
+        assert!(matches("New Rust release is out", &terms));
+        assert!(!matches("New Rust version is out", &terms));
+        assert!(!matches("trusty release", &terms));
+    }
+
+    #[test]
+    fn short_messages_are_highlighted_whole() {
+        let parts = snippet("Привет, мир! Hello world", &terms("мир hello"));
+        assert_eq!(render(&parts), "Привет, [мир]! [Hello] world");
+    }
+
+    #[test]
+    fn long_messages_are_cut_around_the_first_match() {
+        let text = (1..=40)
+            .map(|i| format!("w{i}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let parts = snippet(&text, &terms("w20"));
+        assert_eq!(
+            render(&parts),
+            "…w16 w17 w18 w19 [w20] w21 w22 w23 w24 w25 w26 w27 w28 w29 w30 w31…"
+        );
+
+        let parts = snippet(&text, &terms("w39"));
+        assert_eq!(
+            render(&parts),
+            "…w25 w26 w27 w28 w29 w30 w31 w32 w33 w34 w35 w36 w37 w38 [w39] w40"
+        );
+    }
+}
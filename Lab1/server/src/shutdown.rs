@@ -0,0 +1,105 @@
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+#[derive(Debug)]
+struct Inner {
+    started: watch::Sender<bool>,
+    connections: watch::Sender<usize>,
+}
+
+// Tells open connections that the server is going down and waits for them to finish
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown {
+            inner: Arc::new(Inner {
+                started: watch::channel(false).0,
+                connections: watch::channel(0).0,
+            }),
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn start(&self) {
+        self.inner.started.send_replace(true);
+    }
+
+    pub fn is_started(&self) -> bool {
+        *self.inner.started.borrow()
+    }
+
+    // No new connections are taken once the shutdown has started
+    pub fn track(&self) -> Option<ConnectionGuard> {
+        if self.is_started() {
+            return None;
+        }
+        self.inner.connections.send_modify(|count| *count += 1);
+        Some(ConnectionGuard {
+            shutdown: self.clone(),
+        })
+    }
+
+    pub fn connections(&self) -> usize {
+        *self.inner.connections.borrow()
+    }
+
+    pub async fn drained(&self) {
+        let mut connections = self.inner.connections.subscribe();
+        let _ = connections.wait_for(|count| *count == 0).await;
+    }
+}
+
+// Keeps the connection counted until it is dropped
+pub struct ConnectionGuard {
+    shutdown: Shutdown,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.shutdown
+            .inner
+            .connections
+            .send_modify(|count| *count -= 1);
+    }
+}
+
+// Resolves on SIGTERM or SIGINT (Ctrl+C)
+pub async fn signal_received() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Couldn't listen for SIGTERM");
+    let mut interrupt = signal(SignalKind::interrupt()).expect("Couldn't listen for SIGINT");
+    tokio::select! {
+        _ = terminate.recv() => {},
+        _ = interrupt.recv() => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn drains_once_every_connection_is_released() {
+        let shutdown = Shutdown::default();
+        let first = shutdown.track().unwrap();
+        let second = shutdown.track().unwrap();
+        shutdown.start();
+        assert!(shutdown.track().is_none());
+        assert_eq!(shutdown.connections(), 2);
+
+        drop(first);
+        let drained = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.drained().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!drained.is_finished());
+        drop(second);
+        drained.await.unwrap();
+    }
+}
@@ -0,0 +1,99 @@
+use crate::auth::Identity;
+use crate::chat::{ChatTarget, Chats, Session};
+use crate::error::ChatError;
+use crate::outbox::Outbox;
+use crate::protocol::ProtocolQuery;
+use crate::utils::Pagination;
+use futures_util::{stream, Stream, StreamExt};
+use serde_json::json;
+use std::convert::Infallible;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{info_span, Instrument};
+use warp::reply::Response;
+use warp::sse::Event;
+use warp::ws::Message;
+use warp::Reply;
+
+// Posted events wait here until the session takes them
+const EVENTS_CAPACITY: usize = 16;
+
+// Fallback for clients behind proxies that strip websocket upgrades,
+// events come as server-sent events and go back with POST /sessions/:id/events
+pub fn connect(
+    chats: Chats,
+    target: ChatTarget,
+    identity: Identity,
+    page: Pagination,
+    protocol: ProtocolQuery,
+) -> Response {
+    let Some(connection) = chats.track_connection() else {
+        return ChatError::ServerShutdown.to_response();
+    };
+    let session = Session::new(identity.username);
+    let session_id = session.id;
+    let span = info_span!(
+        "connection",
+        session = session.id,
+        user = %session.username,
+        transport = "sse"
+    );
+    let (events_tx, events_rx) = mpsc::channel(EVENTS_CAPACITY);
+    let outbox = chats.outbox();
+    let rx = outbox.clone();
+    let pongs = events_tx.clone();
+
+    tokio::task::spawn(
+        async move {
+            let _connection = connection;
+            chats.open_stream(&session, events_tx).await;
+            let incoming = ReceiverStream::new(events_rx).map(Ok);
+            chats
+                .join(target, session, page, protocol, incoming, outbox.clone())
+                .await;
+            outbox.close();
+            chats.close_stream(session_id).await;
+        }
+        .instrument(span),
+    );
+    warp::sse::reply(events(rx, pongs)).into_response()
+}
+
+// Closes the session once the client goes away
+struct CloseOnDrop(Outbox);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+// The stream ends after the close frame, the client shouldn't reconnect on its own then
+fn events(
+    outbox: Outbox,
+    pongs: mpsc::Sender<Message>,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    let state = (CloseOnDrop(outbox), pongs, false);
+    stream::unfold(state, |(outbox, pongs, closed)| async move {
+        if closed {
+            return None;
+        }
+        let message = outbox.0.recv().await?;
+        let (event, closed) = match message.close_frame() {
+            Some((code, reason)) => {
+                let data = json!({ "code": code, "reason": reason });
+                (Event::default().event("close").data(data.to_string()), true)
+            }
+            None if message.is_ping() => {
+                // Nobody answers pings here, the client is there while events can be written
+                let _ = pongs.try_send(Message::pong(vec![]));
+                (Event::default().comment("ping"), false)
+            }
+            None => match message.to_str() {
+                Ok(text) => (Event::default().data(text), false),
+                Err(_) => (Event::default().comment(""), false),
+            },
+        };
+        Some((Ok(event), (outbox, pongs, closed)))
+    })
+}
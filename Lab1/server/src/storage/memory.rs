@@ -0,0 +1,534 @@
+use crate::attachment::Attachment;
+use crate::chat::{ChatMessage, MessageKind, Reaction, ReplyTo};
+use crate::error::ChatError;
+use crate::moderation::{Role, Sanction, SanctionKind};
+use crate::search;
+use crate::storage::{
+    add_reaction_user, ChatStore, InboxEntry, MessageQuery, ReadReceipt, Room, SearchQuery,
+};
+use async_trait::async_trait;
+use chrono::Utc;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use tokio::sync::RwLock;
+
+struct MemoryRoom {
+    room: Room,
+    messages: Vec<ChatMessage>,
+    // Username to the id of the last message read by the member
+    members: HashMap<String, i64>,
+    nicknames: HashMap<String, String>,
+    roles: HashMap<String, Role>,
+    sanctions: Vec<Sanction>,
+}
+
+impl MemoryRoom {
+    fn message_mut(&mut self, id: i64) -> Result<&mut ChatMessage, ChatError> {
+        // Ids only grow, so messages stay sorted by them
+        let index = self
+            .messages
+            .binary_search_by_key(&id, |message| message.id)
+            .map_err(|_| ChatError::MessageNotFound(id))?;
+        Ok(&mut self.messages[index])
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    last_id: AtomicI64,
+    last_attachment_id: AtomicI64,
+    rooms: RwLock<HashMap<String, MemoryRoom>>,
+    attachments: RwLock<HashMap<i64, Attachment>>,
+}
+
+#[async_trait]
+impl ChatStore for MemoryStore {
+    async fn insert_room(&self, chat: &str, topic: Option<&str>) -> Result<Room, ChatError> {
+        let room = Room {
+            name: chat.to_string(),
+            topic: topic.map(str::to_string),
+            archived: false,
+            direct: false,
+            created_at: Utc::now(),
+        };
+        self.insert_memory_room(room, HashMap::new()).await
+    }
+
+    async fn insert_direct_room(&self, chat: &str, members: [&str; 2]) -> Result<Room, ChatError> {
+        let room = Room {
+            name: chat.to_string(),
+            topic: None,
+            archived: false,
+            direct: true,
+            created_at: Utc::now(),
+        };
+        let members = members.iter().map(|m| (m.to_string(), 0)).collect();
+        self.insert_memory_room(room, members).await
+    }
+
+    async fn list_members(&self, chat: &str) -> Result<Vec<String>, ChatError> {
+        let rooms = self.rooms.read().await;
+        let mut members: Vec<String> = rooms
+            .get(chat)
+            .map(|r| r.members.keys().cloned().collect())
+            .unwrap_or_default();
+        members.sort();
+        Ok(members)
+    }
+
+    async fn add_member(&self, chat: &str, username: &str) -> Result<(), ChatError> {
+        let mut rooms = self.rooms.write().await;
+        get_room_mut(&mut rooms, chat)?
+            .members
+            .entry(username.to_string())
+            .or_default();
+        Ok(())
+    }
+
+    async fn set_nickname(
+        &self,
+        chat: &str,
+        username: &str,
+        nickname: Option<&str>,
+    ) -> Result<(), ChatError> {
+        let mut rooms = self.rooms.write().await;
+        let memory_room = get_room_mut(&mut rooms, chat)?;
+        match nickname {
+            Some(nickname) if memory_room.members.contains_key(username) => {
+                // Checked under the write lock, like the unique index of SQLite
+                let nickname_lowercase = nickname.to_lowercase();
+                let taken = memory_room.nicknames.iter().any(|(other, other_nickname)| {
+                    other != username && other_nickname.to_lowercase() == nickname_lowercase
+                });
+                if taken {
+                    return Err(ChatError::NicknameTaken(nickname.to_string()));
+                }
+                memory_room
+                    .nicknames
+                    .insert(username.to_string(), nickname.to_string());
+            }
+            _ => {
+                memory_room.nicknames.remove(username);
+            }
+        }
+        Ok(())
+    }
+
+    async fn list_nicknames(&self, chat: &str) -> Result<HashMap<String, String>, ChatError> {
+        let rooms = self.rooms.read().await;
+        Ok(rooms
+            .get(chat)
+            .map(|r| r.nicknames.clone())
+            .unwrap_or_default())
+    }
+
+    async fn is_member(&self, chat: &str, username: &str) -> Result<bool, ChatError> {
+        let rooms = self.rooms.read().await;
+        Ok(rooms
+            .get(chat)
+            .is_some_and(|r| r.members.contains_key(username)))
+    }
+
+    async fn list_inbox(&self, username: &str) -> Result<Vec<InboxEntry>, ChatError> {
+        let rooms = self.rooms.read().await;
+        let mut inbox: Vec<InboxEntry> = rooms
+            .values()
+            .filter(|r| r.room.direct)
+            .filter_map(|r| {
+                let last_read = *r.members.get(username)?;
+                let with = r.members.keys().find(|m| *m != username)?.clone();
+                let unread = r
+                    .messages
+                    .iter()
+                    .filter(|m| m.id > last_read && m.username != username)
+                    .count() as i64;
+                Some(InboxEntry {
+                    chat: r.room.name.clone(),
+                    with,
+                    unread,
+                    last_message: r.messages.iter().rev().find(|m| !m.thread_only).cloned(),
+                })
+            })
+            .collect();
+        inbox.sort_by(|a, b| a.chat.cmp(&b.chat));
+        Ok(inbox)
+    }
+
+    async fn mark_read(&self, chat: &str, username: &str, id: i64) -> Result<i64, ChatError> {
+        let mut rooms = self.rooms.write().await;
+        let memory_room = get_room_mut(&mut rooms, chat)?;
+        let last_read = memory_room.members.entry(username.to_string()).or_default();
+        *last_read = id.max(*last_read);
+        Ok(*last_read)
+    }
+
+    async fn list_read_receipts(&self, chat: &str) -> Result<Vec<ReadReceipt>, ChatError> {
+        let rooms = self.rooms.read().await;
+        let mut receipts: Vec<ReadReceipt> = rooms
+            .get(chat)
+            .map(|r| {
+                r.members
+                    .iter()
+                    .filter(|(_, last_read_id)| **last_read_id > 0)
+                    .map(|(username, last_read_id)| ReadReceipt {
+                        username: username.clone(),
+                        last_read_id: *last_read_id,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        receipts.sort_by(|a, b| a.username.cmp(&b.username));
+        Ok(receipts)
+    }
+
+    async fn get_room(&self, chat: &str) -> Result<Option<Room>, ChatError> {
+        Ok(self.rooms.read().await.get(chat).map(|r| r.room.clone()))
+    }
+
+    async fn list_rooms(&self) -> Result<Vec<Room>, ChatError> {
+        let mut rooms: Vec<Room> = self
+            .rooms
+            .read()
+            .await
+            .values()
+            .map(|r| r.room.clone())
+            .collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(rooms)
+    }
+
+    async fn archive_room(&self, chat: &str) -> Result<(), ChatError> {
+        if let Some(memory_room) = self.rooms.write().await.get_mut(chat) {
+            memory_room.room.archived = true;
+        }
+        Ok(())
+    }
+
+    async fn set_topic(&self, chat: &str, topic: Option<&str>) -> Result<(), ChatError> {
+        if let Some(memory_room) = self.rooms.write().await.get_mut(chat) {
+            memory_room.room.topic = topic.map(str::to_string);
+        }
+        Ok(())
+    }
+
+    async fn delete_room(&self, chat: &str) -> Result<(), ChatError> {
+        self.rooms.write().await.remove(chat);
+        self.attachments
+            .write()
+            .await
+            .retain(|_, attachment| attachment.chat != chat);
+        Ok(())
+    }
+
+    async fn insert_message(
+        &self,
+        chat: &str,
+        username: &str,
+        text: &str,
+        kind: MessageKind,
+        attachments: &[i64],
+        reply_to: Option<ReplyTo>,
+    ) -> Result<ChatMessage, ChatError> {
+        let mut rooms = self.rooms.write().await;
+        let memory_room = get_room_mut(&mut rooms, chat)?;
+        let mut all_attachments = self.attachments.write().await;
+        for (i, id) in attachments.iter().enumerate() {
+            let is_sendable = all_attachments.get(id).is_some_and(|a| {
+                a.chat == chat && a.username == username && a.message_id.is_none()
+            });
+            if !is_sendable || attachments[..i].contains(id) {
+                Err(ChatError::AttachmentForbidden(*id))?
+            }
+        }
+        if let Some(reply_to) = reply_to {
+            memory_room.message_mut(reply_to.parent_id)?.reply_count += 1;
+        }
+        let id = self.last_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut message = ChatMessage::new(id, username.to_string(), text.to_string(), Utc::now());
+        message.kind = kind;
+        message.parent_id = reply_to.map(|reply_to| reply_to.parent_id);
+        message.thread_only = reply_to.is_some_and(|reply_to| reply_to.thread_only);
+        for attachment_id in attachments {
+            if let Some(attachment) = all_attachments.get_mut(attachment_id) {
+                attachment.message_id = Some(id);
+                message.attachments.push(attachment.clone());
+            }
+        }
+        message.attachments.sort_by_key(|a| a.id);
+        memory_room.messages.push(message.clone());
+        Ok(message)
+    }
+
+    async fn list_messages(
+        &self,
+        chat: &str,
+        query: MessageQuery,
+    ) -> Result<Vec<ChatMessage>, ChatError> {
+        let rooms = self.rooms.read().await;
+        let Some(memory_room) = rooms.get(chat) else {
+            return Ok(vec![]);
+        };
+        Ok(memory_room
+            .messages
+            .iter()
+            .rev()
+            .filter(|message| !message.thread_only)
+            .skip_while(|message| query.before.is_some_and(|before| message.id >= before))
+            .take_while(|message| query.after.is_none_or(|after| message.id > after))
+            .skip(query.offset)
+            .take(query.take)
+            .cloned()
+            .collect())
+    }
+
+    async fn list_messages_after(
+        &self,
+        chat: &str,
+        id: i64,
+        take: usize,
+    ) -> Result<Vec<ChatMessage>, ChatError> {
+        let rooms = self.rooms.read().await;
+        let Some(memory_room) = rooms.get(chat) else {
+            return Ok(vec![]);
+        };
+        let mut messages: Vec<ChatMessage> = memory_room
+            .messages
+            .iter()
+            .skip_while(|message| message.id <= id)
+            .filter(|message| !message.thread_only)
+            .take(take)
+            .cloned()
+            .collect();
+        messages.reverse();
+        Ok(messages)
+    }
+
+    async fn list_replies(
+        &self,
+        chat: &str,
+        id: i64,
+        query: MessageQuery,
+    ) -> Result<Vec<ChatMessage>, ChatError> {
+        let rooms = self.rooms.read().await;
+        let Some(memory_room) = rooms.get(chat) else {
+            return Ok(vec![]);
+        };
+        Ok(memory_room
+            .messages
+            .iter()
+            .rev()
+            .filter(|message| message.parent_id == Some(id))
+            .skip_while(|message| query.before.is_some_and(|before| message.id >= before))
+            .take_while(|message| query.after.is_none_or(|after| message.id > after))
+            .skip(query.offset)
+            .take(query.take)
+            .cloned()
+            .collect())
+    }
+
+    async fn search_messages(
+        &self,
+        chat: &str,
+        query: &SearchQuery,
+    ) -> Result<Vec<ChatMessage>, ChatError> {
+        let rooms = self.rooms.read().await;
+        let Some(memory_room) = rooms.get(chat) else {
+            return Ok(vec![]);
+        };
+        Ok(memory_room
+            .messages
+            .iter()
+            .rev()
+            .filter(|message| query.author.as_ref().is_none_or(|a| *a == message.username))
+            .filter(|message| query.from.is_none_or(|from| message.created_at >= from))
+            .filter(|message| query.to.is_none_or(|to| message.created_at <= to))
+            .filter(|message| search::matches(&message.text, &query.terms))
+            .skip(query.offset)
+            .take(query.take + 1)
+            .cloned()
+            .collect())
+    }
+
+    async fn count_messages(&self, chat: &str) -> Result<i64, ChatError> {
+        let rooms = self.rooms.read().await;
+        Ok(rooms.get(chat).map_or(0, |r| r.messages.len() as i64))
+    }
+
+    async fn get_message(&self, chat: &str, id: i64) -> Result<Option<ChatMessage>, ChatError> {
+        let rooms = self.rooms.read().await;
+        Ok(rooms
+            .get(chat)
+            .and_then(|r| r.messages.iter().find(|message| message.id == id))
+            .cloned())
+    }
+
+    async fn update_message(
+        &self,
+        chat: &str,
+        id: i64,
+        text: &str,
+    ) -> Result<ChatMessage, ChatError> {
+        let mut rooms = self.rooms.write().await;
+        let message = get_room_mut(&mut rooms, chat)?.message_mut(id)?;
+        message.text = text.to_string();
+        message.edited_at = Some(Utc::now());
+        Ok(message.clone())
+    }
+
+    async fn delete_message(&self, chat: &str, id: i64) -> Result<(), ChatError> {
+        let mut rooms = self.rooms.write().await;
+        let memory_room = get_room_mut(&mut rooms, chat)?;
+        let Ok(message) = memory_room.message_mut(id) else {
+            return Ok(());
+        };
+        if let Some(parent_id) = message.parent_id {
+            memory_room.message_mut(parent_id)?.reply_count -= 1;
+        }
+        let mut deleted = vec![];
+        memory_room.messages.retain(|message| {
+            let is_deleted = message.id == id || message.parent_id == Some(id);
+            if is_deleted {
+                deleted.push(message.id);
+            }
+            !is_deleted
+        });
+        self.attachments.write().await.retain(|_, attachment| {
+            !attachment
+                .message_id
+                .is_some_and(|id| deleted.contains(&id))
+        });
+        Ok(())
+    }
+
+    async fn insert_attachment(&self, attachment: &Attachment) -> Result<Attachment, ChatError> {
+        let attachment = Attachment {
+            id: self.last_attachment_id.fetch_add(1, Ordering::SeqCst) + 1,
+            ..attachment.clone()
+        };
+        self.attachments
+            .write()
+            .await
+            .insert(attachment.id, attachment.clone());
+        Ok(attachment)
+    }
+
+    async fn get_attachment(&self, id: i64) -> Result<Option<Attachment>, ChatError> {
+        Ok(self.attachments.read().await.get(&id).cloned())
+    }
+
+    async fn add_reaction(
+        &self,
+        chat: &str,
+        id: i64,
+        username: &str,
+        emoji: &str,
+    ) -> Result<Vec<Reaction>, ChatError> {
+        let mut rooms = self.rooms.write().await;
+        let message = get_room_mut(&mut rooms, chat)?.message_mut(id)?;
+        add_reaction_user(&mut message.reactions, emoji, username);
+        Ok(message.reactions.clone())
+    }
+
+    async fn remove_reaction(
+        &self,
+        chat: &str,
+        id: i64,
+        username: &str,
+        emoji: &str,
+    ) -> Result<Vec<Reaction>, ChatError> {
+        let mut rooms = self.rooms.write().await;
+        let message = get_room_mut(&mut rooms, chat)?.message_mut(id)?;
+        for reaction in message.reactions.iter_mut().filter(|r| r.emoji == emoji) {
+            reaction.users.retain(|user| user != username);
+        }
+        message
+            .reactions
+            .retain(|reaction| !reaction.users.is_empty());
+        Ok(message.reactions.clone())
+    }
+
+    async fn get_role(&self, chat: &str, username: &str) -> Result<Role, ChatError> {
+        let rooms = self.rooms.read().await;
+        let role = rooms.get(chat).and_then(|r| r.roles.get(username).copied());
+        Ok(role.unwrap_or(Role::Member))
+    }
+
+    async fn set_role(&self, chat: &str, username: &str, role: Role) -> Result<(), ChatError> {
+        let mut rooms = self.rooms.write().await;
+        let memory_room = get_room_mut(&mut rooms, chat)?;
+        // Same as the upsert of SQLite, a role makes the user a member
+        memory_room.members.entry(username.to_string()).or_default();
+        memory_room.roles.insert(username.to_string(), role);
+        Ok(())
+    }
+
+    async fn get_sanction(
+        &self,
+        chat: &str,
+        username: &str,
+        kind: SanctionKind,
+    ) -> Result<Option<Sanction>, ChatError> {
+        let rooms = self.rooms.read().await;
+        let Some(memory_room) = rooms.get(chat) else {
+            return Ok(None);
+        };
+        Ok(memory_room
+            .sanctions
+            .iter()
+            .find(|s| s.username == username && s.kind == kind)
+            .cloned())
+    }
+
+    async fn insert_sanction(&self, chat: &str, sanction: &Sanction) -> Result<(), ChatError> {
+        let mut rooms = self.rooms.write().await;
+        let sanctions = &mut get_room_mut(&mut rooms, chat)?.sanctions;
+        sanctions.retain(|s| !(s.username == sanction.username && s.kind == sanction.kind));
+        sanctions.push(sanction.clone());
+        Ok(())
+    }
+
+    async fn delete_sanction(
+        &self,
+        chat: &str,
+        username: &str,
+        kind: SanctionKind,
+    ) -> Result<(), ChatError> {
+        let mut rooms = self.rooms.write().await;
+        get_room_mut(&mut rooms, chat)?
+            .sanctions
+            .retain(|s| !(s.username == username && s.kind == kind));
+        Ok(())
+    }
+}
+
+impl MemoryStore {
+    async fn insert_memory_room(
+        &self,
+        room: Room,
+        members: HashMap<String, i64>,
+    ) -> Result<Room, ChatError> {
+        let mut rooms = self.rooms.write().await;
+        if rooms.contains_key(&room.name) {
+            return Err(ChatError::ChatAlreadyExist(room.name));
+        }
+        let memory_room = MemoryRoom {
+            room: room.clone(),
+            messages: vec![],
+            members,
+            nicknames: HashMap::new(),
+            roles: HashMap::new(),
+            sanctions: vec![],
+        };
+        rooms.insert(room.name.clone(), memory_room);
+        Ok(room)
+    }
+}
+
+fn get_room_mut<'a>(
+    rooms: &'a mut HashMap<String, MemoryRoom>,
+    chat: &str,
+) -> Result<&'a mut MemoryRoom, ChatError> {
+    rooms
+        .get_mut(chat)
+        .ok_or_else(|| ChatError::ChatNotFound(chat.to_string()))
+}
@@ -0,0 +1,681 @@
+mod memory;
+mod sqlite;
+
+use crate::attachment::Attachment;
+use crate::chat::{ChatMessage, MessageKind, Reaction, ReplyTo};
+use crate::error::ChatError;
+use crate::moderation::{Role, Sanction, SanctionKind};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub use memory::MemoryStore;
+pub use sqlite::SqliteStore;
+
+pub type Store = Arc<dyn ChatStore>;
+
+#[derive(Deserialize, Serialize, Debug, Clone, sqlx::FromRow)]
+pub struct Room {
+    pub name: String,
+    pub topic: Option<String>,
+    pub archived: bool,
+    // Direct rooms hold a private conversation of exactly two members
+    pub direct: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, sqlx::FromRow)]
+pub struct ReadReceipt {
+    pub username: String,
+    pub last_read_id: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, sqlx::FromRow)]
+pub struct InboxEntry {
+    pub chat: String,
+    pub with: String,
+    pub unread: i64,
+    #[sqlx(skip)]
+    pub last_message: Option<ChatMessage>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MessageQuery {
+    pub before: Option<i64>,
+    pub after: Option<i64>,
+    pub offset: usize,
+    pub take: usize,
+}
+
+// Messages have to contain a word starting with each of the terms
+#[derive(Debug, Clone)]
+pub struct SearchQuery {
+    pub terms: Vec<String>,
+    pub author: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub offset: usize,
+    pub take: usize,
+}
+
+// Messages are always returned newest first
+#[async_trait]
+pub trait ChatStore: Send + Sync {
+    async fn insert_room(&self, chat: &str, topic: Option<&str>) -> Result<Room, ChatError>;
+
+    async fn insert_direct_room(&self, chat: &str, members: [&str; 2]) -> Result<Room, ChatError>;
+
+    async fn list_members(&self, chat: &str) -> Result<Vec<String>, ChatError>;
+
+    // Does nothing for users who are already members
+    async fn add_member(&self, chat: &str, username: &str) -> Result<(), ChatError>;
+
+    async fn is_member(&self, chat: &str, username: &str) -> Result<bool, ChatError>;
+
+    // Empty nickname resets it to the username
+    async fn set_nickname(
+        &self,
+        chat: &str,
+        username: &str,
+        nickname: Option<&str>,
+    ) -> Result<(), ChatError>;
+
+    // Username to nickname, only for the members who have one
+    async fn list_nicknames(&self, chat: &str) -> Result<HashMap<String, String>, ChatError>;
+
+    // Direct rooms of the user with the count of messages they haven't read
+    async fn list_inbox(&self, username: &str) -> Result<Vec<InboxEntry>, ChatError>;
+
+    // Never moves backwards, returns the resulting last read id
+    async fn mark_read(&self, chat: &str, username: &str, id: i64) -> Result<i64, ChatError>;
+
+    async fn list_read_receipts(&self, chat: &str) -> Result<Vec<ReadReceipt>, ChatError>;
+
+    // Users without an explicit role are plain members
+    async fn get_role(&self, chat: &str, username: &str) -> Result<Role, ChatError>;
+
+    async fn set_role(&self, chat: &str, username: &str, role: Role) -> Result<(), ChatError>;
+
+    async fn get_sanction(
+        &self,
+        chat: &str,
+        username: &str,
+        kind: SanctionKind,
+    ) -> Result<Option<Sanction>, ChatError>;
+
+    // Replaces the previous sanction of the same kind
+    async fn insert_sanction(&self, chat: &str, sanction: &Sanction) -> Result<(), ChatError>;
+
+    async fn delete_sanction(
+        &self,
+        chat: &str,
+        username: &str,
+        kind: SanctionKind,
+    ) -> Result<(), ChatError>;
+
+    async fn get_room(&self, chat: &str) -> Result<Option<Room>, ChatError>;
+
+    async fn list_rooms(&self) -> Result<Vec<Room>, ChatError>;
+
+    async fn archive_room(&self, chat: &str) -> Result<(), ChatError>;
+
+    async fn set_topic(&self, chat: &str, topic: Option<&str>) -> Result<(), ChatError>;
+
+    async fn delete_room(&self, chat: &str) -> Result<(), ChatError>;
+
+    // Attachments must be unsent uploads of the same user to the same chat,
+    // replies count towards the reply count of their parent
+    async fn insert_message(
+        &self,
+        chat: &str,
+        username: &str,
+        text: &str,
+        kind: MessageKind,
+        attachments: &[i64],
+        reply_to: Option<ReplyTo>,
+    ) -> Result<ChatMessage, ChatError>;
+
+    // Thread only replies are left out
+    async fn list_messages(
+        &self,
+        chat: &str,
+        query: MessageQuery,
+    ) -> Result<Vec<ChatMessage>, ChatError>;
+
+    // The `take` messages right after `id`, thread only replies are left out
+    async fn list_messages_after(
+        &self,
+        chat: &str,
+        id: i64,
+        take: usize,
+    ) -> Result<Vec<ChatMessage>, ChatError>;
+
+    async fn list_replies(
+        &self,
+        chat: &str,
+        id: i64,
+        query: MessageQuery,
+    ) -> Result<Vec<ChatMessage>, ChatError>;
+
+    // Returns up to `take + 1` messages, so callers can tell if there are more
+    async fn search_messages(
+        &self,
+        chat: &str,
+        query: &SearchQuery,
+    ) -> Result<Vec<ChatMessage>, ChatError>;
+
+    async fn count_messages(&self, chat: &str) -> Result<i64, ChatError>;
+
+    async fn get_message(&self, chat: &str, id: i64) -> Result<Option<ChatMessage>, ChatError>;
+
+    async fn update_message(
+        &self,
+        chat: &str,
+        id: i64,
+        text: &str,
+    ) -> Result<ChatMessage, ChatError>;
+
+    // Deletes the replies along with the message
+    async fn delete_message(&self, chat: &str, id: i64) -> Result<(), ChatError>;
+
+    // Returns the attachment with its new id
+    async fn insert_attachment(&self, attachment: &Attachment) -> Result<Attachment, ChatError>;
+
+    async fn get_attachment(&self, id: i64) -> Result<Option<Attachment>, ChatError>;
+
+    // Both return the reactions of the message after the change
+    async fn add_reaction(
+        &self,
+        chat: &str,
+        id: i64,
+        username: &str,
+        emoji: &str,
+    ) -> Result<Vec<Reaction>, ChatError>;
+
+    async fn remove_reaction(
+        &self,
+        chat: &str,
+        id: i64,
+        username: &str,
+        emoji: &str,
+    ) -> Result<Vec<Reaction>, ChatError>;
+
+    // Waits for the pending writes on shutdown, nothing is stored afterwards
+    async fn close(&self) {}
+}
+
+fn add_reaction_user(reactions: &mut Vec<Reaction>, emoji: &str, username: &str) {
+    match reactions.iter_mut().find(|r| r.emoji == emoji) {
+        Some(reaction) if reaction.users.iter().any(|u| u == username) => {}
+        Some(reaction) => reaction.users.push(username.to_string()),
+        None => reactions.push(Reaction {
+            emoji: emoji.to_string(),
+            users: vec![username.to_string()],
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    // Every test runs against both backends, each store starts empty
+    async fn stores() -> [Store; 2] {
+        [
+            Arc::new(MemoryStore::default()),
+            Arc::new(SqliteStore::connect_and_migrate("sqlite::memory:").await),
+        ]
+    }
+
+    async fn post(store: &Store, chat: &str, username: &str, text: &str) -> ChatMessage {
+        store
+            .insert_message(chat, username, text, MessageKind::Text, &[], None)
+            .await
+            .unwrap()
+    }
+
+    async fn reply(store: &Store, parent_id: i64, text: &str, thread_only: bool) -> ChatMessage {
+        let reply_to = ReplyTo {
+            parent_id,
+            thread_only,
+        };
+        store
+            .insert_message("room", "bob", text, MessageKind::Text, &[], Some(reply_to))
+            .await
+            .unwrap()
+    }
+
+    fn query(before: Option<i64>, after: Option<i64>, offset: usize, take: usize) -> MessageQuery {
+        MessageQuery {
+            before,
+            after,
+            offset,
+            take,
+        }
+    }
+
+    fn ids(messages: &[ChatMessage]) -> Vec<i64> {
+        messages.iter().map(|m| m.id).collect()
+    }
+
+    fn attachment(username: &str) -> Attachment {
+        Attachment {
+            id: 0,
+            chat: "room".to_string(),
+            username: username.to_string(),
+            filename: "cat.png".to_string(),
+            content_type: "image/png".to_string(),
+            size: 3,
+            thumbnail: true,
+            message_id: None,
+            created_at: Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn rooms_keep_their_members_and_settings() {
+        for store in stores().await {
+            store.insert_room("room", Some("hello")).await.unwrap();
+            let duplicate = store.insert_room("room", None).await;
+            assert!(matches!(duplicate, Err(ChatError::ChatAlreadyExist(_))));
+            store
+                .insert_direct_room("dm:a:b", ["a", "b"])
+                .await
+                .unwrap();
+
+            store.add_member("room", "bob").await.unwrap();
+            store.add_member("room", "alice").await.unwrap();
+            store.add_member("room", "bob").await.unwrap();
+            assert_eq!(store.list_members("room").await.unwrap(), ["alice", "bob"]);
+            assert!(store.is_member("room", "bob").await.unwrap());
+            assert!(!store.is_member("room", "carol").await.unwrap());
+
+            store
+                .set_nickname("room", "bob", Some("Bobby"))
+                .await
+                .unwrap();
+            store
+                .set_nickname("room", "carol", Some("Caz"))
+                .await
+                .unwrap();
+            let nicknames = store.list_nicknames("room").await.unwrap();
+            assert_eq!(nicknames, HashMap::from([("bob".into(), "Bobby".into())]));
+            let taken = store.set_nickname("room", "alice", Some("BOBBY")).await;
+            assert!(matches!(taken, Err(ChatError::NicknameTaken(name)) if name == "BOBBY"));
+            store
+                .set_nickname("room", "bob", Some("bobby"))
+                .await
+                .unwrap();
+            store.set_nickname("room", "bob", None).await.unwrap();
+            assert!(store.list_nicknames("room").await.unwrap().is_empty());
+
+            assert_eq!(store.get_role("room", "bob").await.unwrap(), Role::Member);
+            store
+                .set_role("room", "bob", Role::Moderator)
+                .await
+                .unwrap();
+            assert_eq!(
+                store.get_role("room", "bob").await.unwrap(),
+                Role::Moderator
+            );
+            store.set_role("room", "dave", Role::Owner).await.unwrap();
+            assert!(store.is_member("room", "dave").await.unwrap());
+
+            store.set_topic("room", None).await.unwrap();
+            store.archive_room("room").await.unwrap();
+            let room = store.get_room("room").await.unwrap().unwrap();
+            assert_eq!(
+                (room.topic, room.archived, room.direct),
+                (None, true, false)
+            );
+            let rooms = store.list_rooms().await.unwrap();
+            let names: Vec<&str> = rooms.iter().map(|r| r.name.as_str()).collect();
+            assert_eq!(names, ["dm:a:b", "room"]);
+            assert!(rooms[0].direct);
+            assert_eq!(store.list_members("dm:a:b").await.unwrap(), ["a", "b"]);
+            assert!(store.get_room("nope").await.unwrap().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_are_paged_newest_first() {
+        for store in stores().await {
+            store.insert_room("room", None).await.unwrap();
+            let mut posted = vec![];
+            for i in 0..5 {
+                posted.push(
+                    post(&store, "room", "alice", &format!("message {i}"))
+                        .await
+                        .id,
+                );
+            }
+            let all = store.list_messages("room", query(None, None, 0, 10)).await;
+            let expected: Vec<i64> = posted.iter().rev().copied().collect();
+            assert_eq!(ids(&all.unwrap()), expected);
+
+            let page = query(Some(posted[3]), None, 0, 2);
+            let older = store.list_messages("room", page).await.unwrap();
+            assert_eq!(ids(&older), [posted[2], posted[1]]);
+            let page = query(None, Some(posted[1]), 1, 10);
+            let newer = store.list_messages("room", page).await.unwrap();
+            assert_eq!(ids(&newer), [posted[3], posted[2]]);
+            let after = store.list_messages_after("room", posted[1], 2).await;
+            assert_eq!(ids(&after.unwrap()), [posted[3], posted[2]]);
+            assert_eq!(store.count_messages("room").await.unwrap(), 5);
+
+            let edited = store
+                .update_message("room", posted[0], "edited")
+                .await
+                .unwrap();
+            assert_eq!(edited.text, "edited");
+            assert!(edited.edited_at.is_some());
+            let missing = store.get_message("other", posted[0]).await.unwrap();
+            assert!(missing.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn reactions_keep_the_order_they_were_added_in() {
+        for store in stores().await {
+            store.insert_room("room", None).await.unwrap();
+            let id = post(&store, "room", "alice", "hi").await.id;
+            store.add_reaction("room", id, "bob", "👍").await.unwrap();
+            store.add_reaction("room", id, "carol", "❤️").await.unwrap();
+            store.add_reaction("room", id, "carol", "👍").await.unwrap();
+            let reactions = store.add_reaction("room", id, "bob", "👍").await.unwrap();
+            assert_eq!(reactions[0].emoji, "👍");
+            assert_eq!(reactions[0].users, ["bob", "carol"]);
+            assert_eq!(reactions[1].users, ["carol"]);
+
+            store
+                .remove_reaction("room", id, "carol", "❤️")
+                .await
+                .unwrap();
+            let message = store.get_message("room", id).await.unwrap().unwrap();
+            assert_eq!(message.reactions.len(), 1);
+            let listed = store.list_messages("room", query(None, None, 0, 1)).await;
+            assert_eq!(listed.unwrap()[0].reactions, message.reactions);
+        }
+    }
+
+    #[tokio::test]
+    async fn search_matches_word_prefixes() {
+        for store in stores().await {
+            store.insert_room("room", None).await.unwrap();
+            store.insert_room("other", None).await.unwrap();
+            let first = post(&store, "room", "alice", "Deploying the release").await;
+            post(&store, "room", "bob", "nothing to see").await;
+            let last = post(&store, "room", "bob", "release notes are out").await;
+            post(&store, "other", "bob", "release elsewhere").await;
+
+            let mut search = SearchQuery {
+                terms: vec!["releas".to_string()],
+                author: None,
+                from: None,
+                to: None,
+                offset: 0,
+                take: 1,
+            };
+            // One more than the page, to tell there is another one
+            let found = store.search_messages("room", &search).await.unwrap();
+            assert_eq!(ids(&found), [last.id, first.id]);
+
+            search.author = Some("alice".to_string());
+            let found = store.search_messages("room", &search).await.unwrap();
+            assert_eq!(ids(&found), [first.id]);
+
+            search.author = None;
+            search.terms = vec!["deploy".to_string(), "the".to_string()];
+            search.from = Some(first.created_at + Duration::hours(1));
+            let found = store.search_messages("room", &search).await.unwrap();
+            assert!(found.is_empty());
+
+            store
+                .update_message("room", first.id, "rolled back")
+                .await
+                .unwrap();
+            search.from = None;
+            let found = store.search_messages("room", &search).await.unwrap();
+            assert!(found.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn read_receipts_never_move_backwards() {
+        for store in stores().await {
+            store.insert_room("room", None).await.unwrap();
+            store.add_member("room", "alice").await.unwrap();
+            store.add_member("room", "bob").await.unwrap();
+            assert_eq!(store.mark_read("room", "alice", 5).await.unwrap(), 5);
+            assert_eq!(store.mark_read("room", "alice", 3).await.unwrap(), 5);
+            assert_eq!(store.mark_read("room", "carol", 2).await.unwrap(), 2);
+
+            let receipts = store.list_read_receipts("room").await.unwrap();
+            let receipts: Vec<(&str, i64)> = receipts
+                .iter()
+                .map(|r| (r.username.as_str(), r.last_read_id))
+                .collect();
+            assert_eq!(receipts, [("alice", 5), ("carol", 2)]);
+        }
+    }
+
+    #[tokio::test]
+    async fn sanctions_are_replaced_and_deleted() {
+        for store in stores().await {
+            store.insert_room("room", None).await.unwrap();
+            let ban = Sanction::new(
+                "bob".to_string(),
+                SanctionKind::Ban,
+                None,
+                Some(60),
+                "alice".to_string(),
+            );
+            store.insert_sanction("room", &ban).await.unwrap();
+            let reason = Some("spam".to_string());
+            let ban = Sanction::new(
+                "bob".into(),
+                SanctionKind::Ban,
+                reason,
+                None,
+                "alice".into(),
+            );
+            store.insert_sanction("room", &ban).await.unwrap();
+
+            let found = store.get_sanction("room", "bob", SanctionKind::Ban).await;
+            let found = found.unwrap().unwrap();
+            assert_eq!(found.reason.as_deref(), Some("spam"));
+            assert_eq!(found.expires_at, None);
+            let mute = store.get_sanction("room", "bob", SanctionKind::Mute).await;
+            assert!(mute.unwrap().is_none());
+
+            store
+                .delete_sanction("room", "bob", SanctionKind::Ban)
+                .await
+                .unwrap();
+            let found = store.get_sanction("room", "bob", SanctionKind::Ban).await;
+            assert!(found.unwrap().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn attachments_are_sent_once_by_their_uploader() {
+        for store in stores().await {
+            store.insert_room("room", None).await.unwrap();
+            let first = store.insert_attachment(&attachment("alice")).await.unwrap();
+            let second = store.insert_attachment(&attachment("alice")).await.unwrap();
+            let bobs = store.insert_attachment(&attachment("bob")).await.unwrap();
+            assert_ne!(first.id, second.id);
+
+            let forbidden = store
+                .insert_message("room", "alice", "", MessageKind::Text, &[bobs.id], None)
+                .await;
+            assert!(matches!(forbidden, Err(ChatError::AttachmentForbidden(id)) if id == bobs.id));
+            let attachments = [second.id, first.id];
+            let message = store
+                .insert_message("room", "alice", "", MessageKind::Text, &attachments, None)
+                .await
+                .unwrap();
+            assert_eq!(ids_of(&message.attachments), [first.id, second.id]);
+            let sent = store.get_attachment(first.id).await.unwrap().unwrap();
+            assert_eq!(sent.message_id, Some(message.id));
+            let again = store
+                .insert_message("room", "alice", "", MessageKind::Text, &[first.id], None)
+                .await;
+            assert!(matches!(again, Err(ChatError::AttachmentForbidden(_))));
+
+            let listed = store.list_messages("room", query(None, None, 0, 10)).await;
+            assert_eq!(ids(&listed.unwrap()), [message.id]);
+            store.delete_message("room", message.id).await.unwrap();
+            assert!(store.get_attachment(first.id).await.unwrap().is_none());
+            assert!(store.get_attachment(bobs.id).await.unwrap().is_some());
+        }
+    }
+
+    fn ids_of(attachments: &[Attachment]) -> Vec<i64> {
+        attachments.iter().map(|a| a.id).collect()
+    }
+
+    #[tokio::test]
+    async fn replies_are_deleted_with_their_thread() {
+        for store in stores().await {
+            store.insert_room("room", None).await.unwrap();
+            let root = post(&store, "room", "alice", "root message").await;
+            let shown = reply(&store, root.id, "shown reply", false).await;
+            let hidden = reply(&store, root.id, "hidden reply", true).await;
+            let missing = ReplyTo {
+                parent_id: 999,
+                thread_only: false,
+            };
+            let orphan = store
+                .insert_message("room", "bob", "?", MessageKind::Text, &[], Some(missing))
+                .await;
+            assert!(matches!(orphan, Err(ChatError::MessageNotFound(999))));
+
+            let root_now = store.get_message("room", root.id).await.unwrap().unwrap();
+            assert_eq!(root_now.reply_count, 2);
+            let listed = store.list_messages("room", query(None, None, 0, 10)).await;
+            assert_eq!(ids(&listed.unwrap()), [shown.id, root.id]);
+            let replies = store
+                .list_replies("room", root.id, query(None, None, 0, 10))
+                .await;
+            assert_eq!(ids(&replies.unwrap()), [hidden.id, shown.id]);
+
+            store.delete_message("room", shown.id).await.unwrap();
+            let root_now = store.get_message("room", root.id).await.unwrap().unwrap();
+            assert_eq!(root_now.reply_count, 1);
+
+            store.delete_message("room", root.id).await.unwrap();
+            assert!(store
+                .get_message("room", hidden.id)
+                .await
+                .unwrap()
+                .is_none());
+            assert_eq!(store.count_messages("room").await.unwrap(), 0);
+            let search = SearchQuery {
+                terms: vec!["reply".to_string()],
+                author: None,
+                from: None,
+                to: None,
+                offset: 0,
+                take: 10,
+            };
+            assert!(store
+                .search_messages("room", &search)
+                .await
+                .unwrap()
+                .is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn inbox_counts_unread_direct_messages() {
+        for store in stores().await {
+            store.insert_room("room", None).await.unwrap();
+            store.add_member("room", "alice").await.unwrap();
+            store
+                .insert_direct_room("dm:alice:bob", ["alice", "bob"])
+                .await
+                .unwrap();
+            store
+                .insert_direct_room("dm:alice:carol", ["alice", "carol"])
+                .await
+                .unwrap();
+            let first = post(&store, "dm:alice:bob", "bob", "hi").await;
+            post(&store, "dm:alice:bob", "alice", "hello").await;
+            let last = post(&store, "dm:alice:bob", "bob", "how are you?").await;
+            store
+                .mark_read("dm:alice:bob", "alice", first.id)
+                .await
+                .unwrap();
+
+            let inbox = store.list_inbox("alice").await.unwrap();
+            assert_eq!(inbox.len(), 2);
+            assert_eq!(
+                (inbox[0].chat.as_str(), inbox[0].with.as_str()),
+                ("dm:alice:bob", "bob")
+            );
+            assert_eq!(inbox[0].unread, 1);
+            assert_eq!(inbox[0].last_message.as_ref().unwrap().id, last.id);
+            assert_eq!(inbox[1].with, "carol");
+            assert!(inbox[1].last_message.is_none());
+            assert_eq!(store.list_inbox("bob").await.unwrap()[0].unread, 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn inbox_skips_replies_kept_in_threads() {
+        for store in stores().await {
+            store
+                .insert_direct_room("dm:alice:bob", ["alice", "bob"])
+                .await
+                .unwrap();
+            let parent = post(&store, "dm:alice:bob", "alice", "lunch?").await;
+            let reply_to = ReplyTo {
+                parent_id: parent.id,
+                thread_only: true,
+            };
+            store
+                .insert_message(
+                    "dm:alice:bob",
+                    "bob",
+                    "sure",
+                    MessageKind::Text,
+                    &[],
+                    Some(reply_to),
+                )
+                .await
+                .unwrap();
+
+            let inbox = store.list_inbox("bob").await.unwrap();
+            assert_eq!(inbox[0].last_message.as_ref().unwrap().id, parent.id);
+            assert_eq!(inbox[0].last_message.as_ref().unwrap().reply_count, 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn deleted_rooms_take_everything_with_them() {
+        for store in stores().await {
+            store.insert_room("room", None).await.unwrap();
+            store.add_member("room", "alice").await.unwrap();
+            let upload = store.insert_attachment(&attachment("alice")).await.unwrap();
+            let id = post(&store, "room", "alice", "bye").await.id;
+            let ban = Sanction::new("bob".into(), SanctionKind::Ban, None, None, "alice".into());
+            store.insert_sanction("room", &ban).await.unwrap();
+
+            store.delete_room("room").await.unwrap();
+            assert!(store.get_room("room").await.unwrap().is_none());
+            assert!(store.get_message("room", id).await.unwrap().is_none());
+            assert!(store.get_attachment(upload.id).await.unwrap().is_none());
+            assert!(store.list_members("room").await.unwrap().is_empty());
+
+            // The name can be used again from scratch
+            store.insert_room("room", None).await.unwrap();
+            let ban = store.get_sanction("room", "bob", SanctionKind::Ban).await;
+            assert!(ban.unwrap().is_none());
+            assert_eq!(store.count_messages("room").await.unwrap(), 0);
+        }
+    }
+}
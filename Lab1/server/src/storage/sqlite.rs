@@ -0,0 +1,908 @@
+use crate::attachment::Attachment;
+use crate::chat::{ChatMessage, MessageKind, Reaction, ReplyTo};
+use crate::error::ChatError;
+use crate::moderation::{Role, Sanction, SanctionKind};
+use crate::storage::{
+    add_reaction_user, ChatStore, InboxEntry, MessageQuery, ReadReceipt, Room, SearchQuery,
+};
+use async_trait::async_trait;
+use chrono::Utc;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::{Sqlite, SqlitePool, Transaction};
+use std::collections::HashMap;
+use std::str::FromStr;
+use tracing::info;
+
+pub struct SqliteStore {
+    pool: SqlitePool,
+}
+
+impl SqliteStore {
+    pub async fn connect_and_migrate(name: &str) -> Self {
+        let options = SqliteConnectOptions::from_str(name)
+            .unwrap_or_else(|_| panic!("Invalid database url: {name}"))
+            .create_if_missing(true);
+        // Other connections to a shared in-memory database can't open its full-text index
+        let max_connections = if name.contains(":memory:") { 1 } else { 10 };
+        let pool = SqlitePoolOptions::new()
+            .max_connections(max_connections)
+            .connect_with(options)
+            .await
+            .unwrap_or_else(|_| panic!("Couldn't connect to database: {name}"));
+        info!("connected to the database");
+        sqlx::migrate!()
+            .run(&pool)
+            .await
+            .unwrap_or_else(|_| panic!("could not run migrations for database {name}"));
+        info!("migrations were run");
+        Self { pool }
+    }
+
+    async fn get_reactions(&self, id: i64) -> Result<Vec<Reaction>, ChatError> {
+        let rows: Vec<(String, String)> = sqlx::query_as(
+            r#"
+            SELECT emoji, username
+            FROM reactions
+            WHERE message_id = ?
+            ORDER BY created_at, rowid
+        "#,
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?;
+        let mut reactions = vec![];
+        for (emoji, username) in rows {
+            add_reaction_user(&mut reactions, &emoji, &username);
+        }
+        Ok(reactions)
+    }
+
+    async fn load_reactions(
+        &self,
+        chat: &str,
+        messages: &mut [ChatMessage],
+    ) -> Result<(), ChatError> {
+        let (Some(min_id), Some(max_id)) = (
+            messages.iter().map(|m| m.id).min(),
+            messages.iter().map(|m| m.id).max(),
+        ) else {
+            return Ok(());
+        };
+        let rows: Vec<(i64, String, String)> = sqlx::query_as(
+            r#"
+            SELECT reactions.message_id, reactions.emoji, reactions.username
+            FROM reactions
+            JOIN messages ON messages.id = reactions.message_id
+            WHERE messages.chat = ? AND reactions.message_id BETWEEN ? AND ?
+            ORDER BY reactions.created_at, reactions.rowid
+        "#,
+        )
+        .bind(chat)
+        .bind(min_id)
+        .bind(max_id)
+        .fetch_all(&self.pool)
+        .await?;
+        for (id, emoji, username) in rows {
+            if let Some(message) = messages.iter_mut().find(|m| m.id == id) {
+                add_reaction_user(&mut message.reactions, &emoji, &username);
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_attachments(&self, id: i64) -> Result<Vec<Attachment>, ChatError> {
+        let attachments = sqlx::query_as(
+            r#"
+            SELECT id, chat, message_id, username, filename, content_type, size, thumbnail,
+                created_at
+            FROM attachments
+            WHERE message_id = ?
+            ORDER BY id
+        "#,
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(attachments)
+    }
+
+    async fn load_attachments(
+        &self,
+        chat: &str,
+        messages: &mut [ChatMessage],
+    ) -> Result<(), ChatError> {
+        let (Some(min_id), Some(max_id)) = (
+            messages.iter().map(|m| m.id).min(),
+            messages.iter().map(|m| m.id).max(),
+        ) else {
+            return Ok(());
+        };
+        let attachments: Vec<Attachment> = sqlx::query_as(
+            r#"
+            SELECT id, chat, message_id, username, filename, content_type, size, thumbnail,
+                created_at
+            FROM attachments
+            WHERE chat = ? AND message_id BETWEEN ? AND ?
+            ORDER BY id
+        "#,
+        )
+        .bind(chat)
+        .bind(min_id)
+        .bind(max_id)
+        .fetch_all(&self.pool)
+        .await?;
+        for attachment in attachments {
+            if let Some(message) = messages
+                .iter_mut()
+                .find(|m| Some(m.id) == attachment.message_id)
+            {
+                message.attachments.push(attachment);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ChatStore for SqliteStore {
+    async fn insert_room(&self, chat: &str, topic: Option<&str>) -> Result<Room, ChatError> {
+        let room = Room {
+            name: chat.to_string(),
+            topic: topic.map(str::to_string),
+            archived: false,
+            direct: false,
+            created_at: Utc::now(),
+        };
+        let mut transaction = self.pool.begin().await?;
+        insert_room(&mut transaction, &room).await?;
+        transaction.commit().await?;
+        Ok(room)
+    }
+
+    async fn insert_direct_room(&self, chat: &str, members: [&str; 2]) -> Result<Room, ChatError> {
+        let room = Room {
+            name: chat.to_string(),
+            topic: None,
+            archived: false,
+            direct: true,
+            created_at: Utc::now(),
+        };
+        let mut transaction = self.pool.begin().await?;
+        insert_room(&mut transaction, &room).await?;
+        for member in members {
+            sqlx::query(
+                r#"
+                INSERT INTO room_members (chat, username) VALUES (?, ?)
+            "#,
+            )
+            .bind(chat)
+            .bind(member)
+            .execute(&mut *transaction)
+            .await?;
+        }
+        transaction.commit().await?;
+        Ok(room)
+    }
+
+    async fn list_members(&self, chat: &str) -> Result<Vec<String>, ChatError> {
+        let members: Vec<(String,)> = sqlx::query_as(
+            r#"
+            SELECT username FROM room_members WHERE chat = ? ORDER BY username
+        "#,
+        )
+        .bind(chat)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(members.into_iter().map(|(username,)| username).collect())
+    }
+
+    async fn add_member(&self, chat: &str, username: &str) -> Result<(), ChatError> {
+        sqlx::query(
+            r#"
+            INSERT OR IGNORE INTO room_members (chat, username) VALUES (?, ?)
+        "#,
+        )
+        .bind(chat)
+        .bind(username)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn set_nickname(
+        &self,
+        chat: &str,
+        username: &str,
+        nickname: Option<&str>,
+    ) -> Result<(), ChatError> {
+        sqlx::query(
+            r#"
+            UPDATE room_members SET nickname = ? WHERE chat = ? AND username = ?
+        "#,
+        )
+        .bind(nickname)
+        .bind(chat)
+        .bind(username)
+        .execute(&self.pool)
+        .await
+        .map_err(|error| match (error, nickname) {
+            (sqlx::Error::Database(ref db_error), Some(nickname))
+                if db_error.is_unique_violation() =>
+            {
+                ChatError::NicknameTaken(nickname.to_string())
+            }
+            (error, _) => error.into(),
+        })?;
+        Ok(())
+    }
+
+    async fn list_nicknames(&self, chat: &str) -> Result<HashMap<String, String>, ChatError> {
+        let nicknames: Vec<(String, String)> = sqlx::query_as(
+            r#"
+            SELECT username, nickname
+            FROM room_members
+            WHERE chat = ? AND nickname IS NOT NULL
+        "#,
+        )
+        .bind(chat)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(nicknames.into_iter().collect())
+    }
+
+    async fn is_member(&self, chat: &str, username: &str) -> Result<bool, ChatError> {
+        let member: Option<(i64,)> = sqlx::query_as(
+            r#"
+            SELECT 1 FROM room_members WHERE chat = ? AND username = ?
+        "#,
+        )
+        .bind(chat)
+        .bind(username)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(member.is_some())
+    }
+
+    async fn list_inbox(&self, username: &str) -> Result<Vec<InboxEntry>, ChatError> {
+        let mut inbox: Vec<InboxEntry> = sqlx::query_as(
+            r#"
+            SELECT me.chat AS chat, other.username AS "with",
+                (SELECT COUNT(*) FROM messages
+                 WHERE messages.chat = me.chat
+                    AND messages.id > me.last_read_id
+                    AND messages.username != me.username) AS unread
+            FROM room_members me
+            JOIN rooms ON rooms.name = me.chat
+            JOIN room_members other ON other.chat = me.chat AND other.username != me.username
+            WHERE me.username = ? AND rooms.direct
+            ORDER BY me.chat
+        "#,
+        )
+        .bind(username)
+        .fetch_all(&self.pool)
+        .await?;
+        for entry in inbox.iter_mut() {
+            let query = MessageQuery {
+                before: None,
+                after: None,
+                offset: 0,
+                take: 1,
+            };
+            entry.last_message = self.list_messages(&entry.chat, query).await?.pop();
+        }
+        Ok(inbox)
+    }
+
+    async fn mark_read(&self, chat: &str, username: &str, id: i64) -> Result<i64, ChatError> {
+        let (last_read_id,): (i64,) = sqlx::query_as(
+            r#"
+            INSERT INTO room_members (chat, username, last_read_id) VALUES (?, ?, ?)
+            ON CONFLICT (chat, username)
+            DO UPDATE SET last_read_id = MAX(last_read_id, excluded.last_read_id)
+            RETURNING last_read_id
+        "#,
+        )
+        .bind(chat)
+        .bind(username)
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(last_read_id)
+    }
+
+    async fn list_read_receipts(&self, chat: &str) -> Result<Vec<ReadReceipt>, ChatError> {
+        let receipts = sqlx::query_as(
+            r#"
+            SELECT username, last_read_id
+            FROM room_members
+            WHERE chat = ? AND last_read_id > 0
+            ORDER BY username
+        "#,
+        )
+        .bind(chat)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(receipts)
+    }
+
+    async fn get_room(&self, chat: &str) -> Result<Option<Room>, ChatError> {
+        let room = sqlx::query_as(
+            r#"
+            SELECT name, topic, archived, direct, created_at
+            FROM rooms
+            WHERE name = ?
+        "#,
+        )
+        .bind(chat)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(room)
+    }
+
+    async fn list_rooms(&self) -> Result<Vec<Room>, ChatError> {
+        let rooms = sqlx::query_as(
+            r#"
+            SELECT name, topic, archived, direct, created_at
+            FROM rooms
+            ORDER BY name
+        "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rooms)
+    }
+
+    async fn archive_room(&self, chat: &str) -> Result<(), ChatError> {
+        sqlx::query(
+            r#"
+            UPDATE rooms SET archived = TRUE WHERE name = ?
+        "#,
+        )
+        .bind(chat)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn set_topic(&self, chat: &str, topic: Option<&str>) -> Result<(), ChatError> {
+        sqlx::query(
+            r#"
+            UPDATE rooms SET topic = ? WHERE name = ?
+        "#,
+        )
+        .bind(topic)
+        .bind(chat)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_room(&self, chat: &str) -> Result<(), ChatError> {
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+            DELETE FROM attachments WHERE chat = ?
+        "#,
+        )
+        .bind(chat)
+        .execute(&mut *transaction)
+        .await?;
+        sqlx::query(
+            r#"
+            DELETE FROM messages WHERE chat = ?
+        "#,
+        )
+        .bind(chat)
+        .execute(&mut *transaction)
+        .await?;
+        sqlx::query(
+            r#"
+            DELETE FROM sanctions WHERE chat = ?
+        "#,
+        )
+        .bind(chat)
+        .execute(&mut *transaction)
+        .await?;
+        sqlx::query(
+            r#"
+            DELETE FROM room_members WHERE chat = ?
+        "#,
+        )
+        .bind(chat)
+        .execute(&mut *transaction)
+        .await?;
+        sqlx::query(
+            r#"
+            DELETE FROM rooms WHERE name = ?
+        "#,
+        )
+        .bind(chat)
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn insert_message(
+        &self,
+        chat: &str,
+        username: &str,
+        text: &str,
+        kind: MessageKind,
+        attachments: &[i64],
+        reply_to: Option<ReplyTo>,
+    ) -> Result<ChatMessage, ChatError> {
+        let created_at = Utc::now();
+        let parent_id = reply_to.map(|reply_to| reply_to.parent_id);
+        let thread_only = reply_to.is_some_and(|reply_to| reply_to.thread_only);
+        let mut transaction = self.pool.begin().await?;
+        if let Some(parent_id) = parent_id {
+            let result = sqlx::query(
+                r#"
+                UPDATE messages SET reply_count = reply_count + 1 WHERE chat = ? AND id = ?
+            "#,
+            )
+            .bind(chat)
+            .bind(parent_id)
+            .execute(&mut *transaction)
+            .await?;
+            if result.rows_affected() == 0 {
+                Err(ChatError::MessageNotFound(parent_id))?
+            }
+        }
+        let result = sqlx::query(
+            r#"
+            INSERT INTO messages (chat, username, text, kind, created_at, parent_id, thread_only)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+        "#,
+        )
+        .bind(chat)
+        .bind(username)
+        .bind(text)
+        .bind(kind)
+        .bind(created_at)
+        .bind(parent_id)
+        .bind(thread_only)
+        .execute(&mut *transaction)
+        .await?;
+        let id = result.last_insert_rowid();
+        for attachment_id in attachments {
+            let result = sqlx::query(
+                r#"
+                UPDATE attachments SET message_id = ?
+                WHERE id = ? AND chat = ? AND username = ? AND message_id IS NULL
+            "#,
+            )
+            .bind(id)
+            .bind(attachment_id)
+            .bind(chat)
+            .bind(username)
+            .execute(&mut *transaction)
+            .await?;
+            if result.rows_affected() == 0 {
+                Err(ChatError::AttachmentForbidden(*attachment_id))?
+            }
+        }
+        transaction.commit().await?;
+        let mut message = ChatMessage::new(id, username.to_string(), text.to_string(), created_at);
+        message.kind = kind;
+        message.parent_id = parent_id;
+        message.thread_only = thread_only;
+        message.attachments = self.get_attachments(id).await?;
+        Ok(message)
+    }
+
+    async fn list_messages(
+        &self,
+        chat: &str,
+        query: MessageQuery,
+    ) -> Result<Vec<ChatMessage>, ChatError> {
+        let mut messages: Vec<ChatMessage> = sqlx::query_as(
+            r#"
+            SELECT id, username, text, kind, created_at, edited_at, parent_id, thread_only,
+                reply_count
+            FROM messages
+            WHERE chat = ? AND NOT thread_only
+                AND (? IS NULL OR id < ?) AND (? IS NULL OR id > ?)
+            ORDER BY id DESC
+            LIMIT ? OFFSET ?
+        "#,
+        )
+        .bind(chat)
+        .bind(query.before)
+        .bind(query.before)
+        .bind(query.after)
+        .bind(query.after)
+        .bind(query.take as i64)
+        .bind(query.offset as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        self.load_reactions(chat, &mut messages).await?;
+        self.load_attachments(chat, &mut messages).await?;
+        Ok(messages)
+    }
+
+    async fn list_messages_after(
+        &self,
+        chat: &str,
+        id: i64,
+        take: usize,
+    ) -> Result<Vec<ChatMessage>, ChatError> {
+        let mut messages: Vec<ChatMessage> = sqlx::query_as(
+            r#"
+            SELECT * FROM (
+                SELECT id, username, text, kind, created_at, edited_at, parent_id, thread_only,
+                    reply_count
+                FROM messages
+                WHERE chat = ? AND id > ? AND NOT thread_only
+                ORDER BY id
+                LIMIT ?
+            )
+            ORDER BY id DESC
+        "#,
+        )
+        .bind(chat)
+        .bind(id)
+        .bind(take as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        self.load_reactions(chat, &mut messages).await?;
+        self.load_attachments(chat, &mut messages).await?;
+        Ok(messages)
+    }
+
+    async fn list_replies(
+        &self,
+        chat: &str,
+        id: i64,
+        query: MessageQuery,
+    ) -> Result<Vec<ChatMessage>, ChatError> {
+        let mut messages: Vec<ChatMessage> = sqlx::query_as(
+            r#"
+            SELECT id, username, text, kind, created_at, edited_at, parent_id, thread_only,
+                reply_count
+            FROM messages
+            WHERE chat = ? AND parent_id = ?
+                AND (? IS NULL OR id < ?) AND (? IS NULL OR id > ?)
+            ORDER BY id DESC
+            LIMIT ? OFFSET ?
+        "#,
+        )
+        .bind(chat)
+        .bind(id)
+        .bind(query.before)
+        .bind(query.before)
+        .bind(query.after)
+        .bind(query.after)
+        .bind(query.take as i64)
+        .bind(query.offset as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        self.load_reactions(chat, &mut messages).await?;
+        self.load_attachments(chat, &mut messages).await?;
+        Ok(messages)
+    }
+
+    async fn search_messages(
+        &self,
+        chat: &str,
+        query: &SearchQuery,
+    ) -> Result<Vec<ChatMessage>, ChatError> {
+        // Terms only hold letters and digits, so quoting them is enough to escape
+        let pattern = query
+            .terms
+            .iter()
+            .map(|term| format!("\"{term}\"*"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mut messages: Vec<ChatMessage> = sqlx::query_as(
+            r#"
+            SELECT messages.id, messages.username, messages.text, messages.kind,
+                messages.created_at, messages.edited_at, messages.parent_id,
+                messages.thread_only, messages.reply_count
+            FROM messages_fts
+            JOIN messages ON messages.id = messages_fts.rowid
+            WHERE messages_fts MATCH ? AND messages.chat = ?
+                AND (? IS NULL OR messages.username = ?)
+                AND (? IS NULL OR julianday(messages.created_at) >= julianday(?))
+                AND (? IS NULL OR julianday(messages.created_at) <= julianday(?))
+            ORDER BY messages.id DESC
+            LIMIT ? OFFSET ?
+        "#,
+        )
+        .bind(pattern)
+        .bind(chat)
+        .bind(&query.author)
+        .bind(&query.author)
+        .bind(query.from)
+        .bind(query.from)
+        .bind(query.to)
+        .bind(query.to)
+        .bind(query.take as i64 + 1)
+        .bind(query.offset as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        self.load_reactions(chat, &mut messages).await?;
+        self.load_attachments(chat, &mut messages).await?;
+        Ok(messages)
+    }
+
+    async fn count_messages(&self, chat: &str) -> Result<i64, ChatError> {
+        let (count,): (i64,) = sqlx::query_as(
+            r#"
+            SELECT COUNT(*) FROM messages WHERE chat = ?
+        "#,
+        )
+        .bind(chat)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(count)
+    }
+
+    async fn get_role(&self, chat: &str, username: &str) -> Result<Role, ChatError> {
+        let role: Option<(Role,)> = sqlx::query_as(
+            r#"
+            SELECT role FROM room_members WHERE chat = ? AND username = ?
+        "#,
+        )
+        .bind(chat)
+        .bind(username)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(role.map_or(Role::Member, |(role,)| role))
+    }
+
+    async fn set_role(&self, chat: &str, username: &str, role: Role) -> Result<(), ChatError> {
+        sqlx::query(
+            r#"
+            INSERT INTO room_members (chat, username, role) VALUES (?, ?, ?)
+            ON CONFLICT (chat, username) DO UPDATE SET role = excluded.role
+        "#,
+        )
+        .bind(chat)
+        .bind(username)
+        .bind(role)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_sanction(
+        &self,
+        chat: &str,
+        username: &str,
+        kind: SanctionKind,
+    ) -> Result<Option<Sanction>, ChatError> {
+        let sanction = sqlx::query_as(
+            r#"
+            SELECT username, kind, reason, expires_at, created_by, created_at
+            FROM sanctions
+            WHERE chat = ? AND username = ? AND kind = ?
+        "#,
+        )
+        .bind(chat)
+        .bind(username)
+        .bind(kind)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(sanction)
+    }
+
+    async fn insert_sanction(&self, chat: &str, sanction: &Sanction) -> Result<(), ChatError> {
+        sqlx::query(
+            r#"
+            INSERT OR REPLACE INTO sanctions
+                (chat, username, kind, reason, expires_at, created_by, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+        "#,
+        )
+        .bind(chat)
+        .bind(&sanction.username)
+        .bind(sanction.kind)
+        .bind(&sanction.reason)
+        .bind(sanction.expires_at)
+        .bind(&sanction.created_by)
+        .bind(sanction.created_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_sanction(
+        &self,
+        chat: &str,
+        username: &str,
+        kind: SanctionKind,
+    ) -> Result<(), ChatError> {
+        sqlx::query(
+            r#"
+            DELETE FROM sanctions WHERE chat = ? AND username = ? AND kind = ?
+        "#,
+        )
+        .bind(chat)
+        .bind(username)
+        .bind(kind)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_message(&self, chat: &str, id: i64) -> Result<Option<ChatMessage>, ChatError> {
+        let message: Option<ChatMessage> = sqlx::query_as(
+            r#"
+            SELECT id, username, text, kind, created_at, edited_at, parent_id, thread_only,
+                reply_count
+            FROM messages
+            WHERE chat = ? AND id = ?
+        "#,
+        )
+        .bind(chat)
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(mut message) = message else {
+            return Ok(None);
+        };
+        message.reactions = self.get_reactions(id).await?;
+        message.attachments = self.get_attachments(id).await?;
+        Ok(Some(message))
+    }
+
+    async fn update_message(
+        &self,
+        chat: &str,
+        id: i64,
+        text: &str,
+    ) -> Result<ChatMessage, ChatError> {
+        sqlx::query(
+            r#"
+            UPDATE messages SET text = ?, edited_at = ? WHERE chat = ? AND id = ?
+        "#,
+        )
+        .bind(text)
+        .bind(Utc::now())
+        .bind(chat)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        self.get_message(chat, id)
+            .await?
+            .ok_or(ChatError::MessageNotFound(id))
+    }
+
+    async fn delete_message(&self, chat: &str, id: i64) -> Result<(), ChatError> {
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+            UPDATE messages SET reply_count = reply_count - 1
+            WHERE id = (SELECT parent_id FROM messages WHERE chat = ? AND id = ?)
+        "#,
+        )
+        .bind(chat)
+        .bind(id)
+        .execute(&mut *transaction)
+        .await?;
+        // The replies go with it through the foreign key
+        sqlx::query(
+            r#"
+            DELETE FROM messages WHERE chat = ? AND id = ?
+        "#,
+        )
+        .bind(chat)
+        .bind(id)
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn insert_attachment(&self, attachment: &Attachment) -> Result<Attachment, ChatError> {
+        let result = sqlx::query(
+            r#"
+            INSERT INTO attachments
+                (chat, username, filename, content_type, size, thumbnail, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+        "#,
+        )
+        .bind(&attachment.chat)
+        .bind(&attachment.username)
+        .bind(&attachment.filename)
+        .bind(&attachment.content_type)
+        .bind(attachment.size)
+        .bind(attachment.thumbnail)
+        .bind(attachment.created_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(Attachment {
+            id: result.last_insert_rowid(),
+            ..attachment.clone()
+        })
+    }
+
+    async fn get_attachment(&self, id: i64) -> Result<Option<Attachment>, ChatError> {
+        let attachment = sqlx::query_as(
+            r#"
+            SELECT id, chat, message_id, username, filename, content_type, size, thumbnail,
+                created_at
+            FROM attachments
+            WHERE id = ?
+        "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(attachment)
+    }
+
+    async fn add_reaction(
+        &self,
+        _chat: &str,
+        id: i64,
+        username: &str,
+        emoji: &str,
+    ) -> Result<Vec<Reaction>, ChatError> {
+        sqlx::query(
+            r#"
+            INSERT OR IGNORE INTO reactions (message_id, username, emoji, created_at)
+            VALUES (?, ?, ?, ?)
+        "#,
+        )
+        .bind(id)
+        .bind(username)
+        .bind(emoji)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+        self.get_reactions(id).await
+    }
+
+    async fn remove_reaction(
+        &self,
+        _chat: &str,
+        id: i64,
+        username: &str,
+        emoji: &str,
+    ) -> Result<Vec<Reaction>, ChatError> {
+        sqlx::query(
+            r#"
+            DELETE FROM reactions WHERE message_id = ? AND username = ? AND emoji = ?
+        "#,
+        )
+        .bind(id)
+        .bind(username)
+        .bind(emoji)
+        .execute(&self.pool)
+        .await?;
+        self.get_reactions(id).await
+    }
+
+    async fn close(&self) {
+        self.pool.close().await;
+    }
+}
+
+async fn insert_room(
+    transaction: &mut Transaction<'_, Sqlite>,
+    room: &Room,
+) -> Result<(), ChatError> {
+    sqlx::query(
+        r#"
+        INSERT INTO rooms (name, topic, archived, direct, created_at)
+        VALUES (?, ?, ?, ?, ?)
+    "#,
+    )
+    .bind(&room.name)
+    .bind(&room.topic)
+    .bind(room.archived)
+    .bind(room.direct)
+    .bind(room.created_at)
+    .execute(&mut **transaction)
+    .await
+    .map_err(|error| match error {
+        sqlx::Error::Database(ref db_error) if db_error.is_unique_violation() => {
+            ChatError::ChatAlreadyExist(room.name.clone())
+        }
+        error => error.into(),
+    })?;
+    Ok(())
+}
@@ -1,7 +1,42 @@
 use serde::{Deserialize, Serialize};
 
+#[derive(Debug, Clone, Copy)]
+pub struct HistoryConfig {
+    // Page size when the client doesn't ask for one
+    pub default_take: u32,
+    pub max_take: u32,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            default_take: 50,
+            max_take: 200,
+        }
+    }
+}
+
+impl HistoryConfig {
+    pub fn limit_take(&self, take: Option<u32>) -> usize {
+        take.unwrap_or(self.default_take).min(self.max_take) as usize
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Pagination {
     pub take: Option<u32>,
     pub offset: Option<u32>,
+    // Id of the last message the client has seen before reconnecting,
+    // the history then starts right after it and the offset is ignored
+    pub last_seen: Option<i64>,
+}
+
+impl Pagination {
+    pub fn take(&self, history: HistoryConfig) -> usize {
+        history.limit_take(self.take)
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset.unwrap_or(0) as usize
+    }
 }
@@ -0,0 +1,703 @@
+use crate::config::BridgeConfig;
+use crate::error::{AppError, AppResult};
+use futures_util::{SinkExt, StreamExt};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::time::Duration;
+use teloxide::prelude::*;
+use teloxide::types::Me;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::{interval_at, sleep_until, Instant};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::{header::AUTHORIZATION, HeaderValue};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message as WsMessage};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+const PROTOCOL_VERSION: u32 = 1;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+// Pongs don't count as activity, the server drops a quiet bridge after 30 minutes
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// Every Telegram user posts as the bridge, which gets a burst of 5 messages and one a second.
+// The pace is a little slower so that network jitter doesn't get messages refused
+const SEND_BURST: f64 = 5.0;
+const SEND_INTERVAL: Duration = Duration::from_millis(1100);
+
+type ChatSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// The part of the Lab1 chat protocol the bridge speaks
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ClientEvent {
+    Message { text: String },
+    History { after: Option<i64> },
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ServerEvent {
+    Welcome {
+        username: String,
+    },
+    Message {
+        message: ChatMessage,
+    },
+    History {
+        messages: Vec<ChatMessage>,
+        #[serde(default)]
+        has_more: bool,
+    },
+    Ack,
+    Error {
+        #[serde(default)]
+        code: String,
+        message: String,
+        fatal: bool,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum MessageKind {
+    #[default]
+    Text,
+    Action,
+}
+
+#[derive(Deserialize, Debug)]
+struct Attachment {
+    filename: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChatMessage {
+    id: i64,
+    username: String,
+    text: String,
+    #[serde(default)]
+    kind: MessageKind,
+    #[serde(default)]
+    attachments: Vec<Attachment>,
+}
+
+// Telegram messages on their way to the chat, paced to stay under its rate limit
+struct Outgoing {
+    pending: VecDeque<String>,
+    // Refused messages go out again before the pending ones
+    retries: VecDeque<String>,
+    // Sent and waiting for an ack or an error, the server answers in order
+    in_flight: VecDeque<String>,
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl Outgoing {
+    fn new() -> Self {
+        Outgoing {
+            pending: VecDeque::new(),
+            retries: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            tokens: SEND_BURST,
+            updated_at: Instant::now(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pending.is_empty() && self.retries.is_empty()
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed / SEND_INTERVAL.as_secs_f64()).min(SEND_BURST);
+        self.updated_at = now;
+    }
+
+    // When the next message can be sent
+    fn ready_at(&mut self) -> Instant {
+        self.refill();
+        let missing = (1.0 - self.tokens).max(0.0);
+        self.updated_at + SEND_INTERVAL.mul_f64(missing)
+    }
+
+    fn take(&mut self) -> Option<String> {
+        let text = self
+            .retries
+            .pop_front()
+            .or_else(|| self.pending.pop_front())?;
+        self.refill();
+        self.tokens -= 1.0;
+        self.in_flight.push_back(text.clone());
+        Some(text)
+    }
+
+    fn acked(&mut self) {
+        self.in_flight.pop_front();
+    }
+
+    // Returns the refused message unless it is sent again
+    fn refused(&mut self, code: &str) -> Option<String> {
+        let text = self.in_flight.pop_front()?;
+        if code != "rate_limited" {
+            return Some(text);
+        }
+        // The server's bucket is emptier than this one
+        self.tokens = 0.0;
+        self.updated_at = Instant::now();
+        self.retries.push_back(text);
+        None
+    }
+}
+
+// Handed to the dispatcher, Telegram messages go to the chat through it
+#[derive(Clone, Debug)]
+pub struct Bridge {
+    telegram_chat: ChatId,
+    outbox: UnboundedSender<String>,
+}
+
+// Relays the chat room to the Telegram chat until the bridge is dropped
+pub fn start(bot: Bot, config: BridgeConfig) -> Bridge {
+    let (outbox, rx) = mpsc::unbounded_channel();
+    let bridge = Bridge {
+        telegram_chat: config.TELEGRAM_CHAT,
+        outbox,
+    };
+    tokio::spawn(relay_chat(bot, config, rx));
+    bridge
+}
+
+// Only messages of the bridged Telegram chat reach the bridge handler
+pub fn for_message(msg: Message, bridge: Option<Bridge>) -> Option<Bridge> {
+    bridge.filter(|bridge| bridge.telegram_chat == msg.chat.id)
+}
+
+pub async fn telegram_handler(
+    msg: Message,
+    me: Me,
+    bridge: Bridge,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if let Some(text) = from_telegram(&msg, &me) {
+        bridge
+            .outbox
+            .send(text)
+            .map_err(|_| AppError::Bot("The chat bridge has stopped".to_string()))?;
+    }
+    Ok(())
+}
+
+// Bots are skipped, another bridge in the group would echo the chat back
+fn from_telegram(msg: &Message, me: &Me) -> Option<String> {
+    let user = msg.from()?;
+    if user.is_bot || user.id == me.user.id {
+        return None;
+    }
+    let text = msg.text().or(msg.caption())?;
+    let line = format!("{}: {text}", user.full_name());
+    // The chat runs `/name` as a command, `//` sends it as it is
+    Some(match line.starts_with('/') {
+        true => format!("/{line}"),
+        false => line,
+    })
+}
+
+fn to_telegram(message: &ChatMessage) -> String {
+    let mut text = match message.kind {
+        MessageKind::Text => format!("{}: {}", message.username, message.text),
+        MessageKind::Action => format!("* {} {}", message.username, message.text),
+    };
+    for attachment in &message.attachments {
+        text.push_str(&format!("\n📎 {}", attachment.filename));
+    }
+    text
+}
+
+async fn relay_chat(bot: Bot, config: BridgeConfig, mut outbox: UnboundedReceiver<String>) {
+    let mut last_seen = None;
+    let mut outgoing = Outgoing::new();
+    let mut delay = RECONNECT_DELAY;
+    while !outbox.is_closed() {
+        let connected_at = Instant::now();
+        let result = match connect(&config, last_seen).await {
+            Ok(socket) => {
+                relay(
+                    &bot,
+                    &config,
+                    socket,
+                    &mut outbox,
+                    &mut outgoing,
+                    &mut last_seen,
+                )
+                .await
+            }
+            Err(error) => Err(error),
+        };
+        // Idle timeouts, restarts and flooding end the session, the bridge joins again
+        match result {
+            Err(AppError::Chat(error)) => log::error!("Chat bridge was disconnected: {error}"),
+            Err(error) => log::warn!("Chat bridge disconnected: {error}"),
+            Ok(()) => log::warn!("Chat bridge disconnected"),
+        }
+        if connected_at.elapsed() >= MAX_RECONNECT_DELAY {
+            delay = RECONNECT_DELAY;
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+// The room is a path segment of its own, whatever characters its name has
+fn chat_url(config: &BridgeConfig, last_seen: Option<i64>) -> AppResult<Url> {
+    let invalid = || AppError::Config("BRIDGE_CHAT_URL isn't a valid url".to_string());
+    let mut url = Url::parse(&config.CHAT_URL).map_err(|_| invalid())?;
+    url.path_segments_mut()
+        .map_err(|_| invalid())?
+        .pop_if_empty()
+        .extend(["chat", &config.ROOM]);
+    url.query_pairs_mut()
+        .append_pair("version", &PROTOCOL_VERSION.to_string());
+    if let Some(id) = last_seen {
+        url.query_pairs_mut()
+            .append_pair("last_seen", &id.to_string());
+    }
+    Ok(url)
+}
+
+async fn connect(config: &BridgeConfig, last_seen: Option<i64>) -> AppResult<ChatSocket> {
+    let mut request = chat_url(config, last_seen)?
+        .as_str()
+        .into_client_request()?;
+    let token = HeaderValue::from_str(&format!("Bearer {}", config.TOKEN))
+        .map_err(|_| AppError::Config("BRIDGE_TOKEN isn't a valid token".to_string()))?;
+    request.headers_mut().insert(AUTHORIZATION, token);
+    let (socket, _) = tokio_tungstenite::connect_async(request).await?;
+    log::info!("Chat bridge joined {}", config.ROOM);
+    Ok(socket)
+}
+
+// Returns once the socket is closed, or when the bridge is dropped
+async fn relay(
+    bot: &Bot,
+    config: &BridgeConfig,
+    socket: ChatSocket,
+    outbox: &mut UnboundedReceiver<String>,
+    outgoing: &mut Outgoing,
+    last_seen: &mut Option<i64>,
+) -> AppResult<()> {
+    let (mut sink, mut stream) = socket.split();
+    // Whatever was in flight got an answer or was lost with the connection
+    outgoing.in_flight.clear();
+    let mut username = String::new();
+    // Messages missed while reconnecting come in the first history, page by page
+    let mut paging = last_seen.is_some();
+    // Live messages wait for the pages, they would skip the rest of them
+    let mut held = vec![];
+    let start = Instant::now() + KEEP_ALIVE_INTERVAL;
+    let mut keep_alive = interval_at(start, KEEP_ALIVE_INTERVAL);
+    loop {
+        let mut page_on = false;
+        tokio::select! {
+            text = outbox.recv() => {
+                let Some(text) = text else { return Ok(()) };
+                outgoing.pending.push_back(text);
+            }
+            _ = sleep_until(outgoing.ready_at()), if !outgoing.is_empty() => {
+                if let Some(text) = outgoing.take() {
+                    send(&mut sink, &ClientEvent::Message { text }).await?;
+                }
+            }
+            // Asking for anything missed counts as activity and posts nothing
+            _ = keep_alive.tick() => {
+                if !paging {
+                    paging = true;
+                    send(&mut sink, &ClientEvent::History { after: *last_seen }).await?;
+                }
+            }
+            message = stream.next() => {
+                let Some(message) = message else { return Ok(()) };
+                let WsMessage::Text(text) = message? else { continue };
+                let relayed = match serde_json::from_str(&text)? {
+                    ServerEvent::Welcome { username: name } => {
+                        username = name;
+                        vec![]
+                    }
+                    ServerEvent::Message { message } if paging => {
+                        held.push(message);
+                        vec![]
+                    }
+                    ServerEvent::Message { message } => vec![message],
+                    ServerEvent::History { mut messages, has_more } if paging => {
+                        messages.reverse();
+                        if has_more {
+                            page_on = true;
+                        } else {
+                            paging = false;
+                            messages.append(&mut held);
+                        }
+                        messages
+                    }
+                    ServerEvent::History { messages, .. } => {
+                        *last_seen = (*last_seen).max(messages.first().map(|m| m.id));
+                        vec![]
+                    }
+                    ServerEvent::Error { message, fatal: true, .. } => {
+                        return Err(AppError::Chat(message))
+                    }
+                    ServerEvent::Ack => {
+                        outgoing.acked();
+                        vec![]
+                    }
+                    ServerEvent::Error { code, message, .. } => {
+                        if let Some(text) = outgoing.refused(&code) {
+                            log::warn!("Chat bridge error: {message}, {text:?} wasn't sent");
+                        }
+                        vec![]
+                    }
+                    ServerEvent::Other => vec![],
+                };
+                for message in relayed {
+                    // Pages and live messages can overlap
+                    if Some(message.id) <= *last_seen {
+                        continue;
+                    }
+                    *last_seen = Some(message.id);
+                    // The bridge's own posts are the Telegram messages
+                    if message.username == username {
+                        continue;
+                    }
+                    if let Err(error) = bot.send_message(config.TELEGRAM_CHAT, to_telegram(&message)).await {
+                        log::warn!("Message {} wasn't sent to Telegram: {error}", message.id);
+                    }
+                }
+            }
+        }
+        if page_on {
+            send(&mut sink, &ClientEvent::History { after: *last_seen }).await?;
+        }
+    }
+}
+
+async fn send(
+    sink: &mut (impl SinkExt<WsMessage, Error = WsError> + Unpin),
+    event: &ClientEvent,
+) -> AppResult<()> {
+    let event = serde_json::to_string(event)?;
+    sink.send(WsMessage::text(event)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+    use tokio::time::timeout;
+    use warp::ws::{Message as StandInMessage, WebSocket};
+    use warp::Filter;
+
+    const TELEGRAM_CHAT: ChatId = ChatId(-100);
+
+    fn telegram_message(from: Value, text: &str) -> Message {
+        serde_json::from_value(json!({
+            "message_id": 1,
+            "date": 0,
+            "chat": { "id": TELEGRAM_CHAT.0, "type": "group", "title": "Team" },
+            "from": from,
+            "text": text
+        }))
+        .unwrap()
+    }
+
+    fn user(id: u64, first_name: &str, is_bot: bool) -> Value {
+        json!({ "id": id, "is_bot": is_bot, "first_name": first_name })
+    }
+
+    fn me() -> Me {
+        serde_json::from_value(json!({
+            "id": 1,
+            "is_bot": true,
+            "first_name": "Bridge",
+            "username": "bridge_bot",
+            "can_join_groups": true,
+            "can_read_all_group_messages": true,
+            "supports_inline_queries": false
+        }))
+        .unwrap()
+    }
+
+    fn chat_message(id: i64, username: &str, text: &str) -> Value {
+        json!({ "id": id, "username": username, "text": text, "created_at": "2024-11-29T12:00:00Z" })
+    }
+
+    async fn next(rx: &mut UnboundedReceiver<String>) -> String {
+        timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("Nothing was relayed")
+            .unwrap()
+    }
+
+    // Answers every Telegram method with the text it was sent
+    fn telegram_stand_in() -> (SocketAddr, UnboundedReceiver<String>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let route = warp::path!(String / String).and(warp::body::json()).map(
+            move |_token: String, method: String, body: Value| {
+                assert_eq!(method, "SendMessage");
+                assert_eq!(body["chat_id"], TELEGRAM_CHAT.0);
+                let text = body["text"].as_str().unwrap().to_string();
+                tx.send(text.clone()).unwrap();
+                let from = user(1, "Bridge", true);
+                let message = telegram_message(from.clone(), &text);
+                warp::reply::json(&json!({ "ok": true, "result": {
+                    "message_id": message.id.0,
+                    "date": 0,
+                    "chat": { "id": TELEGRAM_CHAT.0, "type": "group", "title": "Team" },
+                    "from": from,
+                    "text": text
+                }}))
+            },
+        );
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (addr, rx)
+    }
+
+    struct ChatStandIn {
+        addr: SocketAddr,
+        // Server events to send, "close" drops the connection
+        events: UnboundedSender<String>,
+        received: UnboundedReceiver<String>,
+        // Path and query of each connection
+        connections: UnboundedReceiver<String>,
+    }
+
+    fn chat_stand_in() -> ChatStandIn {
+        let (events, events_rx) = mpsc::unbounded_channel::<String>();
+        let events_rx = Arc::new(Mutex::new(events_rx));
+        let (received_tx, received) = mpsc::unbounded_channel();
+        let (connections_tx, connections) = mpsc::unbounded_channel();
+        let route = warp::path!("chat" / String)
+            .and(warp::query::raw())
+            .and(warp::header::<String>("authorization"))
+            .and(warp::ws())
+            .map(move |room: String, query: String, auth: String, ws: warp::ws::Ws| {
+                assert_eq!(auth, "Bearer token");
+                connections_tx.send(format!("{room}?{query}")).unwrap();
+                let events_rx = events_rx.clone();
+                let received_tx = received_tx.clone();
+                ws.on_upgrade(move |socket: WebSocket| async move {
+                    let (mut sink, mut stream) = socket.split();
+                    let welcome = json!({ "type": "welcome", "username": "bridge", "version": 1 });
+                    sink.send(StandInMessage::text(welcome.to_string()))
+                        .await
+                        .unwrap();
+                    let mut events_rx = events_rx.lock().await;
+                    loop {
+                        tokio::select! {
+                            event = events_rx.recv() => match event.as_deref() {
+                                Some("close") | None => return,
+                                Some(event) => sink.send(StandInMessage::text(event)).await.unwrap(),
+                            },
+                            message = stream.next() => {
+                                let Some(Ok(message)) = message else { return };
+                                if let Ok(text) = message.to_str() {
+                                    received_tx.send(text.to_string()).unwrap();
+                                }
+                            }
+                        }
+                    }
+                })
+            });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        ChatStandIn {
+            addr,
+            events,
+            received,
+            connections,
+        }
+    }
+
+    fn start_bridge(telegram_addr: SocketAddr, chat: &ChatStandIn) -> Bridge {
+        let bot =
+            Bot::new("token").set_api_url(format!("http://{telegram_addr}/").parse().unwrap());
+        start(
+            bot,
+            BridgeConfig {
+                CHAT_URL: format!("ws://{}", chat.addr),
+                ROOM: "team".to_string(),
+                TOKEN: "token".to_string(),
+                TELEGRAM_CHAT,
+            },
+        )
+    }
+
+    #[test]
+    fn room_names_are_encoded_in_the_url() {
+        let config = BridgeConfig {
+            CHAT_URL: "wss://chat.example/".to_string(),
+            ROOM: "team a/b?".to_string(),
+            TOKEN: "token".to_string(),
+            TELEGRAM_CHAT,
+        };
+        assert_eq!(
+            chat_url(&config, Some(3)).unwrap().as_str(),
+            "wss://chat.example/chat/team%20a%2Fb%3F?version=1&last_seen=3"
+        );
+    }
+
+    #[test]
+    fn messages_are_attributed() {
+        let message: ChatMessage = serde_json::from_value(json!({
+            "id": 1,
+            "username": "alice",
+            "text": "waves",
+            "kind": "action",
+            "attachments": [{ "filename": "cat.png" }]
+        }))
+        .unwrap();
+        assert_eq!(to_telegram(&message), "* alice waves\n📎 cat.png");
+
+        let from_bob = telegram_message(user(2, "Bob", false), "hello");
+        assert_eq!(
+            from_telegram(&from_bob, &me()).as_deref(),
+            Some("Bob: hello")
+        );
+        let from_slash = telegram_message(user(2, "/ban", false), "alice");
+        assert_eq!(
+            from_telegram(&from_slash, &me()).as_deref(),
+            Some("//ban: alice")
+        );
+        let from_me = telegram_message(user(1, "Bridge", true), "alice: hi");
+        assert_eq!(from_telegram(&from_me, &me()), None);
+        let from_bot = telegram_message(user(3, "Other bridge", true), "alice: hi");
+        assert_eq!(from_telegram(&from_bot, &me()), None);
+    }
+
+    #[tokio::test]
+    async fn relays_both_ways_without_echoes() {
+        let (telegram_addr, mut sent) = telegram_stand_in();
+        let mut chat = chat_stand_in();
+        let bridge = start_bridge(telegram_addr, &chat);
+        assert_eq!(next(&mut chat.connections).await, "team?version=1");
+
+        let history =
+            json!({ "type": "history", "messages": [chat_message(3, "alice", "old")], "read": [] });
+        chat.events.send(history.to_string()).unwrap();
+        let own = json!({ "type": "message", "message": chat_message(4, "bridge", "Bob: hello") });
+        chat.events.send(own.to_string()).unwrap();
+        let live = json!({ "type": "message", "message": chat_message(5, "alice", "hi") });
+        chat.events.send(live.to_string()).unwrap();
+        // Neither the history nor the bridge's own message is relayed
+        assert_eq!(next(&mut sent).await, "alice: hi");
+
+        let from_bob = telegram_message(user(2, "Bob", false), "hello");
+        telegram_handler(from_bob, me(), bridge.clone())
+            .await
+            .unwrap();
+        let event: Value = serde_json::from_str(&next(&mut chat.received).await).unwrap();
+        assert_eq!(event, json!({ "type": "message", "text": "Bob: hello" }));
+
+        // Messages posted while the bridge was away are relayed oldest first
+        chat.events.send("close".to_string()).unwrap();
+        assert_eq!(
+            next(&mut chat.connections).await,
+            "team?version=1&last_seen=5"
+        );
+        let missed = json!({ "type": "history", "messages": [
+            chat_message(7, "carol", "second"),
+            chat_message(6, "dave", "first")
+        ], "read": [] });
+        chat.events.send(missed.to_string()).unwrap();
+        assert_eq!(next(&mut sent).await, "dave: first");
+        assert_eq!(next(&mut sent).await, "carol: second");
+    }
+
+    #[tokio::test]
+    async fn fatal_errors_are_followed_by_a_resume() {
+        let (telegram_addr, mut sent) = telegram_stand_in();
+        let mut chat = chat_stand_in();
+        let _bridge = start_bridge(telegram_addr, &chat);
+        assert_eq!(next(&mut chat.connections).await, "team?version=1");
+        let history = json!({ "type": "history", "messages": [], "read": [], "has_more": false });
+        chat.events.send(history.to_string()).unwrap();
+        let live = json!({ "type": "message", "message": chat_message(1, "alice", "hi") });
+        chat.events.send(live.to_string()).unwrap();
+        assert_eq!(next(&mut sent).await, "alice: hi");
+
+        let idle =
+            json!({ "type": "error", "code": "idle_timeout", "message": "Idle", "fatal": true });
+        chat.events.send(idle.to_string()).unwrap();
+        assert_eq!(
+            next(&mut chat.connections).await,
+            "team?version=1&last_seen=1"
+        );
+        let first_page = json!({ "type": "history", "messages": [
+            chat_message(3, "carol", "second"),
+            chat_message(2, "bob", "first")
+        ], "read": [], "has_more": true });
+        chat.events.send(first_page.to_string()).unwrap();
+        // Live messages wait for the missed ones
+        let live = json!({ "type": "message", "message": chat_message(5, "alice", "now") });
+        chat.events.send(live.to_string()).unwrap();
+        assert_eq!(next(&mut sent).await, "bob: first");
+        assert_eq!(next(&mut sent).await, "carol: second");
+
+        let request: Value = serde_json::from_str(&next(&mut chat.received).await).unwrap();
+        assert_eq!(request, json!({ "type": "history", "after": 3 }));
+        let last_page = json!({ "type": "history", "messages": [
+            chat_message(5, "alice", "now"),
+            chat_message(4, "dave", "third")
+        ], "read": [], "has_more": false });
+        chat.events.send(last_page.to_string()).unwrap();
+        assert_eq!(next(&mut sent).await, "dave: third");
+        assert_eq!(next(&mut sent).await, "alice: now");
+        assert!(timeout(Duration::from_millis(200), sent.recv())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn bursts_are_paced_and_refused_messages_retried() {
+        let (telegram_addr, _sent) = telegram_stand_in();
+        let mut chat = chat_stand_in();
+        let bridge = start_bridge(telegram_addr, &chat);
+        next(&mut chat.connections).await;
+        let started_at = Instant::now();
+        for i in 0..7 {
+            let from_bob = telegram_message(user(2, "Bob", false), &i.to_string());
+            telegram_handler(from_bob, me(), bridge.clone())
+                .await
+                .unwrap();
+        }
+
+        let mut received = vec![];
+        for id in 0..5 {
+            let event: Value = serde_json::from_str(&next(&mut chat.received).await).unwrap();
+            received.push(event["text"].as_str().unwrap().to_string());
+            let ack = json!({ "type": "ack", "id": id, "client_id": null });
+            chat.events.send(ack.to_string()).unwrap();
+        }
+        assert_eq!(received, ["Bob: 0", "Bob: 1", "Bob: 2", "Bob: 3", "Bob: 4"]);
+        assert!(started_at.elapsed() < SEND_INTERVAL);
+
+        // The rest waits for the rate limit
+        let event: Value = serde_json::from_str(&next(&mut chat.received).await).unwrap();
+        assert_eq!(event["text"], "Bob: 5");
+        assert!(started_at.elapsed() >= SEND_INTERVAL);
+        let limited = json!({ "type": "error", "code": "rate_limited", "message": "Slow down", "fatal": false });
+        chat.events.send(limited.to_string()).unwrap();
+        let refused_at = Instant::now();
+        let event: Value = serde_json::from_str(&next(&mut chat.received).await).unwrap();
+        assert_eq!(event["text"], "Bob: 5");
+        assert!(refused_at.elapsed() >= SEND_INTERVAL - Duration::from_millis(100));
+        let event: Value = serde_json::from_str(&next(&mut chat.received).await).unwrap();
+        assert_eq!(event["text"], "Bob: 6");
+    }
+}
@@ -1,6 +1,7 @@
 use crate::error::{AppError, AppResult};
 use std::env;
 use std::sync::OnceLock;
+use teloxide::types::ChatId;
 
 pub fn config() -> &'static Config {
     static INSTANCE: OnceLock<Config> = OnceLock::new();
@@ -20,6 +21,8 @@ pub struct Config {
     pub OPENAI: OpenaiConfig,
     pub GROQ: GroqConfig,
     pub TELEGRAM: TelegramConfig,
+    // Only set when a Lab1 chat room is bridged to a Telegram chat
+    pub BRIDGE: Option<BridgeConfig>,
 }
 
 #[allow(non_snake_case)]
@@ -37,6 +40,17 @@ pub struct TelegramConfig {
     pub TOKEN: String,
 }
 
+#[allow(non_snake_case)]
+#[derive(Clone, Debug)]
+pub struct BridgeConfig {
+    // Base url of the Lab1 server, e.g. ws://127.0.0.1:3030
+    pub CHAT_URL: String,
+    pub ROOM: String,
+    // JWT of the chat user the bridge posts as
+    pub TOKEN: String,
+    pub TELEGRAM_CHAT: ChatId,
+}
+
 impl ConfigLoader for Config {
     fn load() -> AppResult<Self>
     where
@@ -46,6 +60,10 @@ impl ConfigLoader for Config {
             OPENAI: OpenaiConfig::load()?,
             GROQ: GroqConfig::load()?,
             TELEGRAM: TelegramConfig::load()?,
+            BRIDGE: match env::var("BRIDGE_ROOM") {
+                Ok(room) if !room.is_empty() => Some(BridgeConfig::load()?),
+                _ => None,
+            },
         })
     }
 }
@@ -80,6 +98,23 @@ impl ConfigLoader for TelegramConfig {
     }
 }
 
+impl ConfigLoader for BridgeConfig {
+    fn load() -> AppResult<Self>
+    where
+        Self: Sized,
+    {
+        let telegram_chat = get_env("BRIDGE_TELEGRAM_CHAT")?
+            .parse()
+            .map_err(|_| AppError::Config("BRIDGE_TELEGRAM_CHAT must be a chat id".to_string()))?;
+        Ok(Self {
+            CHAT_URL: get_env("BRIDGE_CHAT_URL")?,
+            ROOM: get_env("BRIDGE_ROOM")?,
+            TOKEN: get_env("BRIDGE_TOKEN")?,
+            TELEGRAM_CHAT: ChatId(telegram_chat),
+        })
+    }
+}
+
 fn get_env(name: &'static str) -> AppResult<String> {
     Ok(env::var(name).map_err(|_| AppError::Config(format!("Can't read env variable {name}")))?)
 }
@@ -15,5 +15,20 @@ pub enum AppError {
     Anyhow(#[from] anyhow::Error),
     
     #[error("Something went wrong with the bot. Details: {0}")]
-    Bot(String)
+    Bot(String),
+
+    #[error(transparent)]
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error("The chat server refused the bridge. Details: {0}")]
+    Chat(String)
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for AppError {
+    fn from(error: tokio_tungstenite::tungstenite::Error) -> Self {
+        AppError::WebSocket(Box::new(error))
+    }
 }
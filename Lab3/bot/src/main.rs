@@ -1,3 +1,4 @@
+mod bridge;
 mod config;
 mod error;
 mod groq;
@@ -30,13 +31,22 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let bot = Bot::new(config().TELEGRAM.TOKEN.as_str());
     let state = State::default();
+    let bridge = config()
+        .BRIDGE
+        .clone()
+        .map(|bridge| bridge::start(bot.clone(), bridge));
 
     let handler = dptree::entry()
+        .branch(
+            Update::filter_message()
+                .filter_map(bridge::for_message)
+                .endpoint(bridge::telegram_handler),
+        )
         .branch(Update::filter_message().endpoint(message_handler))
         .branch(Update::filter_callback_query().endpoint(callback_handler));
 
     Dispatcher::builder(bot, handler)
-        .dependencies(dptree::deps![state])
+        .dependencies(dptree::deps![state, bridge])
         .enable_ctrlc_handler()
         .build()
         .dispatch()